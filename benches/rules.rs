@@ -0,0 +1,88 @@
+// Measures rule dispatch throughput with a large rule set.
+//
+// The tool is a binary crate, so the required modules are included directly (their unit tests are compiled without
+// test harness here).
+
+#![allow(dead_code, unused_imports)]
+
+#[macro_use]
+#[path = "../src/core.rs"]
+mod core;
+#[path = "../src/metrics.rs"]
+mod metrics;
+#[path = "../src/rules.rs"]
+mod rules;
+#[path = "../src/selector.rs"]
+mod selector;
+#[path = "../src/time.rs"]
+mod time;
+
+use std::fmt::Write;
+use std::hint::black_box;
+use std::time::Instant;
+
+use crate::metrics::{MigratedTimeSeries, TimeSeries};
+use crate::rules::RuleSet;
+
+const RULES: usize = 10_000;
+const TIME_SERIES: usize = 100_000;
+
+fn main() {
+    let mut config = String::new();
+
+    for id in 0..RULES {
+        let _ = match id % 4 {
+            0 => writeln!(config, concat!(
+                "[[rule]]\nmatch = {{ __name__ = \"metric_{id}\" }}\n",
+                "set_labels = {{ migrated = \"true\" }}"), id = id),
+            1 => writeln!(config, concat!(
+                "[[rule]]\nmatch = {{ __name__ = {{ prefix = \"prefix_{id}_\" }} }}\n",
+                "delete = true"), id = id),
+            2 => writeln!(config, concat!(
+                "[[rule]]\nmatch = {{ job = \"job_{id}\", instance = {{ not_equal = \"proxy\" }} }}\n",
+                "scale = 2.0"), id = id),
+            _ => writeln!(config, concat!(
+                "[[rule]]\nmatch = {{ __name__ = \"metric_{id}\", device = {{ suffix = \"md{id}\" }} }}\n",
+                "remove_labels = [\"device\"]"), id = id),
+        };
+    }
+
+    let started = Instant::now();
+    let rules = RuleSet::parse(&config).unwrap();
+    println!("Loaded {} rules in {:.2?}.", rules.len(), started.elapsed());
+
+    let time_series: Vec<TimeSeries> = (0..TIME_SERIES).map(|id| {
+        let rule_id = id * 7 % (RULES * 2);
+
+        let name = match id % 3 {
+            0 => format!("metric_{rule_id}"),
+            1 => format!("prefix_{rule_id}_suffix"),
+            _ => format!("unmatched_metric_{id}"),
+        };
+
+        serde_json::from_value(serde_json::json!({
+            "metric": {
+                "__name__": name,
+                "job": format!("job_{rule_id}"),
+                "instance": "server",
+                "device": format!("md{rule_id}"),
+            },
+            "values": [1],
+            "timestamps": [0],
+        })).unwrap()
+    }).collect();
+
+    let started = Instant::now();
+    let mut matched = 0;
+
+    for series in &time_series {
+        if !matches!(black_box(rules.apply(series)), None | Some(MigratedTimeSeries::Unchanged)) {
+            matched += 1;
+        }
+    }
+
+    let elapsed = started.elapsed();
+    println!(
+        "Processed {} time series ({matched} matched) in {elapsed:.2?}: {:.0} time series/s.",
+        time_series.len(), time_series.len() as f64 / elapsed.as_secs_f64());
+}
\ No newline at end of file
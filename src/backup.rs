@@ -0,0 +1,238 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::metrics::TimeSeries;
+use crate::time::Timestamp;
+
+// Backup archive is a tar file with the manifest stored first and zstd-compressed JSON lines data after it
+
+const VERSION: u32 = 1;
+const MANIFEST_NAME: &str = "manifest.json";
+const DATA_NAME: &str = "data.jsonl.zst";
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: DateTime<Local>,
+    pub source: String,
+    pub start_time: Option<Timestamp>,
+    pub end_time: Option<Timestamp>,
+    pub migrations: Vec<String>,
+    pub rules_checksum: Option<String>,
+    pub series: u64,
+    pub samples: u64,
+    pub size: u64,
+    pub checksum: String,
+}
+
+impl Manifest {
+    pub fn new(
+        source: String, start_time: Option<i64>, end_time: Option<i64>, migrations: Vec<String>,
+        rules_checksum: Option<String>,
+    ) -> Manifest {
+        Manifest {
+            version: VERSION,
+            created: Local::now(),
+            source,
+            start_time: start_time.map(Timestamp),
+            end_time: end_time.map(Timestamp),
+            migrations,
+            rules_checksum,
+            series: 0,
+            samples: 0,
+            size: 0,
+            checksum: String::new(),
+        }
+    }
+}
+
+// Temporary path for the archive data which is written by the exporter or read by the importer
+pub fn data_path(archive: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.data.tmp.zst", archive.display()))
+}
+
+// Creates the archive from the data written to the temporary data path and removes the data file
+pub fn create(archive: &Path, mut manifest: Manifest) -> EmptyResult {
+    let data_path = data_path(archive);
+
+    (manifest.size, manifest.checksum) = checksum(&data_path)?;
+    (manifest.series, manifest.samples) = count(&data_path)?;
+
+    let manifest_data = serde_json::to_vec_pretty(&manifest).map_err(|e| format!(
+        "Failed to serialize the backup manifest: {e}"))?;
+
+    let temp_path = PathBuf::from(format!("{}.tmp", archive.display()));
+
+    (|| -> io::Result<()> {
+        let mut builder = tar::Builder::new(File::create(&temp_path)?);
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest_data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created.timestamp().try_into().unwrap_or_default());
+        header.set_cksum();
+
+        builder.append_data(&mut header, MANIFEST_NAME, manifest_data.as_slice())?;
+        builder.append_path_with_name(&data_path, DATA_NAME)?;
+        builder.into_inner()?.sync_all()?;
+
+        fs::rename(&temp_path, archive)
+    })().map_err(|e| format!("Unable to write {archive:?}: {e}"))?;
+
+    fs::remove_file(&data_path).map_err(|e| format!("Unable to delete {data_path:?}: {e}"))?;
+
+    Ok(())
+}
+
+// Extracts the archive data to the temporary data path verifying it against the manifest
+pub fn extract(archive: &Path) -> GenericResult<Manifest> {
+    let data_path = data_path(archive);
+
+    let manifest = (|| -> GenericResult<Manifest> {
+        let mut manifest: Option<Manifest> = None;
+        let mut extracted = false;
+
+        let mut tar = tar::Archive::new(File::open(archive)?);
+
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+
+            match name.as_str() {
+                MANIFEST_NAME if manifest.is_none() => {
+                    manifest = Some(serde_json::from_reader(entry).map_err(|e| format!(
+                        "Invalid manifest: {e}"))?);
+                },
+                DATA_NAME if !extracted => {
+                    entry.unpack(&data_path)?;
+                    extracted = true;
+                },
+                _ => return Err!("Unexpected archive entry: {name:?}"),
+            }
+        }
+
+        let (Some(manifest), true) = (manifest, extracted) else {
+            return Err!("The archive is incomplete");
+        };
+
+        if manifest.version != VERSION {
+            return Err!("Unsupported archive version: {}", manifest.version);
+        }
+
+        if checksum(&data_path)? != (manifest.size, manifest.checksum.clone()) {
+            return Err!("The archive data checksum doesn't match the manifest");
+        }
+
+        if count(&data_path)? != (manifest.series, manifest.samples) {
+            return Err!("The archive data doesn't match the manifest series and samples count");
+        }
+
+        Ok(manifest)
+    })().map_err(|e| {
+        let _ = fs::remove_file(&data_path);
+        format!("Unable to extract {archive:?}: {e}")
+    })?;
+
+    Ok(manifest)
+}
+
+pub fn checksum(path: &Path) -> GenericResult<(u64, String)> {
+    let mut file = File::open(path).map_err(|e| format!("Unable to open {path:?}: {e}"))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    let mut size = 0;
+
+    loop {
+        let read = file.read(&mut buf).map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+
+    let checksum = hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect();
+    Ok((size, checksum))
+}
+
+fn count(path: &Path) -> GenericResult<(u64, u64)> {
+    let file = File::open(path).map_err(|e| format!("Unable to open {path:?}: {e}"))?;
+    let decoder = zstd::Decoder::new(file).map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+
+    let (mut series, mut samples) = (0, 0);
+
+    for line in BufReader::new(decoder).lines() {
+        let line = line.map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let time_series: TimeSeries = serde_json::from_str(&line).map_err(|e| format!(
+            "Got an invalid time series ({e}): {line}"))?;
+
+        series += 1;
+        samples += time_series.len() as u64;
+    }
+
+    Ok((series, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let archive = std::env::temp_dir().join(format!("vm-migrate-{}-backup.tar", std::process::id()));
+
+        let mut lines = Vec::new();
+        for (name, samples) in [("first", 3), ("second", 2)] {
+            let mut time_series = TimeSeries::new(HashMap::from([("__name__".to_owned(), name.to_owned())]));
+            for index in 0..samples {
+                time_series.add(index * 1000, Some(index as f64));
+            }
+            serde_json::to_writer(&mut lines, &time_series).unwrap();
+            lines.write_all(b"\n").unwrap();
+        }
+
+        let data = zstd::encode_all(lines.as_slice(), 1).unwrap();
+        fs::write(data_path(&archive), &data).unwrap();
+
+        let manifest = Manifest::new(
+            "http://localhost:8428".to_owned(), Some(1000), None, vec!["0001_rename".to_owned()],
+            Some("checksum".to_owned()));
+        create(&archive, manifest).unwrap();
+        assert!(!data_path(&archive).exists());
+
+        let manifest = extract(&archive).unwrap();
+        assert_eq!((manifest.series, manifest.samples, manifest.size), (2, 5, data.len() as u64));
+        assert_eq!(manifest.source, "http://localhost:8428");
+        assert_eq!((manifest.start_time.map(|time| time.0), manifest.end_time.map(|time| time.0)), (Some(1000), None));
+        assert_eq!(manifest.migrations, ["0001_rename"]);
+        assert_eq!(manifest.rules_checksum.as_deref(), Some("checksum"));
+        assert_eq!(fs::read(data_path(&archive)).unwrap(), data);
+        fs::remove_file(data_path(&archive)).unwrap();
+
+        // Corrupted data is detected and not left extracted
+        let mut contents = fs::read(&archive).unwrap();
+        let offset = contents.windows(data.len()).position(|window| window == data).unwrap();
+        contents[offset + data.len() - 1] ^= 1;
+        fs::write(&archive, &contents).unwrap();
+
+        let error = extract(&archive).err().unwrap().to_string();
+        assert!(error.contains("checksum doesn't match"), "{error}");
+        assert!(!data_path(&archive).exists());
+
+        fs::remove_file(&archive).unwrap();
+    }
+}
\ No newline at end of file
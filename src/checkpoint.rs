@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::core::{EmptyResult, GenericResult};
+
+// Records the completed migration tasks, so an interrupted migration can be resumed.
+//
+// The checkpoint file consists of JSON lines: the first one describes the migration parameters which define the set of
+// tasks and each next one is a completed task with its result.
+pub struct Checkpoint<T, R> {
+    path: PathBuf,
+    file: File,
+    completed: HashMap<T, R>,
+}
+
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Header {
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+    pub window: Option<i64>,
+    pub sharded: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub selectors: Vec<String>,
+    // Digest of the migrations and rules, since the results of the completed tasks depend on them
+    pub migrator: String,
+}
+
+impl<T, R> Checkpoint<T, R>
+    where T: Eq + std::hash::Hash + Serialize + DeserializeOwned, R: Serialize + DeserializeOwned
+{
+    pub fn load(path: &Path) -> GenericResult<Option<(Header, HashMap<T, R>)>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err!("Unable to open {path:?}: {e}"),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+
+        let mut next_line = || -> GenericResult<Option<String>> {
+            Ok(lines.next().transpose().map_err(|e| format!("Error while reading {path:?}: {e}"))?)
+        };
+
+        let Some(header) = next_line()? else {
+            return Ok(None);
+        };
+
+        let header: Header = serde_json::from_str(&header).map_err(|e| format!(
+            "Invalid checkpoint file {path:?}: {e}"))?;
+
+        let mut completed = HashMap::new();
+        let mut line = next_line()?;
+
+        while let Some(current) = line {
+            line = next_line()?;
+
+            let (task, result) = match serde_json::from_str(&current) {
+                Ok(entry) => entry,
+                // The last line may be partially written if the process has been killed
+                Err(_) if line.is_none() => break,
+                Err(e) => return Err!("Invalid checkpoint file {path:?}: {e}"),
+            };
+
+            completed.insert(task, result);
+        }
+
+        Ok(Some((header, completed)))
+    }
+
+    pub fn create(path: &Path, header: &Header, completed: HashMap<T, R>) -> GenericResult<Checkpoint<T, R>> {
+        let mut data = serde_json::to_vec(header)?;
+        data.push(b'\n');
+
+        for entry in &completed {
+            serde_json::to_writer(&mut data, &entry)?;
+            data.push(b'\n');
+        }
+
+        let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+
+        fs::write(&temp_path, data).and_then(|_| fs::rename(&temp_path, path)).map_err(|e| format!(
+            "Unable to write {path:?}: {e}"))?;
+
+        let file = OpenOptions::new().append(true).open(path).map_err(|e| format!(
+            "Unable to open {path:?}: {e}"))?;
+
+        Ok(Checkpoint {path: path.to_owned(), file, completed})
+    }
+
+    pub fn is_completed(&self, task: &T) -> bool {
+        self.completed.contains_key(task)
+    }
+
+    pub fn add(&mut self, task: T, result: R) -> EmptyResult {
+        let mut data = serde_json::to_vec(&(&task, &result))?;
+        data.push(b'\n');
+
+        self.file.write_all(&data).and_then(|_| self.file.sync_data()).map_err(|e| format!(
+            "Unable to write {:?}: {e}", self.path))?;
+
+        self.completed.insert(task, result);
+        Ok(())
+    }
+
+    pub fn remove(self) -> EmptyResult {
+        fs::remove_file(&self.path).map_err(|e| format!(
+            "Unable to delete {:?}: {e}", self.path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestCheckpoint = Checkpoint<String, u64>;
+
+    fn header() -> Header {
+        Header {
+            start_time: Some(1000),
+            end_time: Some(2000),
+            window: None,
+            sharded: true,
+            selectors: Vec::new(),
+            migrator: "digest".to_owned(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vm-migrate-{}-{name}.checkpoint", std::process::id()))
+    }
+
+    #[test]
+    fn resume() {
+        let path = temp_path("resume");
+        assert!(TestCheckpoint::load(&path).unwrap().is_none());
+
+        let mut checkpoint = TestCheckpoint::create(&path, &header(), HashMap::new()).unwrap();
+        checkpoint.add("first".to_owned(), 1).unwrap();
+        checkpoint.add("second".to_owned(), 2).unwrap();
+        assert!(checkpoint.is_completed(&"first".to_owned()));
+        assert!(!checkpoint.is_completed(&"third".to_owned()));
+
+        let (loaded_header, completed) = TestCheckpoint::load(&path).unwrap().unwrap();
+        assert!(loaded_header == header());
+        assert_eq!(completed, HashMap::from([("first".to_owned(), 1), ("second".to_owned(), 2)]));
+
+        let mut checkpoint = TestCheckpoint::create(&path, &header(), completed).unwrap();
+        checkpoint.add("third".to_owned(), 3).unwrap();
+
+        let (_, completed) = TestCheckpoint::load(&path).unwrap().unwrap();
+        assert_eq!(completed.len(), 3);
+
+        checkpoint.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn truncated() {
+        let path = temp_path("truncated");
+
+        let mut checkpoint = TestCheckpoint::create(&path, &header(), HashMap::new()).unwrap();
+        checkpoint.add("first".to_owned(), 1).unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"["second","#).unwrap();
+
+        let (_, completed) = TestCheckpoint::load(&path).unwrap().unwrap();
+        assert_eq!(completed, HashMap::from([("first".to_owned(), 1)]));
+
+        file.write_all(b"\n[\"third\",3]\n").unwrap();
+        assert!(TestCheckpoint::load(&path).is_err());
+
+        checkpoint.remove().unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::core::GenericResult;
+use crate::metrics::{LabeledSample, TimeSeries};
+
+// CSV format in the layout of VictoriaMetrics /api/v1/export/csv: one sample per row with the columns specified as a
+// comma-separated list of `__name__`, `__value__`, `__timestamp__:<format>` and label names, where the timestamp format
+// is one of `unix_s`, `unix_ms`, `unix_ns` and `rfc3339`. The labels which aren't listed in the columns are lost.
+#[derive(PartialEq)]
+pub struct Columns {
+    spec: String,
+    columns: Vec<Column>,
+}
+
+#[derive(PartialEq)]
+enum Column {
+    Name,
+    Value,
+    Timestamp(TimestampFormat),
+    Label(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TimestampFormat {
+    UnixS,
+    UnixMs,
+    UnixNs,
+    Rfc3339,
+}
+
+impl Columns {
+    pub fn parse(spec: &str) -> GenericResult<Columns> {
+        let mut columns = Vec::new();
+
+        for column in spec.split(',') {
+            let column = match column.trim() {
+                "__name__" => Column::Name,
+                "__value__" => Column::Value,
+                "" => return Err!("Invalid CSV columns: {spec:?}"),
+                column => match column.split_once(':') {
+                    Some(("__timestamp__", format)) => Column::Timestamp(match format {
+                        "unix_s" => TimestampFormat::UnixS,
+                        "unix_ms" => TimestampFormat::UnixMs,
+                        "unix_ns" => TimestampFormat::UnixNs,
+                        "rfc3339" => TimestampFormat::Rfc3339,
+                        _ => return Err!("Unsupported CSV timestamp format: {format:?}"),
+                    }),
+                    _ => Column::Label(column.to_owned()),
+                },
+            };
+
+            if columns.contains(&column) {
+                return Err!("Invalid CSV columns: {spec:?} (duplicated columns)");
+            }
+
+            columns.push(column);
+        }
+
+        for (column, name) in [
+            (Column::Name, "__name__"),
+            (Column::Value, "__value__"),
+        ] {
+            if !columns.contains(&column) {
+                return Err!("Invalid CSV columns: {spec:?} (missing {name} column)");
+            }
+        }
+
+        match columns.iter().filter(|column| matches!(column, Column::Timestamp(_))).count() {
+            1 => {},
+            _ => return Err!("Invalid CSV columns: {spec:?} (exactly one __timestamp__ column is expected)"),
+        }
+
+        Ok(Columns {spec: spec.to_owned(), columns})
+    }
+
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+}
+
+pub fn encode(columns: &Columns, time_series: &TimeSeries, buf: &mut Vec<u8>) {
+    for (time, value) in time_series.iter() {
+        for (index, column) in columns.columns.iter().enumerate() {
+            if index != 0 {
+                buf.push(b',');
+            }
+
+            let field = match column {
+                Column::Name => time_series.name().to_owned(),
+                Column::Value => value.map(format_value).unwrap_or_else(|| "NaN".to_owned()),
+                Column::Timestamp(format) => format_time(time, *format),
+                Column::Label(name) => time_series.label(name).to_owned(),
+            };
+
+            write_field(buf, &field);
+        }
+
+        buf.push(b'\n');
+    }
+}
+
+// Parses a CSV row into sample labels, time and value (NaN is treated as a null value)
+pub fn parse_row(columns: &Columns, row: &str) -> GenericResult<LabeledSample> {
+    let fields = split_row(row)?;
+    if fields.len() != columns.columns.len() {
+        return Err!("Got an invalid CSV row (expected {} columns): {row}", columns.columns.len());
+    }
+
+    let mut labels = HashMap::new();
+    let (mut time, mut value) = (0, None);
+
+    for (column, field) in columns.columns.iter().zip(fields) {
+        match column {
+            Column::Name => {
+                labels.insert("__name__".to_owned(), field);
+            },
+            Column::Value => {
+                value = parse_value(&field).ok_or_else(|| format!("Got an invalid CSV value: {field:?}"))?;
+            },
+            Column::Timestamp(format) => {
+                time = parse_time(&field, *format).ok_or_else(|| format!("Got an invalid CSV timestamp: {field:?}"))?;
+            },
+            Column::Label(name) => if !field.is_empty() {
+                labels.insert(name.clone(), field);
+            },
+        }
+    }
+
+    Ok((labels, time, value))
+}
+
+// Checks that the row doesn't end inside a quoted field: such a row is continued on the next line
+pub fn is_complete_row(row: &str) -> bool {
+    row.matches('"').count().is_multiple_of(2)
+}
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_owned()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn parse_value(value: &str) -> Option<Option<f64>> {
+    Some(match value {
+        "NaN" | "" => None,
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        _ => Some(value.parse().ok()?),
+    })
+}
+
+fn format_time(time: i64, format: TimestampFormat) -> String {
+    match format {
+        TimestampFormat::UnixS => time.div_euclid(1000).to_string(),
+        TimestampFormat::UnixMs => time.to_string(),
+        TimestampFormat::UnixNs => (i128::from(time) * 1_000_000).to_string(),
+        TimestampFormat::Rfc3339 => DateTime::<Utc>::from_timestamp_millis(time)
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            .unwrap_or_default(),
+    }
+}
+
+fn parse_time(time: &str, format: TimestampFormat) -> Option<i64> {
+    match format {
+        TimestampFormat::UnixS => time.parse::<f64>().ok().map(|time| (time * 1000.0).round() as i64),
+        TimestampFormat::UnixMs => time.parse().ok(),
+        TimestampFormat::UnixNs => time.parse::<i128>().ok().and_then(|time| (time / 1_000_000).try_into().ok()),
+        TimestampFormat::Rfc3339 => DateTime::parse_from_rfc3339(time).ok().map(|time| time.timestamp_millis()),
+    }
+}
+
+fn write_field(buf: &mut Vec<u8>, field: &str) {
+    if !field.contains([',', '"', '\n', '\r']) {
+        buf.extend(field.as_bytes());
+        return;
+    }
+
+    buf.push(b'"');
+    buf.extend(field.replace('"', "\"\"").as_bytes());
+    buf.push(b'"');
+}
+
+fn split_row(row: &str) -> GenericResult<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = row.chars().peekable();
+
+    loop {
+        let mut field = String::new();
+
+        if chars.peek() == Some(&'"') {
+            chars.next();
+
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    },
+                    Some('"') => break,
+                    Some(char) => field.push(char),
+                    None => return Err!("Got an invalid CSV row (unterminated quoted field): {row}"),
+                }
+            }
+
+            if !matches!(chars.peek(), Some(',') | None) {
+                return Err!("Got an invalid CSV row (unexpected data after quoted field): {row}");
+            }
+        } else {
+            while let Some(&char) = chars.peek() {
+                if char == ',' {
+                    break;
+                }
+                field.push(char);
+                chars.next();
+            }
+        }
+
+        fields.push(field);
+
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::TryStreamExt;
+
+    use crate::formats::{self, Format};
+
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let job = "a,\"b\"\n\nc";
+        let mut time_series = TimeSeries::new(labels(&[
+            ("__name__", "metric"), ("job", job), ("instance", "host"), ("env", "lost"),
+        ]));
+
+        let samples = [
+            (1_700_000_000_000, Some(1.5)), (1_700_000_001_000, None), (1_700_000_002_000, Some(f64::INFINITY)),
+            (1_700_000_003_000, Some(f64::NEG_INFINITY)), (1_700_000_004_000, Some(-1e-5)),
+        ];
+        for (time, value) in samples {
+            time_series.add(time, value);
+        }
+
+        for format in ["unix_s", "unix_ms", "unix_ns", "rfc3339"] {
+            let columns = Columns::parse(&format!("job,__name__,__timestamp__:{format},__value__,instance")).unwrap();
+
+            let mut buf = Vec::new();
+            encode(&columns, &time_series, &mut buf);
+
+            // The rows are continued on the next lines inside the quoted fields
+            let format = Format::Csv(Arc::new(columns));
+            let decoded: Vec<_> = formats::read_time_series(format, buf.as_slice()).try_collect().await.unwrap();
+            assert_eq!(decoded.len(), 1);
+
+            let (decoded, data) = &decoded[0];
+            assert_eq!(decoded.format_metric(), TimeSeries::new(labels(&[
+                ("__name__", "metric"), ("job", job), ("instance", "host"),
+            ])).format_metric());
+            assert_eq!(decoded.iter().collect::<Vec<_>>(), samples);
+            assert_eq!(*data, buf);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let columns = Columns::parse("__name__,__value__,__timestamp__:rfc3339,job").unwrap();
+
+        assert_eq!(
+            parse_row(&columns, r#"up,1,2024-01-01T00:00:00.5+01:00,"""node""""#).unwrap(),
+            (labels(&[("__name__", "up"), ("job", r#""node""#)]), 1_704_063_600_500, Some(1.0)),
+        );
+        assert_eq!(
+            parse_row(&columns, "up,,2024-01-01T00:00:00Z,").unwrap(),
+            (labels(&[("__name__", "up")]), 1_704_067_200_000, None),
+        );
+
+        for row in ["up,1,2024-01-01T00:00:00Z", "up,x,2024-01-01T00:00:00Z,", "up,1,1704067200,", r#"up,1,"2024"x,"#] {
+            assert!(parse_row(&columns, row).is_err(), "{row}");
+        }
+
+        for spec in ["__name__,__value__", "__name__,__timestamp__:unix_s", "__name__,__value__,__timestamp__:unix",
+                     "__name__,__value__,__timestamp__:unix_s,job,job", "__name__,,__value__,__timestamp__:unix_s"] {
+            assert!(Columns::parse(spec).is_err(), "{spec}");
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,254 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, btree_map};
+
+use crate::metrics::TimeSeries;
+
+// Both sides are collected into time series sorted by label set and then joined
+
+#[derive(Clone, Copy, Default)]
+pub struct Tolerance {
+    pub absolute: f64,
+    pub relative: f64,
+}
+
+impl Tolerance {
+    fn matches(&self, a: Option<f64>, b: Option<f64>) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                a == b || (a - b).abs() <= self.absolute.max(self.relative * a.abs().max(b.abs()))
+            },
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Series {
+    series: BTreeMap<String, TimeSeries>,
+}
+
+pub enum Difference {
+    SourceOnly(String, usize),
+    TargetOnly(String, usize),
+    Samples(SampleDifference),
+}
+
+pub struct SampleDifference {
+    pub metric: String,
+    pub source_samples: usize,
+    pub target_samples: usize,
+    // Samples with timestamps which are missing on the other side
+    pub source_only: usize,
+    pub target_only: usize,
+    pub mismatched: usize,
+    // The first mismatched sample: time, source and target value
+    pub first_mismatch: Option<(i64, Option<f64>, Option<f64>)>,
+}
+
+impl Series {
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn get(&self, metric: &str) -> Option<&TimeSeries> {
+        self.series.get(metric)
+    }
+
+    // A time series may be exported in multiple parts (lines or time windows)
+    pub fn add(&mut self, time_series: TimeSeries) {
+        match self.series.get_mut(&time_series.format_metric()) {
+            Some(existing) => {
+                for (time, value) in time_series.iter() {
+                    existing.add(time, value);
+                }
+            },
+            None => {
+                self.series.insert(time_series.format_metric(), time_series);
+            },
+        }
+    }
+
+    // Returns the differences of the target time series from the source ones sorted by metric. Sample values are
+    // compared at the timestamps present on both sides.
+    pub fn compare(self, target: Series, tolerance: Tolerance) -> Vec<Difference> {
+        let mut differences = Vec::new();
+
+        let mut source = self.series.into_iter().peekable();
+        let mut target = target.series.into_iter().peekable();
+
+        loop {
+            let order = match (source.peek(), target.peek()) {
+                (Some((source_metric, _)), Some((target_metric, _))) => source_metric.cmp(target_metric),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+
+            match order {
+                Ordering::Less => {
+                    let (metric, time_series) = source.next().unwrap();
+                    differences.push(Difference::SourceOnly(metric, time_series.len()));
+                },
+                Ordering::Greater => {
+                    let (metric, time_series) = target.next().unwrap();
+                    differences.push(Difference::TargetOnly(metric, time_series.len()));
+                },
+                Ordering::Equal => {
+                    let (metric, source_series) = source.next().unwrap();
+                    let (_, target_series) = target.next().unwrap();
+
+                    if let Some(difference) = compare_samples(metric, source_series, target_series, tolerance) {
+                        differences.push(Difference::Samples(difference));
+                    }
+                },
+            }
+        }
+
+        differences
+    }
+}
+
+impl IntoIterator for Series {
+    type Item = (String, TimeSeries);
+    type IntoIter = btree_map::IntoIter<String, TimeSeries>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.series.into_iter()
+    }
+}
+
+fn compare_samples(
+    metric: String, mut source: TimeSeries, mut target: TimeSeries, tolerance: Tolerance,
+) -> Option<SampleDifference> {
+    source.sort();
+    target.sort();
+
+    let mut difference = SampleDifference {
+        metric,
+        source_samples: source.len(),
+        target_samples: target.len(),
+        source_only: 0,
+        target_only: 0,
+        mismatched: 0,
+        first_mismatch: None,
+    };
+
+    let mut source_samples = source.iter().peekable();
+    let mut target_samples = target.iter().peekable();
+
+    loop {
+        let (source_time, target_time) = match (source_samples.peek(), target_samples.peek()) {
+            (Some(&(source_time, _)), Some(&(target_time, _))) => (source_time, target_time),
+            (Some(_), None) => (i64::MIN, i64::MAX),
+            (None, Some(_)) => (i64::MAX, i64::MIN),
+            (None, None) => break,
+        };
+
+        match source_time.cmp(&target_time) {
+            Ordering::Less => {
+                source_samples.next();
+                difference.source_only += 1;
+            },
+            Ordering::Greater => {
+                target_samples.next();
+                difference.target_only += 1;
+            },
+            Ordering::Equal => {
+                let (_, source_value) = source_samples.next().unwrap();
+                let (_, target_value) = target_samples.next().unwrap();
+
+                if !tolerance.matches(source_value, target_value) {
+                    difference.mismatched += 1;
+                    difference.first_mismatch.get_or_insert((source_time, source_value, target_value));
+                }
+            },
+        }
+    }
+
+    if difference.source_only == 0 && difference.target_only == 0 && difference.mismatched == 0 {
+        return None;
+    }
+
+    Some(difference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_series(name: &str, samples: &[(i64, Option<f64>)]) -> TimeSeries {
+        let mut time_series = TimeSeries::new([("__name__".to_owned(), name.to_owned())].into_iter().collect());
+        for &(time, value) in samples {
+            time_series.add(time, value);
+        }
+        time_series
+    }
+
+    fn series(time_series: Vec<TimeSeries>) -> Series {
+        let mut series = Series::default();
+        for time_series in time_series {
+            series.add(time_series);
+        }
+        series
+    }
+
+    #[test]
+    fn tolerance() {
+        let exact = Tolerance::default();
+        assert!(exact.matches(Some(1.0), Some(1.0)));
+        assert!(exact.matches(None, None));
+        assert!(!exact.matches(Some(1.0), Some(1.0 + 1e-12)));
+        assert!(!exact.matches(Some(1.0), None));
+        assert!(!exact.matches(None, Some(1.0)));
+
+        let absolute = Tolerance {absolute: 0.5, relative: 0.0};
+        assert!(absolute.matches(Some(1.0), Some(1.5)));
+        assert!(absolute.matches(Some(-1.0), Some(-0.5)));
+        assert!(!absolute.matches(Some(1.0), Some(1.6)));
+
+        let relative = Tolerance {absolute: 0.0, relative: 0.01};
+        assert!(relative.matches(Some(1000.0), Some(1010.0)));
+        assert!(relative.matches(Some(-1000.0), Some(-990.0)));
+        assert!(!relative.matches(Some(1.0), Some(1.1)));
+        assert!(!relative.matches(Some(0.0), Some(1e-9)));
+
+        assert!(Tolerance {absolute: 0.01, relative: 0.01}.matches(Some(0.0), Some(0.005)));
+        assert!(!Tolerance {absolute: 1.0, relative: 0.0}.matches(Some(f64::NAN), Some(f64::NAN)));
+    }
+
+    #[test]
+    fn compare() {
+        let source = series(vec![
+            time_series("a", &[(0, Some(1.0))]),
+            time_series("b", &[(0, Some(1.0)), (1000, Some(2.0))]),
+            time_series("c", &[(3000, Some(3.0)), (0, Some(1.0)), (1000, Some(2.0))]),
+            time_series("e", &[(0, Some(1.0)), (1000, None)]),
+        ]);
+
+        let mut target = series(vec![
+            time_series("b", &[(0, Some(1.05)), (1000, Some(2.0))]),
+            time_series("c", &[(0, Some(1.0)), (1000, Some(4.0)), (2000, Some(5.0)), (4000, None)]),
+            time_series("d", &[(0, Some(1.0)), (1000, Some(1.0))]),
+            time_series("e", &[(1000, None)]),
+        ]);
+
+        // The time series may be added in parts
+        target.add(time_series("e", &[(0, Some(1.0))]));
+
+        let differences = source.compare(target, Tolerance {absolute: 0.1, relative: 0.0});
+        assert_eq!(differences.len(), 3);
+
+        assert!(matches!(&differences[0], Difference::SourceOnly(metric, 1) if metric == "a"));
+
+        let Difference::Samples(difference) = &differences[1] else {
+            panic!("Unexpected difference");
+        };
+        assert_eq!(difference.metric, "c");
+        assert_eq!((difference.source_samples, difference.target_samples), (3, 4));
+        assert_eq!((difference.source_only, difference.target_only, difference.mismatched), (1, 2, 1));
+        assert_eq!(difference.first_mismatch, Some((1000, Some(2.0), Some(4.0))));
+
+        assert!(matches!(&differences[2], Difference::TargetOnly(metric, 2) if metric == "d"));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::metrics::TimeSeries;
+
+// Sample count and checksum of each time series. The checksum doesn't depend on the sample order, so a time series may
+// be added in parts (as it's exported in multiple lines or time windows).
+#[derive(Default)]
+pub struct Digests {
+    series: HashMap<String, Digest>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Digest {
+    pub samples: u64,
+    checksum: u64,
+}
+
+pub enum Difference {
+    Missing(String, Digest),
+    Extra(String, Digest),
+    Mismatch(String, Digest, Digest),
+}
+
+impl Digests {
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn add(&mut self, time_series: &TimeSeries) {
+        let digest = self.series.entry(time_series.format_metric()).or_default();
+
+        for (time, value) in time_series.iter() {
+            let mut hasher = DefaultHasher::new();
+            (time, value.map(f64::to_bits)).hash(&mut hasher);
+
+            digest.samples += 1;
+            digest.checksum = digest.checksum.wrapping_add(hasher.finish());
+        }
+    }
+
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, filter: F) {
+        self.series.retain(|metric, _digest| filter(metric));
+    }
+
+    // Returns the differences of the actual time series from the expected ones sorted by metric
+    pub fn compare(&self, actual: &Digests) -> Vec<Difference> {
+        let mut differences = Vec::new();
+
+        for (metric, &expected) in &self.series {
+            match actual.series.get(metric) {
+                Some(&digest) if digest == expected => {},
+                Some(&digest) => differences.push(Difference::Mismatch(metric.clone(), expected, digest)),
+                None => differences.push(Difference::Missing(metric.clone(), expected)),
+            }
+        }
+
+        for (metric, &digest) in &actual.series {
+            if !self.series.contains_key(metric) {
+                differences.push(Difference::Extra(metric.clone(), digest));
+            }
+        }
+
+        differences.sort_by(|a, b| a.metric().cmp(b.metric()));
+        differences
+    }
+}
+
+impl Difference {
+    pub fn metric(&self) -> &str {
+        match self {
+            Difference::Missing(metric, _) | Difference::Extra(metric, _) |
+            Difference::Mismatch(metric, _, _) => metric,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_series(name: &str, samples: &[(i64, Option<f64>)]) -> TimeSeries {
+        let mut time_series = TimeSeries::new([("__name__".to_owned(), name.to_owned())].into_iter().collect());
+        for &(time, value) in samples {
+            time_series.add(time, value);
+        }
+        time_series
+    }
+
+    #[test]
+    fn add() {
+        let mut expected = Digests::default();
+        expected.add(&time_series("up", &[(0, Some(1.0)), (1000, None), (2000, Some(0.0))]));
+
+        // The time series is added in parts in other order
+        let mut actual = Digests::default();
+        actual.add(&time_series("up", &[(2000, Some(0.0))]));
+        actual.add(&time_series("up", &[(1000, None), (0, Some(1.0))]));
+
+        assert_eq!(actual.len(), 1);
+        assert!(expected.compare(&actual).is_empty());
+
+        let mut actual = Digests::default();
+        actual.add(&time_series("up", &[(0, Some(1.0)), (1000, Some(0.0)), (2000, None)]));
+        assert!(matches!(expected.compare(&actual)[..], [Difference::Mismatch(..)]));
+    }
+
+    #[test]
+    fn compare() {
+        let mut expected = Digests::default();
+        expected.add(&time_series("a", &[(0, Some(1.0))]));
+        expected.add(&time_series("b", &[(0, Some(1.0)), (1000, Some(2.0))]));
+        expected.add(&time_series("c", &[(0, Some(1.0))]));
+
+        let mut actual = Digests::default();
+        actual.add(&time_series("b", &[(0, Some(1.0))]));
+        actual.add(&time_series("c", &[(0, Some(1.0))]));
+        actual.add(&time_series("d", &[(0, Some(1.0))]));
+
+        let differences = expected.compare(&actual);
+        assert_eq!(differences.iter().map(Difference::metric).collect::<Vec<_>>(), ["a", "b", "d"]);
+
+        assert!(matches!(differences[0], Difference::Missing(_, Digest {samples: 1, ..})));
+        assert!(matches!(differences[1], Difference::Mismatch(_, Digest {samples: 2, ..}, Digest {samples: 1, ..})));
+        assert!(matches!(differences[2], Difference::Extra(_, Digest {samples: 1, ..})));
+    }
+}
\ No newline at end of file
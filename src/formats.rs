@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_stream::try_stream;
+use futures_core::stream::Stream;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::csv::{self, Columns};
+use crate::graphite::{self, Templates};
+use crate::influx::{self, Mapping};
+use crate::metrics::TimeSeries;
+use crate::native;
+use crate::prometheus;
+use crate::remote;
+use crate::retry::transient;
+
+#[derive(Clone, PartialEq)]
+pub enum Format {
+    Json,
+    Native,
+    Csv(Arc<Columns>),
+    Prometheus,
+    Remote,
+    Influx(Arc<Mapping>),
+    Graphite(Arc<Templates>),
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::Native => "Native",
+            Format::Csv(_) => "CSV",
+            Format::Prometheus => "Prometheus text",
+            Format::Remote => "Prometheus remote storage",
+            Format::Influx(_) => "InfluxDB line protocol",
+            Format::Graphite(_) => "Graphite plaintext",
+        }
+    }
+
+    // InfluxDB and Graphite data can only be read from files
+    pub fn is_source_only(&self) -> bool {
+        matches!(self, Format::Influx(_) | Format::Graphite(_))
+    }
+
+    pub fn export_path(&self) -> Option<&'static str> {
+        Some(match self {
+            Format::Json => "/api/v1/export",
+            Format::Native => "/api/v1/export/native",
+            Format::Csv(_) => "/api/v1/export/csv",
+            Format::Prometheus | Format::Remote | Format::Influx(_) | Format::Graphite(_) => return None,
+        })
+    }
+
+    pub fn export_params(&self) -> Vec<(&str, &str)> {
+        match self {
+            Format::Json => vec![("reduce_mem_usage", "1")],
+            Format::Csv(columns) => vec![("format", columns.spec())],
+            Format::Native | Format::Prometheus | Format::Remote | Format::Influx(_) | Format::Graphite(_) => {
+                Vec::new()
+            },
+        }
+    }
+
+    // VictoriaMetrics CSV import requires metric names to be specified in the request, so it's not supported. Remote
+    // write requests are sent to the target URL as is.
+    pub fn import_path(&self) -> Option<&'static str> {
+        Some(match self {
+            Format::Json => "/api/v1/import",
+            Format::Native => "/api/v1/import/native",
+            Format::Csv(_) | Format::Remote | Format::Influx(_) | Format::Graphite(_) => return None,
+            Format::Prometheus => "/api/v1/import/prometheus",
+        })
+    }
+
+    pub fn encode(&self, time_series: &TimeSeries, buf: &mut Vec<u8>) -> EmptyResult {
+        match self {
+            Format::Json => {
+                serde_json::to_writer(&mut *buf, time_series).map_err(|e| format!(
+                    "Failed to serialize time series: {e}"))?;
+                buf.push(b'\n');
+            },
+            Format::Native => native::encode(time_series, buf),
+            Format::Csv(columns) => csv::encode(columns, time_series, buf),
+            Format::Prometheus => prometheus::encode(time_series, buf),
+            Format::Remote => remote::encode(time_series, buf),
+            Format::Influx(_) | Format::Graphite(_) => {
+                return Err!("{} format is not supported for writing", self.name());
+            },
+        }
+        Ok(())
+    }
+}
+
+// Reads time series in a line-based format (any format except native) returning them along with their source data.
+// CSV rows, Prometheus text, InfluxDB and Graphite lines are grouped into time series by consecutive lines with samples
+// of the same labels.
+pub fn read_time_series<R: AsyncBufRead + Unpin>(
+    format: Format, reader: R,
+) -> impl Stream<Item = GenericResult<(TimeSeries, Vec<u8>)>> {
+    try_stream! {
+        let mut lines = reader.lines();
+        let mut current: Vec<(HashMap<String, String>, TimeSeries, Vec<u8>)> = Vec::new();
+
+        let read_error = |e| transient(format!("Failed to read source data: {e}"));
+
+        while let Some(mut line) = lines.next_line().await.map_err(read_error)? {
+            if line.is_empty() {
+                continue;
+            }
+
+            let samples = match format {
+                Format::Json => {
+                    let time_series: TimeSeries = serde_json::from_str(&line).map_err(|e| format!(
+                        "Got an invalid time series ({e}): {line}"))?;
+
+                    let mut data = line.into_bytes();
+                    data.push(b'\n');
+
+                    yield (time_series, data);
+                    continue;
+                },
+                Format::Csv(ref columns) => {
+                    // Quoted fields may contain line breaks
+                    while !csv::is_complete_row(&line) {
+                        let Some(next) = lines.next_line().await.map_err(read_error)? else {
+                            break;
+                        };
+                        line.push('\n');
+                        line.push_str(&next);
+                    }
+
+                    let sample = csv::parse_row(columns, &line)?;
+                    vec![sample]
+                },
+                Format::Prometheus => prometheus::parse_line(&line)?.into_iter().collect(),
+                Format::Influx(ref mapping) => influx::parse_line(mapping, &line)?,
+                Format::Graphite(ref templates) => graphite::parse_line(templates, &line)?.into_iter().collect(),
+                Format::Native | Format::Remote => Err(format!("{} format is not line-based", format.name()))?,
+            };
+
+            if samples.is_empty() {
+                continue;
+            }
+
+            let same = samples.len() == current.len() && samples.iter().zip(&current).all(
+                |((labels, _, _), (current_labels, _, _))| labels == current_labels);
+
+            if same {
+                for ((_, time, value), (_, time_series, data)) in samples.into_iter().zip(&mut current) {
+                    time_series.add(time, value);
+                    data.extend(line.as_bytes());
+                    data.push(b'\n');
+                }
+                continue;
+            }
+
+            for (_, time_series, data) in current.drain(..) {
+                yield (time_series, data);
+            }
+
+            for (labels, time, value) in samples {
+                let mut time_series = TimeSeries::new(labels.clone());
+                time_series.add(time, value);
+
+                let mut data = line.clone().into_bytes();
+                data.push(b'\n');
+
+                current.push((labels, time_series, data));
+            }
+        }
+
+        for (_, time_series, data) in current {
+            yield (time_series, data);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,123 @@
+use crate::diff::Series;
+use crate::metrics::TimeSeries;
+
+// A range of samples copied from the secondary time series into a gap of the primary one
+pub struct Fill {
+    pub metric: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub samples: usize,
+}
+
+// Finds the secondary samples which are missing in the primary time series: the ones falling into the intervals between
+// primary samples longer than the minimum gap (any missing timestamp if it's zero). If the minimum gap isn't specified,
+// it's 1.5 of the primary time series scrape interval, so a single missed scrape is a gap, but scrape jitter isn't.
+// Samples before the first and after the last primary sample are always considered missing.
+pub fn find(primary: &Series, secondary: Series, min_gap: Option<i64>) -> (Vec<TimeSeries>, Vec<Fill>) {
+    let mut missing = Vec::new();
+    let mut fills: Vec<Fill> = Vec::new();
+
+    for (metric, mut time_series) in secondary {
+        time_series.sort();
+
+        let timestamps: Vec<i64> = primary.get(&metric).map(|primary| {
+            let mut timestamps: Vec<i64> = primary.iter().map(|(time, _value)| time).collect();
+            timestamps.sort_unstable();
+            timestamps
+        }).unwrap_or_default();
+
+        let min_gap = min_gap.unwrap_or_else(|| {
+            get_scrape_interval(&timestamps).map_or(0, |interval| interval * 3 / 2)
+        });
+        let mut result = time_series.clone_empty();
+        let mut last_gap = None;
+
+        for (time, value) in time_series.iter() {
+            let index = timestamps.partition_point(|&primary_time| primary_time < time);
+
+            let is_missing = timestamps.get(index) != Some(&time) && match (
+                index.checked_sub(1).map(|index| timestamps[index]), timestamps.get(index),
+            ) {
+                (Some(previous), Some(&next)) => next - previous > min_gap,
+                _ => true,
+            };
+
+            if !is_missing {
+                last_gap = None;
+                continue;
+            }
+
+            result.add(time, value);
+
+            match fills.last_mut() {
+                Some(fill) if last_gap == Some(index) => {
+                    fill.end_time = time;
+                    fill.samples += 1;
+                },
+                _ => fills.push(Fill {metric: metric.clone(), start_time: time, end_time: time, samples: 1}),
+            }
+
+            last_gap = Some(index);
+        }
+
+        if !result.is_empty() {
+            missing.push(result);
+        }
+    }
+
+    (missing, fills)
+}
+
+// Detects the scrape interval as the median interval between the samples
+fn get_scrape_interval(timestamps: &[i64]) -> Option<i64> {
+    let mut intervals: Vec<i64> = timestamps.windows(2).map(|pair| pair[1] - pair[0]).filter(|&interval| interval > 0)
+        .collect();
+
+    if intervals.is_empty() {
+        return None;
+    }
+
+    let middle = intervals.len() / 2;
+    Some(*intervals.select_nth_unstable(middle).1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn series(times: &[i64]) -> Series {
+        let mut time_series = TimeSeries::new(HashMap::from([("__name__".to_owned(), "up".to_owned())]));
+        for &time in times {
+            time_series.add(time, Some(1.0));
+        }
+
+        let mut series = Series::default();
+        series.add(time_series);
+        series
+    }
+
+    fn filled(primary: &[i64], secondary: &[i64], min_gap: Option<i64>) -> Vec<(i64, i64, usize)> {
+        let (_, fills) = find(&series(primary), series(secondary), min_gap);
+        fills.iter().map(|fill| (fill.start_time, fill.end_time, fill.samples)).collect()
+    }
+
+    #[test]
+    fn scrape_interval() {
+        // Secondary scrapes are shifted relative to primary ones and the primary has missed two scrapes
+        let primary = [0, 10_000, 20_500, 29_500, 60_000, 70_000];
+        let secondary = [5_000, 15_000, 25_000, 35_000, 45_000, 55_000, 65_000, 75_000];
+
+        assert_eq!(get_scrape_interval(&primary), Some(10_000));
+        assert_eq!(filled(&primary, &secondary, None), [(35_000, 55_000, 3), (75_000, 75_000, 1)]);
+        assert_eq!(filled(&primary, &secondary, Some(0)).len(), 6);
+        assert_eq!(filled(&primary, &secondary, Some(60_000)), [(75_000, 75_000, 1)]);
+    }
+
+    #[test]
+    fn missing_series() {
+        assert_eq!(filled(&[], &[1000, 2000], None), [(1000, 2000, 2)]);
+        assert_eq!(get_scrape_interval(&[1000]), None);
+    }
+}
\ No newline at end of file
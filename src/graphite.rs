@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use crate::core::GenericResult;
+use crate::metrics::LabeledSample;
+
+// Graphite plaintext protocol: `path[;tag=value...] value timestamp` line per sample with timestamp in seconds. The
+// dotted path is mapped to metric name and labels by the first template with matching filter. Paths which don't match
+// any template are converted to metric name as is with dots replaced by underscores.
+
+#[derive(PartialEq)]
+pub struct Templates {
+    templates: Vec<Template>,
+}
+
+#[derive(PartialEq)]
+struct Template {
+    filter: Vec<String>,
+    parts: Vec<Part>,
+    labels: Vec<(String, String)>,
+}
+
+// Name parts are joined with underscores and label parts with dots. Rest parts consume all the remaining path parts.
+#[derive(PartialEq)]
+enum Part {
+    Skip,
+    Name,
+    NameRest,
+    Label(String),
+    LabelRest(String),
+}
+
+impl Templates {
+    // Each template is `[filter ]template[ label=value,...]`, where filter is a dotted path pattern with `*` wildcards
+    // and template is a dotted list of `__name__` or label names (suffixed with `*` to consume the rest of the path)
+    // or empty parts to skip, like `servers.* .host.__name__*`.
+    pub fn parse(templates: &[&str]) -> GenericResult<Templates> {
+        let mut result = Vec::new();
+
+        for &spec in templates {
+            let invalid = || format!("Invalid Graphite template: {spec:?}");
+
+            let mut fields: Vec<&str> = spec.split_whitespace().collect();
+
+            let mut labels = Vec::new();
+            if fields.len() > 1 && fields[fields.len() - 1].contains('=') {
+                for label in fields.pop().unwrap().split(',') {
+                    match label.split_once('=') {
+                        Some((name, value)) if !name.is_empty() && name != "__name__" && !value.is_empty() => {
+                            labels.push((name.to_owned(), value.to_owned()));
+                        },
+                        _ => return Err(invalid().into()),
+                    }
+                }
+            }
+
+            let (filter, template) = match fields.as_slice() {
+                [template] => (Vec::new(), *template),
+                [filter, template] => (filter.split('.').map(ToOwned::to_owned).collect(), *template),
+                _ => return Err(invalid().into()),
+            };
+
+            let mut parts = Vec::new();
+
+            for part in template.split('.') {
+                if matches!(parts.last(), Some(Part::NameRest | Part::LabelRest(_))) {
+                    return Err(invalid().into());
+                }
+
+                parts.push(match part {
+                    "" => Part::Skip,
+                    "__name__" => Part::Name,
+                    "__name__*" => Part::NameRest,
+                    _ => match part.strip_suffix('*') {
+                        Some(name) if !name.is_empty() => Part::LabelRest(name.to_owned()),
+                        Some(_) => return Err(invalid().into()),
+                        None => Part::Label(part.to_owned()),
+                    },
+                });
+            }
+
+            if !parts.iter().any(|part| matches!(part, Part::Name | Part::NameRest)) {
+                return Err!("Invalid Graphite template: {spec:?} (__name__ part is missing)");
+            }
+
+            result.push(Template {filter, parts, labels});
+        }
+
+        Ok(Templates {templates: result})
+    }
+
+    fn apply(&self, path: &str) -> GenericResult<HashMap<String, String>> {
+        let path: Vec<&str> = path.split('.').collect();
+
+        let Some(template) = self.templates.iter().find(|template| {
+            template.filter.len() <= path.len() &&
+                template.filter.iter().zip(&path).all(|(pattern, part)| matches_pattern(pattern, part))
+        }) else {
+            let mut labels = HashMap::new();
+            labels.insert("__name__".to_owned(), path.join("_"));
+            return Ok(labels);
+        };
+
+        let mut name: Vec<&str> = Vec::new();
+        let mut labels: HashMap<String, Vec<&str>> = HashMap::new();
+
+        for (index, part) in template.parts.iter().enumerate() {
+            let Some(&value) = path.get(index) else {
+                break;
+            };
+
+            match part {
+                Part::Skip => {},
+                Part::Name => name.push(value),
+                Part::NameRest => name.extend(&path[index..]),
+                Part::Label(label) => labels.entry(label.clone()).or_default().push(value),
+                Part::LabelRest(label) => labels.entry(label.clone()).or_default().extend(&path[index..]),
+            }
+        }
+
+        if name.is_empty() {
+            return Err!("Unable to get metric name of {:?} using the Graphite templates", path.join("."));
+        }
+
+        let mut result: HashMap<String, String> = template.labels.iter().cloned().collect();
+        result.extend(labels.into_iter().map(|(label, values)| (label, values.join("."))));
+        result.insert("__name__".to_owned(), name.join("_"));
+
+        Ok(result)
+    }
+}
+
+// Parses a line into sample labels, time and value. Returns None for comments and empty lines.
+pub fn parse_line(templates: &Templates, line: &str) -> GenericResult<Option<LabeledSample>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let invalid = || format!("Got an invalid Graphite line: {line}");
+
+    let mut fields = line.split_whitespace();
+    let (Some(path), Some(value), Some(time), None) = (
+        fields.next(), fields.next(), fields.next(), fields.next(),
+    ) else {
+        return Err!("Got an invalid Graphite line (a value with timestamp is expected): {line}");
+    };
+
+    let mut tags = path.split(';');
+    let path = tags.next().unwrap_or_default();
+    if path.is_empty() || path.split('.').any(str::is_empty) {
+        return Err(invalid().into());
+    }
+
+    let mut labels = templates.apply(path)?;
+
+    for tag in tags {
+        match tag.split_once('=') {
+            Some((name, value)) if !name.is_empty() && name != "__name__" => if !value.is_empty() {
+                labels.insert(name.to_owned(), value.to_owned());
+            },
+            _ => return Err(invalid().into()),
+        }
+    }
+
+    let value: f64 = value.parse().map_err(|_| invalid())?;
+    let time: f64 = time.parse().map_err(|_| invalid())?;
+
+    Ok(Some((labels, (time * 1000.0).round() as i64, if value.is_nan() {None} else {Some(value)})))
+}
+
+fn matches_pattern(pattern: &str, part: &str) -> bool {
+    let mut pieces = pattern.split('*');
+    let first = pieces.next().unwrap_or_default();
+
+    let Some(mut rest) = part.strip_prefix(first) else {
+        return false;
+    };
+
+    let pieces: Vec<&str> = pieces.collect();
+    let Some((last, middle)) = pieces.split_last() else {
+        return rest.is_empty();
+    };
+
+    for piece in middle {
+        match rest.find(piece) {
+            Some(index) => rest = &rest[index + piece.len()..],
+            None => return false,
+        }
+    }
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn templates() {
+        let templates = Templates::parse(&[
+            "servers.*.cpu .host.__name__*",
+            "app-* .__name__.env. dc=eu",
+        ]).unwrap();
+
+        let parse = |line: &str| parse_line(&templates, line).unwrap().unwrap();
+
+        assert_eq!(parse("servers.web1.cpu.idle 12.5 1700000000"),
+                   (labels(&[("__name__", "cpu_idle"), ("host", "web1")]), 1_700_000_000_000, Some(12.5)));
+        assert_eq!(parse("app-api.requests.prod.extra 1 1700000000.25"),
+                   (labels(&[("__name__", "requests"), ("env", "prod"), ("dc", "eu")]), 1_700_000_000_250, Some(1.0)));
+        assert_eq!(parse("other.metric.total;job=node;empty= nan 1700000000"),
+                   (labels(&[("__name__", "other_metric_total"), ("job", "node")]), 1_700_000_000_000, None));
+        // The path is too short to get the metric name from it
+        assert!(Templates::parse(&["servers.* .host.__name__"]).unwrap().apply("servers.web1").is_err());
+
+        for templates in [&[".host"][..], &[".__name__*.host"], &["host*"], &["__name__ job=a,=b"], &["a b c"]] {
+            assert!(Templates::parse(templates).is_err(), "{templates:?}");
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let templates = Templates::parse(&[]).unwrap();
+
+        assert_eq!(parse_line(&templates, "# comment").unwrap(), None);
+        for line in ["metric 1", "metric 1 2 3", "metric x 1000", "metric..total 1 1000", "metric;job 1 1000"] {
+            assert!(parse_line(&templates, line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn patterns() {
+        for (pattern, part, matches) in [
+            ("*", "", true), ("web*", "web1", true), ("*-api-*", "eu-api-1", true), ("a*b*c", "abc", true),
+            ("web*", "db1", false), ("*-api", "api", false), ("a*bc", "ac", false), ("web", "web1", false),
+        ] {
+            assert_eq!(matches_pattern(pattern, part), matches, "{pattern} {part}");
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use crate::core::GenericResult;
+use crate::metrics::LabeledSample;
+
+// InfluxDB line protocol: `measurement[,tag=value...] field=value[,field=value...] timestamp` line per point. Each
+// numeric or boolean field becomes a sample of the time series named by the template with the point's tags as labels.
+// String fields are skipped.
+
+pub const DEFAULT_TEMPLATE: &str = "{measurement}_{field}";
+
+#[derive(PartialEq)]
+pub struct Mapping {
+    name: String,
+    labels: Vec<(String, String)>,
+    precision: Precision,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Mapping {
+    // Template is a metric name optionally followed by a space and comma-separated list of labels, where both may
+    // refer to {measurement} and {field}, like `{measurement} field={field}`.
+    pub fn parse(template: &str, precision: Precision) -> GenericResult<Mapping> {
+        let invalid = || format!("Invalid InfluxDB template: {template:?}");
+
+        let (name, labels) = match template.trim().split_once(char::is_whitespace) {
+            Some((name, labels)) => (name, Some(labels.trim())),
+            None => (template.trim(), None),
+        };
+
+        if name.is_empty() {
+            return Err(invalid().into());
+        }
+
+        let mut mapping = Mapping {name: name.to_owned(), labels: Vec::new(), precision};
+
+        for label in labels.into_iter().flat_map(|labels| labels.split(',')) {
+            match label.split_once('=') {
+                Some((name, value)) if !name.is_empty() && name != "__name__" => {
+                    mapping.labels.push((name.to_owned(), value.to_owned()));
+                },
+                _ => return Err(invalid().into()),
+            }
+        }
+
+        Ok(mapping)
+    }
+}
+
+// Parses a line into samples of its fields. Returns no samples for comments and empty lines.
+pub fn parse_line(mapping: &Mapping, line: &str) -> GenericResult<Vec<LabeledSample>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Vec::new());
+    }
+
+    let invalid = || format!("Got an invalid InfluxDB line: {line}");
+
+    let (series, rest) = split_once(line, ' ', false).ok_or_else(invalid)?;
+    let (fields, time) = split_once(rest.trim_start(), ' ', true).ok_or_else(|| format!(
+        "Got an invalid InfluxDB line (a timestamp is expected): {line}"))?;
+
+    let mut series = split(series, ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err(invalid().into());
+    }
+
+    let mut tags = HashMap::new();
+    for tag in series {
+        let (name, value) = split_once(tag, '=', false).ok_or_else(invalid)?;
+        let (name, value) = (unescape(name), unescape(value));
+        if name.is_empty() {
+            return Err(invalid().into());
+        }
+
+        if !value.is_empty() {
+            tags.insert(name, value);
+        }
+    }
+
+    let time: i64 = time.trim().parse().map_err(|_| invalid())?;
+    let time = match mapping.precision {
+        Precision::Nanoseconds => time.div_euclid(1_000_000),
+        Precision::Microseconds => time.div_euclid(1_000),
+        Precision::Milliseconds => time,
+        Precision::Seconds => time.saturating_mul(1000),
+    };
+
+    let mut samples = Vec::new();
+
+    for field in split(fields, ',', true) {
+        let (field, value) = split_once(field, '=', true).ok_or_else(invalid)?;
+        let field = unescape(field);
+
+        let value = match value {
+            _ if value.starts_with('"') => continue,
+            "t" | "T" | "true" | "True" | "TRUE" => 1.0,
+            "f" | "F" | "false" | "False" | "FALSE" => 0.0,
+            _ => match value.strip_suffix(['i', 'u']) {
+                Some(value) => value.parse::<i128>().map_err(|_| invalid())? as f64,
+                None => value.parse().map_err(|_| invalid())?,
+            },
+        };
+
+        let render = |template: &str| template.replace("{measurement}", &measurement).replace("{field}", &field);
+
+        let mut labels = tags.clone();
+        labels.insert("__name__".to_owned(), render(&mapping.name));
+
+        for (name, value) in &mapping.labels {
+            let value = render(value);
+            if value.is_empty() {
+                labels.remove(name);
+            } else {
+                labels.insert(name.clone(), value);
+            }
+        }
+
+        samples.push((labels, time, if value.is_nan() {None} else {Some(value)}));
+    }
+
+    Ok(samples)
+}
+
+// Splits the text by the separator which isn't escaped by backslash (or inside a quoted string)
+fn split(text: &str, separator: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+
+    while let Some((part, next)) = split_once(rest, separator, quotes) {
+        parts.push(part);
+        rest = next;
+    }
+
+    parts.push(rest);
+    parts
+}
+
+fn split_once(text: &str, separator: char, quotes: bool) -> Option<(&str, &str)> {
+    let (mut escaped, mut quoted) = (false, false);
+
+    for (index, char) in text.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            _ if char == separator && !quoted => return Some((&text[..index], &text[index + 1..])),
+            _ => {},
+        }
+    }
+
+    None
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        match (char, chars.peek()) {
+            ('\\', Some(&next @ (',' | '=' | ' ' | '"' | '\\'))) => {
+                result.push(next);
+                chars.next();
+            },
+            _ => result.push(char),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let mapping = Mapping::parse(DEFAULT_TEMPLATE, Precision::Nanoseconds).unwrap();
+
+        let mut samples = parse_line(&mapping, concat!(
+            r#"cpu\ load,host=server\,1,empty=,region=us\=west "#,
+            r#"value=0.5,count=10i,total=3u,up=true,down=F,message="a, b=c d",nan=NaN 1700000000123456789"#,
+        )).unwrap();
+        samples.sort_by(|a, b| a.0["__name__"].cmp(&b.0["__name__"]));
+
+        let tags = [("host", "server,1"), ("region", "us=west")];
+        let expected: Vec<_> = [
+            ("cpu load_count", Some(10.0)), ("cpu load_down", Some(0.0)), ("cpu load_nan", None),
+            ("cpu load_total", Some(3.0)), ("cpu load_up", Some(1.0)), ("cpu load_value", Some(0.5)),
+        ].into_iter().map(|(name, value)| {
+            let mut labels = labels(&tags);
+            labels.insert("__name__".to_owned(), name.to_owned());
+            (labels, 1_700_000_000_123, value)
+        }).collect();
+        assert_eq!(samples, expected);
+
+        assert!(parse_line(&mapping, "# comment").unwrap().is_empty());
+        for line in ["cpu value=1", "cpu value=x 1000", "cpu,host value=1 1000", ",host=a value=1 1000", "cpu 1000"] {
+            assert!(parse_line(&mapping, line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn mapping() {
+        let mapping = Mapping::parse("{measurement} field={field},host=", Precision::Seconds).unwrap();
+        assert_eq!(
+            parse_line(&mapping, "cpu,host=a,dc=b value=1 1700000000").unwrap(),
+            [(labels(&[("__name__", "cpu"), ("field", "value"), ("dc", "b")]), 1_700_000_000_000, Some(1.0))],
+        );
+
+        let mapping = Mapping::parse("{measurement}", Precision::Microseconds).unwrap();
+        assert_eq!(parse_line(&mapping, "cpu value=1 -1500").unwrap()[0].1, -2);
+
+        for template in ["", "name job", "name __name__=a", "name =a"] {
+            assert!(Mapping::parse(template, Precision::Seconds).is_err(), "{template:?}");
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,126 @@
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use tokio::fs::File;
+use tokio::io::{self, AsyncBufRead, AsyncWrite, BufReader, BufWriter};
+use url::Url;
+
+use crate::core::GenericResult;
+
+pub type Reader = Box<dyn AsyncBufRead + Send + Unpin>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+// Data source or target: VictoriaMetrics URL, Prometheus remote read (source only) or remote write (target only)
+// endpoint URL with remote-read+ or remote-write+ scheme prefix, Prometheus TSDB directory URL with tsdb+file scheme,
+// Parquet file or directory URL with parquet+file scheme (target only), file:// URL or `-` for stdin/stdout
+#[derive(Clone)]
+pub enum Location {
+    VictoriaMetrics(Url),
+    RemoteRead(Url),
+    RemoteWrite(Url),
+    Tsdb(PathBuf),
+    Parquet(PathBuf),
+    File(PathBuf),
+    Stdio,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Location {
+    pub fn parse(location: &str) -> GenericResult<Location> {
+        if location == "-" {
+            return Ok(Location::Stdio);
+        }
+
+        let parse_url = |url| Url::parse(url).map_err(|e| format!("Invalid URL: {location:?}: {e}"));
+        let url = parse_url(location)?;
+
+        Ok(match url.scheme() {
+            "http" | "https" => Location::VictoriaMetrics(url),
+            "remote-read+http" | "remote-read+https" => Location::RemoteRead(parse_url(
+                location.split_once('+').unwrap().1)?),
+            "remote-write+http" | "remote-write+https" => Location::RemoteWrite(parse_url(
+                location.split_once('+').unwrap().1)?),
+            "file" => Location::File(url.to_file_path().map_err(|_| format!("Invalid file URL: {location:?}"))?),
+            "tsdb+file" => Location::Tsdb(parse_url(location.split_once('+').unwrap().1)?.to_file_path().map_err(|_| {
+                format!("Invalid file URL: {location:?}")
+            })?),
+            "parquet+file" => Location::Parquet(parse_url(location.split_once('+').unwrap().1)?.to_file_path().map_err(
+                |_| format!("Invalid file URL: {location:?}"))?),
+            scheme => return Err!("Unsupported URL scheme: {scheme:?}"),
+        })
+    }
+
+    // Detects compression by file extension
+    pub fn compression(&self) -> Compression {
+        let Location::File(path) = self else {
+            return Compression::None;
+        };
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    pub async fn open(&self, compression: Compression) -> GenericResult<Reader> {
+        let reader: Reader = match self {
+            Location::VictoriaMetrics(_) | Location::RemoteRead(_) | Location::RemoteWrite(_) | Location::Tsdb(_) |
+            Location::Parquet(_) => unreachable!(),
+            Location::File(path) => Box::new(BufReader::new(File::open(path).await.map_err(|e| format!(
+                "Unable to open {path:?}: {e}"))?)),
+            Location::Stdio => Box::new(BufReader::new(io::stdin())),
+        };
+
+        Ok(match compression {
+            Compression::None => reader,
+            Compression::Gzip => Box::new(BufReader::new(GzipDecoder::new(reader))),
+            Compression::Zstd => Box::new(BufReader::new(ZstdDecoder::new(reader))),
+        })
+    }
+
+    pub async fn create(&self, compression: Compression) -> GenericResult<Writer> {
+        let writer: Writer = match self {
+            Location::VictoriaMetrics(_) | Location::RemoteRead(_) | Location::RemoteWrite(_) | Location::Tsdb(_) |
+            Location::Parquet(_) => unreachable!(),
+            Location::File(path) => Box::new(BufWriter::new(File::create(path).await.map_err(|e| format!(
+                "Unable to create {path:?}: {e}"))?)),
+            Location::Stdio => Box::new(BufWriter::new(io::stdout())),
+        };
+
+        Ok(match compression {
+            Compression::None => writer,
+            Compression::Gzip => Box::new(GzipEncoder::new(writer)),
+            Compression::Zstd => Box::new(ZstdEncoder::new(writer)),
+        })
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::VictoriaMetrics(url) => format_url(url).fmt(f),
+            Location::RemoteRead(url) => write!(f, "remote-read+{}", format_url(url)),
+            Location::RemoteWrite(url) => write!(f, "remote-write+{}", format_url(url)),
+            Location::Tsdb(path) => write!(f, "tsdb+file://{}", path.display()),
+            Location::Parquet(path) => write!(f, "parquet+file://{}", path.display()),
+            Location::File(path) => path.display().fmt(f),
+            Location::Stdio => "-".fmt(f),
+        }
+    }
+}
+
+// Don't expose the credentials
+fn format_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    let _ = url.set_password(None);
+    url
+}
\ No newline at end of file
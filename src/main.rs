@@ -1,18 +1,57 @@
 #[macro_use] mod core;
+mod backup;
+mod checkpoint;
+mod csv;
+mod diff;
+mod digest;
+mod formats;
+mod gaps;
+mod graphite;
+mod influx;
+mod location;
 mod metrics;
+mod migrations;
 mod migrator;
+mod native;
+mod parquet;
 mod processor;
+mod prometheus;
+mod remote;
+mod retry;
+mod rules;
+mod selector;
 mod stat;
+mod time;
+mod tsdb;
+mod varint;
+mod xor;
 
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 
 use clap::{Arg, ArgAction, Command, value_parser};
-use easy_logging::LoggingConfig;
-use log::{Level, error};
+use easy_logging::{LoggingConfig, fern};
+use log::{Level, debug, error, info};
 use url::Url;
 
-use crate::core::GenericResult;
+use crate::core::{EmptyResult, GenericResult};
+use crate::migrations::{Migration, State};
+use crate::migrator::Migrator;
+use crate::csv::Columns;
+use crate::diff::{Series, Tolerance};
+use crate::digest::{Difference, Digests};
+use crate::formats::Format;
+use crate::graphite::Templates;
+use crate::influx::{Mapping, Precision};
+use crate::location::{Compression, Location};
+use crate::processor::Options;
+use crate::rules::RuleSet;
+use crate::selector::Selector;
+use crate::stat::MigrationStat;
 
 fn main() -> ExitCode {
     let config = match parse_args() {
@@ -23,7 +62,19 @@ fn main() -> ExitCode {
         }
     };
 
-    if let Err(err) = LoggingConfig::new(module_path!(), config.log_level).minimal().build() {
+    // Don't mix the logs with the data written to stdout
+    let logging = if matches!(config.target, Some(Location::Stdio)) {
+        fern::Dispatch::new()
+            .level(log::LevelFilter::Off)
+            .level_for(module_path!(), config.log_level.to_level_filter())
+            .format(|out, message, _record| out.finish(format_args!("{message}")))
+            .chain(io::stderr())
+            .apply()
+    } else {
+        LoggingConfig::new(module_path!(), config.log_level).minimal().build()
+    };
+
+    if let Err(err) = logging {
         let _ = writeln!(io::stderr(), "Failed to initialize the logging: {err}.");
         return ExitCode::FAILURE;
     }
@@ -34,7 +85,7 @@ fn main() -> ExitCode {
         std::process::abort();
     }));
 
-    if let Err(err) = processor::process(&config.source, config.start_time.as_deref(), config.target.as_ref()) {
+    if let Err(err) = run(config) {
         error!("{err}.");
         return ExitCode::FAILURE;
     }
@@ -42,14 +93,573 @@ fn main() -> ExitCode {
     ExitCode::SUCCESS
 }
 
+fn run(config: Config) -> EmptyResult {
+    let mut migrations = Vec::new();
+    let mut state = None;
+
+    if let Some(ref path) = config.migrations {
+        let state_path = config.state.as_ref().unwrap();
+        let migration_state = State::load(state_path)?;
+
+        if let Some(version) = config.rollback {
+            migrations = migration_state.applied_since(migrations::load(path)?, version)?
+                .into_iter().map(Migration::invert).collect::<GenericResult<_>>()?;
+
+            if migrations.is_empty() {
+                return Err!("There are no applied migrations to roll back");
+            }
+
+            info!("Rolling back migrations: {}.", migrations.iter().map(Migration::id).collect::<Vec<_>>().join(", "));
+        } else if matches!(config.mode, Mode::Verify) {
+            // The target data has been migrated with the recorded migrations (the ones the source data already has are
+            // skipped with --since) or, if the migration hasn't been recorded, with the pending ones
+            migrations = migration_state.applied(migrations::load(path)?, config.since.unwrap_or_default())?;
+            migrations.extend(migration_state.pending(migrations::load(path)?)?);
+
+            if migrations.is_empty() {
+                info!("There are no migrations to verify.");
+            } else {
+                info!("Verifying migrations: {}.", migrations.iter().map(Migration::id).collect::<Vec<_>>().join(", "));
+            }
+        } else {
+            migrations = migration_state.pending(migrations::load(path)?)?;
+
+            if migrations.is_empty() {
+                info!("There are no pending migrations.");
+            } else {
+                info!("Pending migrations: {}.", migrations.iter().map(Migration::id).collect::<Vec<_>>().join(", "));
+            }
+        }
+
+        state = Some((migration_state, state_path));
+    }
+
+    let rules = match config.rules {
+        Some(ref path) => {
+            let mut rules = RuleSet::load(path)?;
+            debug!("Loaded {} migration rules.", rules.len());
+
+            if config.reverse {
+                rules = rules.invert()?;
+                debug!("Inverted rules:\n{}", rules.format()?.trim_end());
+            }
+
+            Some(rules)
+        },
+        None => None,
+    };
+
+    // The built-in migration can't be reverted, so don't apply it on rollback. Backup and restore transfer the data as
+    // is unless the rules are specified explicitly. In-place migration requires the rules to select the affected time
+    // series.
+    let builtin = !config.reverse && config.rollback.is_none() && matches!(config.mode, Mode::Migrate | Mode::Verify);
+
+    if let Mode::Restore(ref archive) = config.mode {
+        let manifest = backup::extract(archive)?;
+        info!("Restoring {} time series ({} samples) backed up from {} at {}.",
+            manifest.series, manifest.samples, manifest.source, manifest.created.format("%Y-%m-%d %H:%M:%S"));
+    }
+
+    let source = config.source.to_string();
+    let migrator = Arc::new(Migrator::new(migrations, rules, builtin));
+    let options = Options {
+        source: config.source,
+        target: config.target,
+        compression: config.compression,
+        source_format: config.source_format,
+        target_format: config.target_format,
+        start_time: config.start_time,
+        end_time: config.end_time,
+        window: config.window,
+        jobs: config.jobs,
+        checkpoint: config.checkpoint,
+        resume: config.resume,
+        retries: config.retries,
+        batch_lines: config.batch_lines,
+        batch_size: config.batch_size,
+        import_jobs: config.import_jobs,
+        selectors: config.selectors,
+        metrics: None,
+        export_params: config.export_params,
+        import_params: config.import_params,
+        parquet: config.parquet,
+        record: config.record,
+    };
+
+    let mut migration_stat = Vec::new();
+
+    let result = match config.mode {
+        Mode::InPlace(ref archive) => migrate_in_place(
+            options, migrator.clone(), archive.as_deref(), config.rules.as_deref(),
+        ).map(|stat| migration_stat = stat),
+        Mode::Verify => verify(options, migrator.clone()),
+        Mode::Diff(tolerance) => compare(options, tolerance, config.shard_size),
+        Mode::FillGaps(min_gap) => fill_gaps(options, min_gap, config.shard_size),
+        _ => processor::process(options, migrator.clone()).map(|stat| migration_stat = stat),
+    };
+
+    match config.mode {
+        Mode::Migrate | Mode::InPlace(_) | Mode::Verify | Mode::Diff(_) | Mode::FillGaps(_) => result?,
+        Mode::Backup(ref archive) => {
+            if result.is_err() {
+                let _ = fs::remove_file(backup::data_path(archive));
+            }
+            result?;
+
+            let rules_checksum = config.rules.as_ref().map(|path| backup::checksum(path)).transpose()?;
+            let manifest = backup::Manifest::new(
+                source, config.start_time, config.end_time,
+                migrator.migrations().iter().map(Migration::id).collect(),
+                rules_checksum.map(|(_, checksum)| checksum));
+
+            backup::create(archive, manifest)?;
+        },
+        Mode::Restore(ref archive) => {
+            let data_path = backup::data_path(archive);
+            let removed = fs::remove_file(&data_path);
+            result?;
+            removed.map_err(|e| format!("Unable to delete {data_path:?}: {e}"))?;
+        },
+    }
+
+    if config.record {
+        let (mut state, path) = state.unwrap();
+
+        for (index, migration) in migrator.migrations().iter().enumerate() {
+            if migration.rollback {
+                state.remove(migration.version);
+            } else {
+                let irreversible = migration_stat.get(index).is_some_and(|stat| stat.collisions != 0);
+                state.add(migration.version, &migration.name, irreversible);
+            }
+        }
+
+        state.save(path)?;
+    }
+
+    Ok(())
+}
+
+
+// Migrates only the time series affected by the migration in place: backs them up, checks the migration in dry-run
+// mode on the backed up data, deletes the original time series, imports the migrated ones and checks that all of them
+// have been imported. Only dry run is performed if backup archive isn't specified.
+//
+// VictoriaMetrics deletes the whole time series regardless of time range, so the samples ingested after the backup
+// would be lost: the affected time series mustn't be written during the migration.
+fn migrate_in_place(
+    options: Options, migrator: Arc<Migrator>, archive: Option<&Path>, rules: Option<&Path>,
+) -> GenericResult<Vec<MigrationStat>> {
+    let Location::VictoriaMetrics(ref url) = options.source else {
+        return Err!("In-place migration is supported only for VictoriaMetrics source");
+    };
+
+    let selectors = migrator.selectors().ok_or(
+        "In-place migration is not possible: the migration may change any time series")?;
+
+    if selectors.is_empty() {
+        info!("There is nothing to migrate.");
+        return Ok(Vec::new());
+    }
+
+    let selectors: Vec<Selector> = selectors.iter().map(|selector| Selector::parse(selector))
+        .collect::<GenericResult<_>>()?;
+    debug!("The affected time series: {}.", selectors.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "));
+
+    let Some(archive) = archive else {
+        info!("Checking the migration of the affected time series...");
+        return processor::process(Options {selectors, ..options}, migrator);
+    };
+
+    info!("Backing up the affected time series to {archive:?}...");
+    let data_path = backup::data_path(archive);
+
+    let result = processor::process(Options {
+        selectors: selectors.clone(),
+        target: Some(Location::File(data_path.clone())),
+        target_format: Format::Json,
+        import_params: Vec::new(),
+        ..options.clone()
+    }, Arc::new(Migrator::new(Vec::new(), None, false)));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&data_path);
+    }
+    result?;
+
+    let rules_checksum = rules.map(backup::checksum).transpose()?;
+    backup::create(archive, backup::Manifest::new(
+        url.to_string(), None, None,
+        migrator.migrations().iter().map(Migration::id).collect(),
+        rules_checksum.map(|(_, checksum)| checksum)))?;
+
+    // Verify the archive and use the backed up data to ensure that the migration succeeds before the deletion
+    let manifest = backup::extract(archive)?;
+    info!("{} time series ({} samples) are backed up.", manifest.series, manifest.samples);
+
+    let source_options = Options {target: None, ..options.clone()};
+
+    let result = (|| -> GenericResult<Vec<MigrationStat>> {
+        let options = Options {
+            source: Location::File(data_path.clone()),
+            target: Some(options.source.clone()),
+            source_format: Format::Json,
+            jobs: 1,
+            selectors: Vec::new(),
+            export_params: Vec::new(),
+            ..options.clone()
+        };
+
+        info!("Checking the migration of the backed up time series...");
+        let (names, expected) = count_samples(Options {target: None, ..options.clone()}, migrator.clone())?;
+
+        info!("Deleting the original time series...");
+        processor::delete_series(url, &selectors, options.retries)?;
+
+        info!("Importing the migrated time series...");
+        let retries = options.retries;
+        let stat = processor::process(options, migrator).map_err(|e| format!(
+            "{e}. The original time series can be restored from {archive:?}"))?;
+
+        processor::reset_rollup_cache(url, retries)?;
+
+        info!("Checking the imported time series...");
+        check_imported(Options {selectors: Vec::new(), ..source_options}, url, names, &expected).map_err(|e| format!(
+            "{e}. The original time series can be restored from {archive:?}"))?;
+
+        Ok(stat)
+    })();
+
+    let removed = fs::remove_file(&data_path);
+    let stat = result?;
+    removed.map_err(|e| format!("Unable to delete {data_path:?}: {e}"))?;
+
+    Ok(stat)
+}
+
+
+// Migrates the source data again and compares the result with the target data
+fn verify(options: Options, migrator: Arc<Migrator>) -> EmptyResult {
+    let target = options.target.clone().unwrap();
+
+    if let Location::VictoriaMetrics(ref url) = target {
+        info!("Flushing the target data...");
+        processor::force_flush(url, options.retries)?;
+    }
+
+    let digest = |options: Options, migrator: Arc<Migrator>| -> GenericResult<Digests> {
+        let digests = Arc::new(Mutex::new(Digests::default()));
+        let consumer = digests.clone();
+
+        processor::consume(options, migrator, Box::new(move |time_series| {
+            consumer.lock().unwrap().add(&time_series);
+        }))?;
+
+        Ok(Arc::into_inner(digests).unwrap().into_inner().unwrap())
+    };
+
+    info!("Migrating the source data...");
+    let expected = digest(options.clone(), migrator)?;
+
+    // Files contain only the migrated data, so time range and jobs options apply only to VictoriaMetrics target
+    let is_database = matches!(target, Location::VictoriaMetrics(_));
+
+    info!("Reading the target data...");
+    let mut actual = digest(Options {
+        source: target,
+        source_format: options.target_format.clone(),
+        start_time: options.start_time.filter(|_| is_database),
+        end_time: options.end_time.filter(|_| is_database),
+        window: options.window.filter(|_| is_database),
+        jobs: if is_database {options.jobs} else {1},
+        export_params: Vec::new(),
+        ..options
+    }, Arc::new(Migrator::new(Vec::new(), None, false)))?;
+
+    // Migration markers are written to the target only
+    actual.retain(|metric| !metric.starts_with("vm_migrate_"));
+
+    let (mut missing, mut extra, mut mismatched) = (0, 0, 0);
+
+    for difference in expected.compare(&actual) {
+        match difference {
+            Difference::Missing(metric, digest) => {
+                info!("Missing: {metric} ({} samples)", digest.samples);
+                missing += 1;
+            },
+            Difference::Extra(metric, digest) => {
+                info!("Extra: {metric} ({} samples)", digest.samples);
+                extra += 1;
+            },
+            Difference::Mismatch(metric, expected, actual) => {
+                if expected.samples == actual.samples {
+                    info!("Mismatch: {metric} (sample values differ)");
+                } else {
+                    info!("Mismatch: {metric} ({} samples expected, got {})", expected.samples, actual.samples);
+                }
+                mismatched += 1;
+            },
+        }
+    }
+
+    if missing + extra + mismatched != 0 {
+        return Err!(
+            "Verification failed: {missing} missing, {extra} extra and {mismatched} mismatched time series out of {}",
+            expected.len());
+    }
+
+    info!("{} time series are verified.", expected.len());
+    Ok(())
+}
+
+// Compares the source and target data as is, shard by shard
+fn compare(options: Options, tolerance: Tolerance, shard_size: usize) -> EmptyResult {
+    let target_options = Options {
+        source: options.target.clone().unwrap(),
+        source_format: options.target_format.clone(),
+        ..options.clone()
+    };
+
+    let shards = get_shards(&options, &target_options, shard_size)?;
+    let (mut total, mut source_only, mut target_only, mut different) = (0, 0, 0, 0);
+
+    for metrics in shards {
+        if metrics.is_none() {
+            info!("Reading the source data...");
+        }
+        let source = collect_series(Options {metrics: metrics.clone(), ..options.clone()})?;
+        total += source.len();
+
+        if metrics.is_none() {
+            info!("Reading the target data...");
+        }
+        let target = collect_series(Options {metrics, ..target_options.clone()})?;
+
+        for difference in source.compare(target, tolerance) {
+            match difference {
+                diff::Difference::SourceOnly(metric, samples) => {
+                    info!("Source only: {metric} ({samples} samples)");
+                    source_only += 1;
+                },
+                diff::Difference::TargetOnly(metric, samples) => {
+                    info!("Target only: {metric} ({samples} samples)");
+                    target_only += 1;
+                },
+                diff::Difference::Samples(difference) => {
+                    let mut details = Vec::new();
+
+                    if difference.source_samples != difference.target_samples {
+                        details.push(format!(
+                            "{} samples in source, {} in target",
+                            difference.source_samples, difference.target_samples));
+                    }
+
+                    if difference.source_only != 0 || difference.target_only != 0 {
+                        details.push(format!(
+                            "{} timestamps only in source, {} only in target",
+                            difference.source_only, difference.target_only));
+                    }
+
+                    if let Some((time, source_value, target_value)) = difference.first_mismatch {
+                        let format = |value: Option<f64>| value.map_or_else(
+                            || "null".to_owned(), |value| value.to_string());
+                        details.push(format!(
+                            "{} mismatched values, first at {}: {} != {}",
+                            difference.mismatched, time::format_time(time),
+                            format(source_value), format(target_value)));
+                    }
+
+                    info!("Different: {} ({})", difference.metric, details.join("; "));
+                    different += 1;
+                },
+            }
+        }
+    }
+
+    if source_only + target_only + different != 0 {
+        return Err!(concat!(
+            "The data differs: {} time series only in source, {} only in target and {} different out of {} ",
+            "source time series"), source_only, target_only, different, total);
+    }
+
+    info!("{total} time series are identical.");
+    Ok(())
+}
+
+// Copies the samples missing in the target (primary) instance from the source (secondary) one, shard by shard
+fn fill_gaps(options: Options, min_gap: Option<i64>, shard_size: usize) -> EmptyResult {
+    let primary_options = Options {
+        source: options.target.clone().unwrap(),
+        source_format: options.target_format.clone(),
+        ..options.clone()
+    };
+
+    let shards = get_shards(&options, &primary_options, shard_size)?;
+    let (mut series, mut samples, mut ranges) = (0, 0, 0);
+
+    for metrics in shards {
+        if metrics.is_none() {
+            info!("Reading the primary data...");
+        }
+        let primary = collect_series(Options {metrics: metrics.clone(), ..primary_options.clone()})?;
+
+        if metrics.is_none() {
+            info!("Reading the secondary data...");
+        }
+        let secondary = collect_series(Options {metrics, ..options.clone()})?;
+
+        let (missing, fills) = gaps::find(&primary, secondary, min_gap);
+        if fills.is_empty() {
+            continue;
+        }
+
+        for fill in &fills {
+            info!("Fill: {} [{} - {}] ({} samples)",
+                fill.metric, time::format_time(fill.start_time), time::format_time(fill.end_time), fill.samples);
+        }
+
+        series += missing.len();
+        samples += fills.iter().map(|fill| fill.samples).sum::<usize>();
+        ranges += fills.len();
+
+        processor::import_time_series(options.clone(), missing)?;
+    }
+
+    if ranges == 0 {
+        info!("There are no gaps to fill.");
+    } else {
+        info!("{samples} samples are filled in {ranges} ranges of {series} time series.");
+    }
+
+    Ok(())
+}
+
+// Splits the data of both sides by metric names into shards of --shard-size metrics, so only one shard is held in
+// memory at a time. Only VictoriaMetrics instances can be sharded, otherwise the whole data is a single shard.
+fn get_shards(source: &Options, target: &Options, shard_size: usize) -> GenericResult<Vec<Option<Vec<String>>>> {
+    let is_database = |options: &Options| matches!(options.source, Location::VictoriaMetrics(_));
+    if !is_database(source) || !is_database(target) {
+        return Ok(vec![None]);
+    }
+
+    let mut names = processor::metric_names(source)?;
+    names.extend(processor::metric_names(target)?);
+    names.sort();
+    names.dedup();
+
+    let shards: Vec<_> = names.chunks(shard_size).map(|names| Some(names.to_vec())).collect();
+    info!("Reading {} metrics in {} shards...", names.len(), shards.len());
+
+    Ok(shards)
+}
+
+// Counts the samples of the migrated time series by their labels. Also returns their metric names.
+fn count_samples(
+    options: Options, migrator: Arc<Migrator>,
+) -> GenericResult<(BTreeSet<String>, HashMap<String, usize>)> {
+    let counts = Arc::new(Mutex::new((BTreeSet::new(), HashMap::new())));
+    let consumer = counts.clone();
+
+    processor::consume(options, migrator, Box::new(move |time_series| {
+        let (ref mut names, ref mut samples) = *consumer.lock().unwrap();
+        names.insert(time_series.name().to_owned());
+        *samples.entry(time_series.format_metric()).or_default() += time_series.len();
+    }))?;
+
+    Ok(Arc::into_inner(counts).unwrap().into_inner().unwrap())
+}
+
+// Exports the imported time series back and checks that none of their samples are missing
+fn check_imported(
+    options: Options, url: &Url, names: BTreeSet<String>, expected: &HashMap<String, usize>,
+) -> EmptyResult {
+    if expected.is_empty() {
+        return Ok(());
+    }
+
+    processor::force_flush(url, options.retries)?;
+
+    let selectors = names.iter().map(|name| Selector::parse(&format!("{{__name__={}}}", selector::quote(name))))
+        .collect::<GenericResult<_>>()?;
+
+    let migrator = Arc::new(Migrator::new(Vec::new(), None, false));
+    let (_, imported) = count_samples(Options {selectors, ..options}, migrator)?;
+
+    let (mut missing_series, mut missing_samples) = (0, 0);
+
+    for (metric, &samples) in expected {
+        let imported = imported.get(metric).copied().unwrap_or_default();
+        if imported == 0 {
+            missing_series += 1;
+        }
+        missing_samples += samples.saturating_sub(imported);
+    }
+
+    if missing_series != 0 || missing_samples != 0 {
+        return Err!(
+            "The imported data doesn't match the backup: {} time series and {} samples are missing",
+            missing_series, missing_samples);
+    }
+
+    info!("{} time series ({} samples) are imported.", expected.len(), expected.values().sum::<usize>());
+    Ok(())
+}
+
+fn collect_series(options: Options) -> GenericResult<Series> {
+    let series = Arc::new(Mutex::new(Series::default()));
+    let consumer = series.clone();
+
+    processor::consume(options, Arc::new(Migrator::new(Vec::new(), None, false)), Box::new(move |time_series| {
+        consumer.lock().unwrap().add(time_series);
+    }))?;
+
+    Ok(Arc::into_inner(series).unwrap().into_inner().unwrap())
+}
+
+enum Mode {
+    Migrate,
+    InPlace(Option<PathBuf>),
+    Verify,
+    Diff(Tolerance),
+    FillGaps(Option<i64>),
+    Backup(PathBuf),
+    Restore(PathBuf),
+}
 
 struct Config {
-    source: Url,
-    start_time: Option<String>,
-    target: Option<Url>,
+    mode: Mode,
+    source: Location,
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+    window: Option<i64>,
+    target: Option<Location>,
+    compression: Option<Compression>,
+    source_format: Format,
+    target_format: Format,
+    rules: Option<PathBuf>,
+    migrations: Option<PathBuf>,
+    state: Option<PathBuf>,
+    record: bool,
+    rollback: Option<u32>,
+    since: Option<u32>,
+    shard_size: usize,
+    reverse: bool,
+    jobs: usize,
+    checkpoint: Option<PathBuf>,
+    resume: bool,
+    retries: usize,
+    batch_lines: usize,
+    batch_size: usize,
+    import_jobs: usize,
+    selectors: Vec<Selector>,
+    export_params: Vec<(String, String)>,
+    import_params: Vec<(String, String)>,
+    parquet: parquet::Options,
     log_level: Level,
 }
 
+const FORMATS: [&str; 6] = ["json", "native", "csv", "prometheus", "influx", "graphite"];
+
 fn parse_args() -> GenericResult<Config> {
     let matches = Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -58,31 +668,232 @@ fn parse_args() -> GenericResult<Config> {
         .disable_help_subcommand(true)
         .help_expected(true)
 
-        .args([
-            Arg::new("verbose")
-                .short('v').long("verbose")
-                .action(ArgAction::Count)
-                .help("Set verbosity level"),
-
-            Arg::new("start")
-                .long("start")
-                .value_name("TIME")
-                .help("Start time (https://docs.victoriametrics.com/#timestamp-formats)"),
-
-            Arg::new("source")
-                .value_name("SOURCE")
-                .required(true)
-                .value_parser(value_parser!(Url))
-                .help("Source VictoriaMetrics URL"),
-
-            Arg::new("target")
-                .value_name("TARGET")
-                .value_parser(value_parser!(Url))
-                .help("Target VictoriaMetrics URL"),
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+
+        .args(get_common_args())
+        .args([source_arg(), target_arg(false)])
+
+        .subcommands([
+            Command::new("backup")
+                .about("Back up the source data into an archive")
+                .args(get_common_args().into_iter().filter(|arg| arg.get_id() != "record"))
+                .args([source_arg(), archive_arg()]),
+
+            Command::new("restore")
+                .about(concat!(
+                    "Restore the data from an archive with its verification (only verify the archive if target isn't ",
+                    "specified)"))
+                .args(get_common_args())
+                .args([archive_arg(), target_arg(false)]),
+
+            Command::new("in-place")
+                .about(concat!(
+                    "Migrate only the affected time series in place: back them up, delete the originals and import ",
+                    "the migrated ones"))
+                .args(get_common_args().into_iter().filter(|arg| arg.get_id() != "record"))
+                .args([
+                    source_arg(),
+
+                    Arg::new("backup")
+                        .long("backup")
+                        .value_name("ARCHIVE")
+                        .value_parser(value_parser!(PathBuf))
+                        .required_unless_present("dry_run")
+                        .conflicts_with("dry_run")
+                        .help("Back up the affected time series into the archive before deletion"),
+
+                    Arg::new("ingestion_stopped")
+                        .long("ingestion-stopped")
+                        .action(ArgAction::SetTrue)
+                        .required_unless_present("dry_run")
+                        .help(concat!(
+                            "Confirm that the affected time series aren't written during the migration (the samples ",
+                            "ingested after the backup are lost on deletion of the original time series)")),
+
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only check the migration of the affected time series without changing anything"),
+
+                    Arg::new("record")
+                        .long("record")
+                        .action(ArgAction::SetTrue)
+                        .requires_all(["migrations", "backup"])
+                        .help("Record the applied migrations in the state file on success"),
+                ]),
+
+            Command::new("verify")
+                .about(concat!(
+                    "Verify the migration: migrate the source data again and compare the result with the target data ",
+                    "by sample counts and checksums"))
+                .args(get_common_args().into_iter().filter(|arg| arg.get_id() != "record"))
+                .args([
+                    source_arg(),
+                    target_arg(true),
+
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("VERSION")
+                        .value_parser(value_parser!(u32))
+                        .requires("migrations")
+                        .conflicts_with("rollback")
+                        .help(concat!(
+                            "Apply only the applied migrations starting from the specified version (the source data ",
+                            "already has the older ones)")),
+                ]),
+
+            Command::new("diff")
+                .about(concat!(
+                    "Compare the source and target data: report the time series present only on one side, sample ",
+                    "count differences and value mismatches"))
+                .args(get_common_args().into_iter().filter(|arg| arg.get_id() != "record"))
+                .args([
+                    source_arg(),
+                    target_arg(true),
+                    shard_size_arg(),
+
+                    Arg::new("tolerance")
+                        .long("tolerance")
+                        .value_name("VALUE")
+                        .value_parser(value_parser!(f64))
+                        .default_value("0")
+                        .help("Absolute tolerance for value comparison"),
+
+                    Arg::new("relative_tolerance")
+                        .long("relative-tolerance")
+                        .value_name("VALUE")
+                        .value_parser(value_parser!(f64))
+                        .default_value("0")
+                        .help("Relative tolerance for value comparison (like 1e-9)"),
+                ]),
+
+            Command::new("fill-gaps")
+                .about(concat!(
+                    "Fill gaps in the primary VictoriaMetrics instance with the samples of the same time series from ",
+                    "the secondary one"))
+                .args(get_common_args().into_iter().filter(|arg| arg.get_id() != "record"))
+                .args([
+                    Arg::new("primary")
+                        .value_name("PRIMARY")
+                        .required(true)
+                        .value_parser(Location::parse)
+                        .help("Primary VictoriaMetrics URL to import the missing samples to"),
+
+                    Arg::new("secondary")
+                        .value_name("SECONDARY")
+                        .required(true)
+                        .value_parser(Location::parse)
+                        .help("Secondary VictoriaMetrics URL to take the missing samples from"),
+
+                    shard_size_arg(),
+
+                    Arg::new("min_gap")
+                        .long("min-gap")
+                        .value_name("DURATION")
+                        .help(concat!(
+                            "Fill only the gaps between primary samples longer than the specified duration (like ",
+                            "1m). By default, the gaps longer than 1.5 of the scrape interval detected for each time ",
+                            "series are filled. Use 0 to fill all timestamps missing in the primary instance, which ",
+                            "suits only the instances with aligned scrape timestamps")),
+                ]),
         ])
 
         .get_matches();
 
+    let (mode, matches) = match matches.subcommand() {
+        Some(("backup", matches)) => (Mode::Backup(matches.get_one::<PathBuf>("archive").cloned().unwrap()), matches),
+        Some(("restore", matches)) => (Mode::Restore(matches.get_one::<PathBuf>("archive").cloned().unwrap()), matches),
+        Some(("in-place", matches)) => (Mode::InPlace(matches.get_one::<PathBuf>("backup").cloned()), matches),
+        Some(("verify", matches)) => (Mode::Verify, matches),
+        Some(("fill-gaps", matches)) => (Mode::FillGaps(
+            matches.get_one::<String>("min_gap").map(|value| time::parse_duration(value)).transpose()?,
+        ), matches),
+        Some(("diff", matches)) => (Mode::Diff(Tolerance {
+            absolute: matches.get_one("tolerance").cloned().unwrap(),
+            relative: matches.get_one("relative_tolerance").cloned().unwrap(),
+        }), matches),
+        _ => (Mode::Migrate, &matches),
+    };
+
+    for name in ["jobs", "batch_lines", "import_jobs"] {
+        if matches.get_one::<usize>(name).cloned().unwrap() == 0 {
+            return Err!("Invalid --{} value", name.replace('_', "-"));
+        }
+    }
+
+    if matches.try_get_one::<usize>("shard_size").ok().flatten() == Some(&0) {
+        return Err!("Invalid --shard-size value");
+    }
+
+    let csv_columns = Arc::new(Columns::parse(matches.get_one::<String>("csv_columns").unwrap())?);
+
+    let influx_mapping = Arc::new(Mapping::parse(
+        matches.get_one::<String>("influx_template").unwrap(),
+        match matches.get_one::<String>("influx_precision").unwrap().as_str() {
+            "ns" => Precision::Nanoseconds,
+            "us" => Precision::Microseconds,
+            "ms" => Precision::Milliseconds,
+            "s" => Precision::Seconds,
+            _ => unreachable!(),
+        },
+    )?);
+
+    let graphite_templates: Vec<&str> = matches.get_many::<String>("graphite_templates")
+        .map(|templates| templates.map(String::as_str).collect())
+        .unwrap_or_default();
+    let graphite_templates = Arc::new(Templates::parse(&graphite_templates)?);
+
+    let get_format = |name: &str| {
+        let name = matches.get_one::<String>(name).or(matches.get_one::<String>("format")).unwrap();
+        match name.as_str() {
+            "json" => Format::Json,
+            "native" => Format::Native,
+            "csv" => Format::Csv(csv_columns.clone()),
+            "prometheus" => Format::Prometheus,
+            "influx" => Format::Influx(influx_mapping.clone()),
+            "graphite" => Format::Graphite(graphite_templates.clone()),
+            _ => unreachable!(),
+        }
+    };
+
+    // Source-only formats specified by --format don't apply to target
+    let get_target_format = || match get_format("target_format") {
+        format if format.is_source_only() && matches.get_one::<String>("target_format").is_none() => Format::Json,
+        format => format,
+    };
+
+    // Archive data is always stored as zstd-compressed JSON lines
+    let (source_format, target_format) = match mode {
+        Mode::Migrate | Mode::InPlace(_) | Mode::Verify | Mode::Diff(_) | Mode::FillGaps(_) => (
+            get_format("source_format"), get_target_format()),
+        Mode::Backup(_) => (get_format("source_format"), Format::Json),
+        Mode::Restore(_) => (Format::Json, get_target_format()),
+    };
+
+    let compression = matches.get_one::<String>("compression").map(|compression| match compression.as_str() {
+        "none" => Compression::None,
+        "gzip" => Compression::Gzip,
+        "zstd" => Compression::Zstd,
+        _ => unreachable!(),
+    });
+
+    if compression.is_some() && !matches!(mode, Mode::Migrate) {
+        return Err!("--compression can be used only for migration");
+    }
+
+    let batch_size = parse_size(matches.get_one::<String>("batch_size").unwrap())?;
+
+    let start_time = matches.get_one::<String>("start").map(|value| time::parse_time(value)).transpose()?;
+    let end_time = matches.get_one::<String>("end").map(|value| time::parse_time(value)).transpose()?;
+    let window = matches.get_one::<String>("window").map(|value| time::parse_duration(value)).transpose()?;
+
+    if let (Some(start_time), Some(end_time)) = (start_time, end_time) {
+        if start_time >= end_time {
+            return Err!("Start time must be less than end time");
+        }
+    }
+
     let log_level = match matches.get_count("verbose") {
         0 => Level::Info,
         1 => Level::Debug,
@@ -90,10 +901,373 @@ fn parse_args() -> GenericResult<Config> {
         _ => return Err!("Invalid verbosity level"),
     };
 
+    let (source, target) = match mode {
+        Mode::Migrate | Mode::Verify | Mode::Diff(_) => (
+            matches.get_one("source").cloned().unwrap(), matches.get_one("target").cloned()),
+        Mode::Backup(ref archive) => (
+            matches.get_one("source").cloned().unwrap(), Some(Location::File(backup::data_path(archive)))),
+        Mode::Restore(ref archive) => (
+            Location::File(backup::data_path(archive)), matches.get_one("target").cloned()),
+        Mode::InPlace(_) => (matches.get_one("source").cloned().unwrap(), None),
+        Mode::FillGaps(_) => (
+            matches.get_one("secondary").cloned().unwrap(), matches.get_one("primary").cloned()),
+    };
+
+    // The time series are deleted as a whole, so the migration must cover them completely
+    if matches!(mode, Mode::InPlace(_)) {
+        if !matches!(source, Location::VictoriaMetrics(_)) {
+            return Err!("In-place migration is supported only for VictoriaMetrics source");
+        }
+
+        for name in ["start", "end", "window", "checkpoint", "match"] {
+            if matches.contains_id(name) {
+                return Err!("--{name} can't be used for in-place migration");
+            }
+        }
+    }
+
+    // The migrated time series may not match the selectors, so the whole target data is compared
+    if matches!(mode, Mode::Verify) {
+        for name in ["checkpoint", "match"] {
+            if matches.contains_id(name) {
+                return Err!("--{name} can't be used for verification");
+            }
+        }
+    }
+
+    if let Mode::FillGaps(_) = mode {
+        if !matches!(source, Location::VictoriaMetrics(_)) || !matches!(target, Some(Location::VictoriaMetrics(_))) {
+            return Err!("Gap filling is supported only for VictoriaMetrics instances");
+        }
+
+        for name in ["rules", "migrations", "checkpoint"] {
+            if matches.contains_id(name) {
+                return Err!("--{name} can't be used for gap filling");
+            }
+        }
+    }
+
+    // The data is compared as is
+    if let Mode::Diff(tolerance) = mode {
+        for name in ["rules", "migrations", "checkpoint", "import_params"] {
+            if matches.contains_id(name) {
+                return Err!("--{} can't be used for comparison", name.replace('_', "-"));
+            }
+        }
+
+        if !(tolerance.absolute >= 0.0 && tolerance.relative >= 0.0) {
+            return Err!("Invalid tolerance value");
+        }
+    }
+
+    let partitions: Vec<&String> = matches.get_many("parquet_partition").map(Iterator::collect).unwrap_or_default();
+    let parquet = parquet::Options {
+        partition_by_metric: partitions.iter().any(|&key| key == "metric"),
+        partition_by_day: partitions.iter().any(|&key| key == "day"),
+    };
+
+    if !partitions.is_empty() && !matches!(target, Some(Location::Parquet(_))) {
+        return Err!("Parquet options can be used only with Parquet target");
+    }
+
     Ok(Config {
-        source: matches.get_one("source").cloned().unwrap(),
-        start_time: matches.get_one("start").cloned(),
-        target: matches.get_one("target").cloned(),
+        mode,
+        source,
+        start_time,
+        end_time,
+        window,
+        target,
+        compression,
+        source_format,
+        target_format,
+        rules: matches.get_one("rules").cloned(),
+        migrations: matches.get_one("migrations").cloned(),
+        state: matches.get_one("state").cloned(),
+        record: matches.try_get_one::<bool>("record").ok().flatten().cloned().unwrap_or_default(),
+        rollback: matches.get_one("rollback").cloned(),
+        since: matches.try_get_one("since").ok().flatten().cloned(),
+        shard_size: matches.try_get_one("shard_size").ok().flatten().cloned().unwrap_or_default(),
+        reverse: matches.get_flag("reverse"),
+        jobs: matches.get_one("jobs").cloned().unwrap(),
+        checkpoint: matches.get_one("checkpoint").cloned(),
+        resume: matches.get_flag("resume"),
+        retries: matches.get_one("retries").cloned().unwrap(),
+        batch_lines: matches.get_one("batch_lines").cloned().unwrap(),
+        batch_size,
+        import_jobs: matches.get_one("import_jobs").cloned().unwrap(),
+        selectors: matches.get_many("match").map(|selectors| selectors.cloned().collect()).unwrap_or_default(),
+        export_params: matches.get_many("export_params").map(|params| params.cloned().collect()).unwrap_or_default(),
+        import_params: matches.get_many("import_params").map(|params| params.cloned().collect()).unwrap_or_default(),
+        parquet,
         log_level,
     })
+}
+
+fn get_common_args() -> Vec<Arg> {
+    vec![
+        Arg::new("verbose")
+            .short('v').long("verbose")
+            .action(ArgAction::Count)
+            .help("Set verbosity level"),
+
+        Arg::new("match")
+            .long("match")
+            .value_name("SELECTOR")
+            .value_parser(Selector::parse)
+            .action(ArgAction::Append)
+            .help(concat!(
+                "Migrate only the time series matching the Prometheus series selector (like ",
+                "'{job=\"node\",instance=~\"server-.*\"}'). Can be specified multiple times")),
+
+        Arg::new("start")
+            .long("start")
+            .value_name("TIME")
+            .allow_hyphen_values(true)
+            .help("Start time (Unix timestamp, RFC 3339, YYYY-MM-DD[THH:MM[:SS]] or duration ago like -1d)"),
+
+        Arg::new("end")
+            .long("end")
+            .value_name("TIME")
+            .allow_hyphen_values(true)
+            .help("End time (exclusive, in the same format as start time)"),
+
+        Arg::new("window")
+            .long("window")
+            .value_name("DURATION")
+            .requires("start")
+            .help("Split the time range into windows of the specified duration (like 1d) to migrate them separately"),
+
+        Arg::new("jobs")
+            .short('j').long("jobs")
+            .value_name("NUMBER")
+            .value_parser(value_parser!(usize))
+            .default_value("1")
+            .help("Number of metrics to migrate in parallel (exports the whole database in one stream if 1)"),
+
+        Arg::new("checkpoint")
+            .long("checkpoint")
+            .value_name("PATH")
+            .value_parser(value_parser!(PathBuf))
+            .help("Record the completed metrics and time windows in the specified checkpoint file"),
+
+        Arg::new("resume")
+            .long("resume")
+            .action(ArgAction::SetTrue)
+            .requires("checkpoint")
+            .help("Resume the interrupted migration skipping the work recorded in the checkpoint file"),
+
+        Arg::new("retries")
+            .long("retries")
+            .value_name("NUMBER")
+            .value_parser(value_parser!(usize))
+            .default_value("5")
+            .help("Number of retries for transient errors"),
+
+        Arg::new("batch_lines")
+            .long("batch-lines")
+            .value_name("NUMBER")
+            .value_parser(value_parser!(usize))
+            .default_value("10000")
+            .help("Maximum number of time series (native blocks for native format) per import request"),
+
+        Arg::new("batch_size")
+            .long("batch-size")
+            .value_name("SIZE")
+            .default_value("32M")
+            .help("Maximum import request size (in bytes or with K/M/G suffix)"),
+
+        Arg::new("import_jobs")
+            .long("import-jobs")
+            .value_name("NUMBER")
+            .value_parser(value_parser!(usize))
+            .default_value("1")
+            .help("Number of parallel import requests per job"),
+
+        Arg::new("export_params")
+            .long("export-param")
+            .value_name("NAME=VALUE")
+            .value_parser(parse_param)
+            .action(ArgAction::Append)
+            .help(concat!(
+                "Extra VictoriaMetrics export request parameter (like max_rows_per_line=1000 or reduce_mem_usage=0). ",
+                "Can be specified multiple times")),
+
+        Arg::new("import_params")
+            .long("import-param")
+            .value_name("NAME=VALUE")
+            .value_parser(parse_param)
+            .action(ArgAction::Append)
+            .help(concat!(
+                "Extra VictoriaMetrics import request parameter (like extra_label=source=prometheus). Can be ",
+                "specified multiple times")),
+
+        Arg::new("format")
+            .long("format")
+            .value_name("FORMAT")
+            .value_parser(FORMATS)
+            .default_value("json")
+            .help(concat!(
+                "Data transfer format: JSON lines, native (faster, only the time series affected by the ",
+                "migration are decoded), CSV, Prometheus text, InfluxDB line protocol or Graphite plaintext (the ",
+                "latter two are supported only for source files)")),
+
+        Arg::new("source_format")
+            .long("source-format")
+            .value_name("FORMAT")
+            .value_parser(FORMATS)
+            .help("Source data format (overrides --format)"),
+
+        Arg::new("target_format")
+            .long("target-format")
+            .value_name("FORMAT")
+            .value_parser(FORMATS)
+            .help("Target data format (overrides --format)"),
+
+        Arg::new("csv_columns")
+            .long("csv-columns")
+            .value_name("COLUMNS")
+            .default_value("__name__,__value__,__timestamp__:unix_ms")
+            .help(concat!(
+                "CSV columns: __name__, __value__, __timestamp__:<unix_s|unix_ms|unix_ns|rfc3339> and label names ",
+                "(the labels which aren't listed are lost)")),
+
+        Arg::new("influx_template")
+            .long("influx-template")
+            .value_name("TEMPLATE")
+            .default_value(influx::DEFAULT_TEMPLATE)
+            .help(concat!(
+                "InfluxDB metric name template optionally followed by comma-separated labels, both referring to ",
+                "{measurement} and {field} (like \"{measurement} field={field}\"). Tags are mapped to labels")),
+
+        Arg::new("influx_precision")
+            .long("influx-precision")
+            .value_name("PRECISION")
+            .value_parser(["ns", "us", "ms", "s"])
+            .default_value("ns")
+            .help("InfluxDB timestamp precision"),
+
+        Arg::new("graphite_templates")
+            .long("graphite-template")
+            .value_name("TEMPLATE")
+            .action(ArgAction::Append)
+            .help(concat!(
+                "Graphite template: [filter ]template[ label=value,...], where filter is a dotted path pattern with * ",
+                "wildcards and template is a dotted list of __name__, label names (suffixed with * to take the rest ",
+                "of the path) or empty parts to skip, like \"servers.* .host.__name__*\". The first matching ",
+                "template is used. Can be specified multiple times")),
+
+        Arg::new("parquet_partition")
+            .long("parquet-partition")
+            .value_name("KEY")
+            .value_parser(["metric", "day"])
+            .value_delimiter(',')
+            .action(ArgAction::Append)
+            .help("Partition Parquet output by metric name and/or day (UTC)"),
+
+        Arg::new("rules")
+            .short('r').long("rules")
+            .value_name("PATH")
+            .value_parser(value_parser!(PathBuf))
+            .help("Migration rules file"),
+
+        Arg::new("migrations")
+            .short('m').long("migrations")
+            .value_name("PATH")
+            .value_parser(value_parser!(PathBuf))
+            .requires("state")
+            .help("Directory with numbered migration rule files to apply the pending ones from"),
+
+        Arg::new("state")
+            .long("state")
+            .value_name("PATH")
+            .value_parser(value_parser!(PathBuf))
+            .requires("migrations")
+            .help("Applied migrations state file"),
+
+        Arg::new("record")
+            .long("record")
+            .action(ArgAction::SetTrue)
+            .requires_all(["migrations", "target"])
+            .help("Record the applied migrations in the state file on success"),
+
+        Arg::new("rollback")
+            .long("rollback")
+            .value_name("VERSION")
+            .value_parser(value_parser!(u32))
+            .requires("migrations")
+            .help("Roll back the applied migrations starting from the specified version"),
+
+        Arg::new("reverse")
+            .long("reverse")
+            .action(ArgAction::SetTrue)
+            .requires("rules")
+            .help("Revert the changes made by the rules"),
+
+        Arg::new("compression")
+            .long("compression")
+            .value_name("TYPE")
+            .value_parser(["none", "gzip", "zstd"])
+            .help("Compression of source and target files (detected by .gz and .zst extensions by default)"),
+    ]
+}
+
+fn source_arg() -> Arg {
+    Arg::new("source")
+        .value_name("SOURCE")
+        .required(true)
+        .value_parser(Location::parse)
+        .help(concat!(
+            "Source VictoriaMetrics URL, Prometheus remote read URL prefixed with remote-read+ ",
+            "(like remote-read+http://localhost:9090/api/v1/read), Prometheus TSDB data directory or snapshot URL ",
+            "(like tsdb+file:///var/lib/prometheus), file:// URL or - for stdin"))
+}
+
+fn shard_size_arg() -> Arg {
+    Arg::new("shard_size")
+        .long("shard-size")
+        .value_name("NUMBER")
+        .value_parser(value_parser!(usize))
+        .default_value("100")
+        .help("Number of metrics to read into memory at a time when comparing VictoriaMetrics instances")
+}
+
+fn target_arg(required: bool) -> Arg {
+    Arg::new("target")
+        .value_name("TARGET")
+        .required(required)
+        .value_parser(Location::parse)
+        .help(concat!(
+            "Target VictoriaMetrics URL, Prometheus remote write URL prefixed with remote-write+ ",
+            "(like remote-write+http://localhost:9090/api/v1/write), Prometheus TSDB data directory URL to write new ",
+            "blocks to (like tsdb+file:///var/lib/prometheus), Parquet file (or directory if partitioned) URL (like ",
+            "parquet+file:///data/metrics.parquet), file:// URL or - for stdout"))
+}
+
+fn archive_arg() -> Arg {
+    Arg::new("archive")
+        .value_name("ARCHIVE")
+        .required(true)
+        .value_parser(value_parser!(PathBuf))
+        .help("Backup archive path")
+}
+
+fn parse_size(value: &str) -> GenericResult<usize> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'K')) => (&value[..index], 1024),
+        Some((index, 'M')) => (&value[..index], 1024 * 1024),
+        Some((index, 'G')) => (&value[..index], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    match number.parse::<usize>().ok().and_then(|size| size.checked_mul(multiplier)) {
+        Some(size) if size != 0 => Ok(size),
+        _ => Err!("Invalid size: {value:?}"),
+    }
+}
+
+fn parse_param(value: &str) -> GenericResult<(String, String)> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => Err!("Invalid parameter: {value:?} (NAME=VALUE is expected)"),
+    }
 }
\ No newline at end of file
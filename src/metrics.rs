@@ -3,6 +3,9 @@ use std::fmt::Write;
 
 use serde_derive::{Deserialize, Serialize};
 
+// A sample with its time series labels as it's represented in text formats
+pub type LabeledSample = (HashMap<String, String>, i64, Option<f64>);
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct TimeSeries {
@@ -12,6 +15,14 @@ pub struct TimeSeries {
 }
 
 impl TimeSeries {
+    pub fn new(metric: HashMap<String, String>) -> TimeSeries {
+        TimeSeries {
+            metric,
+            values: Vec::new(),
+            timestamps: Vec::new(),
+        }
+    }
+
     pub fn name(&self) -> &str {
         self.metric.get("__name__").expect("Got a metric without name")
     }
@@ -24,11 +35,28 @@ impl TimeSeries {
         self.metric.insert(name.to_owned(), value.to_owned());
     }
 
-    pub fn format_metric(&self) -> String {
-        let mut metric = self.name().to_owned();
+    pub fn remove_label(&mut self, name: &str) {
+        self.metric.remove(name);
+    }
+
+    pub fn has_same_labels(&self, other: &TimeSeries) -> bool {
+        self.metric == other.metric
+    }
+
+    // Returns all labels except metric name sorted by label name
+    pub fn labels(&self) -> Vec<(&str, &str)> {
+        let mut labels: Vec<_> = self.metric.iter()
+            .filter(|(name, _value)| *name != "__name__")
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
 
-        let mut labels: Vec<_> = self.metric.iter().filter(|(name, _value)| *name != "__name__").collect();
         labels.sort();
+        labels
+    }
+
+    pub fn format_metric(&self) -> String {
+        let mut metric = self.name().to_owned();
+        let labels = self.labels();
 
         for (index, (name, value)) in labels.iter().enumerate() {
             if index == 0 {
@@ -65,6 +93,25 @@ impl TimeSeries {
         self.values.push(value);
     }
 
+    pub fn extend(&mut self, other: &TimeSeries) {
+        self.timestamps.extend(&other.timestamps);
+        self.values.extend(&other.values);
+    }
+
+    pub fn sort(&mut self) {
+        let mut samples: Vec<_> = self.iter().collect();
+        samples.sort_by_key(|&(time, _value)| time);
+        (self.timestamps, self.values) = samples.into_iter().unzip();
+    }
+
+    pub fn map_values<F>(&mut self, map: F)
+        where F: Fn(f64) -> f64
+    {
+        for value in self.values.iter_mut().flatten() {
+            *value = map(*value);
+        }
+    }
+
     pub fn filter<F>(&self, filter: F) -> TimeSeries
         where F: Fn(i64, Option<f64>) -> bool
     {
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::rules::RuleSet;
+
+// Numbered migration rule files like `0001-tinkoff-rebranding.toml`. Each file is a rule set which is applied to the
+// output of the previous migrations.
+pub struct Migration {
+    pub version: u32,
+    pub name: String,
+    pub rules: RuleSet,
+    pub rollback: bool,
+}
+
+impl Migration {
+    pub fn id(&self) -> String {
+        format!("{:04}-{}", self.version, self.name)
+    }
+
+    pub fn invert(self) -> GenericResult<Migration> {
+        let rules = self.rules.invert().map_err(|e| format!(
+            "{} migration can't be rolled back. {e}", self.id()))?;
+
+        debug!("{} migration rollback rules:\n{}", self.id(), rules.format()?.trim_end());
+
+        Ok(Migration {
+            rules,
+            rollback: !self.rollback,
+            ..self
+        })
+    }
+}
+
+pub fn load(path: &Path) -> GenericResult<Vec<Migration>> {
+    let mut migrations = BTreeMap::new();
+
+    let entries = fs::read_dir(path).map_err(|e| format!(
+        "Unable to read {path:?}: {e}"))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+        let path = entry.path();
+
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+
+        let Some(file_name) = file_name.strip_suffix(".toml") else {
+            continue;
+        };
+
+        let Some((version, name)) = file_name.split_once('-').and_then(|(version, name)| {
+            Some((version.parse::<u32>().ok()?, name))
+        }) else {
+            return Err!("Invalid migration file name: {path:?}");
+        };
+
+        if version == 0 || name.is_empty() {
+            return Err!("Invalid migration file name: {path:?}");
+        }
+
+        let rules = RuleSet::load(&path)?;
+
+        if migrations.insert(version, Migration {version, name: name.to_owned(), rules, rollback: false}).is_some() {
+            return Err!("{path:?} migration version is not unique");
+        }
+    }
+
+    Ok(migrations.into_values().collect())
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    applied: Vec<AppliedMigration>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct AppliedMigration {
+    version: u32,
+    name: String,
+    time: DateTime<Local>,
+    // The rollback rules won't restore some time series migrated by the migration
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    irreversible: bool,
+}
+
+impl State {
+    pub fn load(path: &Path) -> GenericResult<State> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => return Err!("Unable to read {path:?}: {e}"),
+        };
+
+        Ok(serde_json::from_slice(&data).map_err(|e| format!(
+            "Error while reading {path:?}: {e}"))?)
+    }
+
+    pub fn save(&self, path: &Path) -> EmptyResult {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        data.push(b'\n');
+
+        let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+
+        fs::write(&temp_path, data).and_then(|_| fs::rename(&temp_path, path)).map_err(|e| format!(
+            "Unable to write {path:?}: {e}"))?;
+
+        Ok(())
+    }
+
+    // Returns migrations which haven't been applied yet
+    pub fn pending(&self, migrations: Vec<Migration>) -> GenericResult<Vec<Migration>> {
+        let mut pending = Vec::new();
+        let last_applied = self.applied.iter().map(|migration| migration.version).max();
+
+        for migration in migrations {
+            if let Some(applied) = self.applied.iter().find(|applied| applied.version == migration.version) {
+                if applied.name != migration.name {
+                    warn!("{} migration has been applied as {:04}-{}.", migration.id(), applied.version, applied.name);
+                }
+                continue;
+            }
+
+            if last_applied.is_some_and(|last_applied| migration.version < last_applied) {
+                return Err!(
+                    "{} migration is older than the last applied migration: migrations must be applied in order",
+                    migration.id());
+            }
+
+            pending.push(migration);
+        }
+
+        Ok(pending)
+    }
+
+    // Returns the applied migrations starting from the specified version in the order they have been applied in
+    pub fn applied(&self, migrations: Vec<Migration>, version: u32) -> GenericResult<Vec<Migration>> {
+        let mut migrations: BTreeMap<u32, Migration> = migrations.into_iter()
+            .map(|migration| (migration.version, migration))
+            .collect();
+
+        let mut applied = Vec::new();
+
+        for migration in self.applied.iter().filter(|migration| migration.version >= version) {
+            let Some(migration) = migrations.remove(&migration.version) else {
+                return Err!("{:04}-{} migration file is missing", migration.version, migration.name);
+            };
+            applied.push(migration);
+        }
+
+        Ok(applied)
+    }
+
+    // Returns the applied migrations starting from the specified version in the order they have to be rolled back in
+    pub fn applied_since(&self, migrations: Vec<Migration>, version: u32) -> GenericResult<Vec<Migration>> {
+        let mut migrations: BTreeMap<u32, Migration> = migrations.into_iter()
+            .map(|migration| (migration.version, migration))
+            .collect();
+
+        let mut applied = Vec::new();
+
+        for migration in self.applied.iter().rev().filter(|migration| migration.version >= version) {
+            if migration.irreversible {
+                return Err!(concat!(
+                    "{:04}-{} migration can't be rolled back: the rollback rules won't restore some time series ",
+                    "migrated by it"), migration.version, migration.name);
+            }
+
+            let Some(migration) = migrations.remove(&migration.version) else {
+                return Err!("{:04}-{} migration file is missing", migration.version, migration.name);
+            };
+            applied.push(migration);
+        }
+
+        Ok(applied)
+    }
+
+    pub fn remove(&mut self, version: u32) {
+        self.applied.retain(|migration| migration.version != version);
+    }
+
+    pub fn add(&mut self, version: u32, name: &str, irreversible: bool) {
+        self.applied.push(AppliedMigration {
+            version,
+            name: name.to_owned(),
+            time: Local::now(),
+            irreversible,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migration(version: u32) -> Migration {
+        Migration {
+            version,
+            name: format!("migration-{version}"),
+            rules: RuleSet::parse("").unwrap(),
+            rollback: false,
+        }
+    }
+
+    #[test]
+    fn rollback() {
+        let mut state = State::default();
+        state.add(1, "migration-1", false);
+        state.add(2, "migration-2", false);
+
+        let applied = state.applied_since((1..=3).map(migration).collect(), 2).unwrap();
+        assert_eq!(applied.iter().map(|migration| migration.version).collect::<Vec<_>>(), [2]);
+
+        let applied = state.applied_since((1..=3).map(migration).collect(), 1).unwrap();
+        assert_eq!(applied.iter().map(|migration| migration.version).collect::<Vec<_>>(), [2, 1]);
+
+        assert!(state.applied_since(vec![migration(1)], 1).is_err());
+    }
+
+    #[test]
+    fn applied() {
+        let mut state = State::default();
+        state.add(1, "migration-1", false);
+        state.add(2, "migration-2", true);
+
+        let versions = |migrations: Vec<Migration>| {
+            migrations.iter().map(|migration| migration.version).collect::<Vec<_>>()
+        };
+        assert_eq!(versions(state.applied((1..=3).map(migration).collect(), 0).unwrap()), [1, 2]);
+        assert_eq!(versions(state.applied((1..=3).map(migration).collect(), 2).unwrap()), [2]);
+        assert_eq!(versions(state.pending((1..=3).map(migration).collect()).unwrap()), [3]);
+
+        assert!(state.applied(vec![migration(2)], 1).is_err());
+    }
+
+    #[test]
+    fn irreversible_rollback() {
+        let mut state = State::default();
+        state.add(1, "migration-1", true);
+        state.add(2, "migration-2", false);
+
+        assert!(state.applied_since((1..=2).map(migration).collect(), 2).is_ok());
+
+        let error = state.applied_since((1..=2).map(migration).collect(), 1).err().unwrap().to_string();
+        assert!(error.contains("0001-migration-1 migration can't be rolled back"), "{error}");
+    }
+}
\ No newline at end of file
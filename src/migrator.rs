@@ -1,6 +1,196 @@
 use chrono::{Local, TimeZone};
+use sha2::{Digest, Sha256};
+
+use crate::core::GenericResult;
 
 use crate::metrics::{TimeSeries, MigratedTimeSeries};
+use crate::migrations::Migration;
+use crate::rules::RuleSet;
+use crate::stat::Stat;
+
+// Applies the pending migrations one after another, each one to the output of the previous, then the ad-hoc rules and
+// the built-in migration (if enabled)
+pub struct Migrator {
+    migrations: Vec<Migration>,
+    // Rollback rules of the applied migrations to check that the migrated time series can be restored by them
+    rollbacks: Vec<Option<RuleSet>>,
+    rules: Option<RuleSet>,
+    builtin: bool,
+}
+
+impl Migrator {
+    pub fn new(migrations: Vec<Migration>, rules: Option<RuleSet>, builtin: bool) -> Migrator {
+        let rollbacks = migrations.iter().map(|migration| if migration.rollback {
+            None
+        } else {
+            migration.rules.invert().ok()
+        }).collect();
+
+        Migrator {
+            migrations,
+            rollbacks,
+            rules,
+            builtin,
+        }
+    }
+
+    pub fn migrations(&self) -> &[Migration] {
+        &self.migrations
+    }
+
+    // Returns a digest of the applied migrations and rules, which identifies the changes made by the migrator
+    pub fn digest(&self) -> GenericResult<String> {
+        let mut hasher = Sha256::new();
+
+        for migration in &self.migrations {
+            hasher.update(format!("{} rollback={}\n", migration.id(), migration.rollback));
+            hasher.update(migration.rules.format()?);
+        }
+
+        if let Some(ref rules) = self.rules {
+            hasher.update("rules\n");
+            hasher.update(rules.format()?);
+        }
+
+        hasher.update(format!("builtin={}\n", self.builtin));
+
+        Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    // Returns Prometheus series selectors which match all the time series the migration may change (or its rollback
+    // rules may collide with) or None if they can't be determined
+    pub fn selectors(&self) -> Option<Vec<String>> {
+        if self.builtin {
+            return None;
+        }
+
+        let mut selectors = Vec::new();
+
+        let rules = self.migrations.iter().map(|migration| &migration.rules)
+            .chain(self.rollbacks.iter().flatten())
+            .chain(self.rules.iter());
+
+        for rules in rules {
+            for selector in rules.selectors()? {
+                if !selectors.contains(&selector) {
+                    selectors.push(selector);
+                }
+            }
+        }
+
+        Some(selectors)
+    }
+
+    // Checks whether the migration depends on the samples of the time series, so it requires the whole time series
+    pub fn has_sample_conditions(&self) -> bool {
+        self.migrations.iter().any(|migration| migration.rules.has_sample_conditions()) ||
+            self.rules.as_ref().is_some_and(RuleSet::has_sample_conditions)
+    }
+
+    // Checks whether the time series may be changed judging by its labels only. The built-in migration is expected to
+    // depend only on labels here.
+    pub fn may_change(&self, time_series: &TimeSeries) -> bool {
+        self.migrations.iter().any(|migration| migration.rules.may_apply(time_series)) ||
+            self.rollbacks.iter().flatten().any(|rules| rules.may_apply(time_series)) ||
+            self.rules.as_ref().is_some_and(|rules| rules.may_apply(time_series)) ||
+            self.builtin && !matches!(migrate(time_series), MigratedTimeSeries::Unchanged)
+    }
+
+    // Migrates the time series counting the changes made by each migration in the statistics
+    pub fn migrate(&self, time_series: &TimeSeries, stat: &mut Stat) -> MigratedTimeSeries {
+        let mut result: Option<Vec<TimeSeries>> = None;
+
+        let stages = self.migrations.iter().enumerate().map(|(index, migration)| Some((&migration.rules, Some(index))))
+            .chain(self.rules.iter().map(|rules| Some((rules, None))))
+            .chain(self.builtin.then_some(None));
+
+        for stage in stages {
+            let mut apply = |time_series: &TimeSeries| {
+                let Some((rules, migration)) = stage else {
+                    return migrate(time_series);
+                };
+
+                let result = rules.apply(time_series).unwrap_or(MigratedTimeSeries::Unchanged);
+
+                if let Some(index) = migration {
+                    let stat = stat.migration(index);
+
+                    if let Some(ref rollback) = self.rollbacks[index] {
+                        if !is_reversible(time_series, &result, rollback) {
+                            stat.collisions += 1;
+                        }
+                    }
+
+                    match result {
+                        MigratedTimeSeries::Unchanged => {},
+                        MigratedTimeSeries::Changed(ref time_series) if !time_series.is_empty() => {
+                            stat.changed += 1;
+                        },
+                        MigratedTimeSeries::Rewrite(ref results) if results.iter().any(|result| !result.is_empty()) => {
+                            stat.changed += 1;
+                        },
+                        MigratedTimeSeries::Changed(_) | MigratedTimeSeries::Rewrite(_) |
+                        MigratedTimeSeries::Deleted => {
+                            stat.deleted += 1;
+                        },
+                    }
+                }
+
+                result
+            };
+
+            result = match result {
+                None => match apply(time_series) {
+                    MigratedTimeSeries::Unchanged => None,
+                    MigratedTimeSeries::Changed(time_series) => Some(vec![time_series]),
+                    MigratedTimeSeries::Rewrite(results) => Some(results),
+                    MigratedTimeSeries::Deleted => Some(Vec::new()),
+                },
+
+                Some(current) => {
+                    let mut results = Vec::with_capacity(current.len());
+
+                    for time_series in current {
+                        if time_series.is_empty() {
+                            continue;
+                        }
+
+                        match apply(&time_series) {
+                            MigratedTimeSeries::Unchanged => results.push(time_series),
+                            MigratedTimeSeries::Changed(time_series) => results.push(time_series),
+                            MigratedTimeSeries::Rewrite(rewritten) => results.extend(rewritten),
+                            MigratedTimeSeries::Deleted => {},
+                        }
+                    }
+
+                    Some(results)
+                },
+            };
+        }
+
+        match result {
+            None => MigratedTimeSeries::Unchanged,
+            Some(results) if results.iter().all(TimeSeries::is_empty) => MigratedTimeSeries::Deleted,
+            Some(mut results) if results.len() == 1 => MigratedTimeSeries::Changed(results.pop().unwrap()),
+            Some(results) => MigratedTimeSeries::Rewrite(results),
+        }
+    }
+}
+
+// Checks that the rollback rules restore the original labels of the migrated time series and don't change the time
+// series which haven't been migrated
+fn is_reversible(source: &TimeSeries, result: &MigratedTimeSeries, rollback: &RuleSet) -> bool {
+    match result {
+        MigratedTimeSeries::Unchanged => matches!(rollback.apply(source), None | Some(MigratedTimeSeries::Unchanged)),
+        MigratedTimeSeries::Changed(migrated) => match rollback.apply(migrated) {
+            Some(MigratedTimeSeries::Changed(restored)) => restored.has_same_labels(source),
+            Some(MigratedTimeSeries::Unchanged) | None => migrated.has_same_labels(source),
+            Some(MigratedTimeSeries::Rewrite(_) | MigratedTimeSeries::Deleted) => false,
+        },
+        // Invertible rules don't delete or rewrite time series
+        MigratedTimeSeries::Rewrite(_) | MigratedTimeSeries::Deleted => true,
+    }
+}
 
 // TODO(konishchev): starting from 1747550059:
 // TODO(konishchev): node_memory_MemTotal_bytes -> server_memory_meminfo{name="MemTotal"}
@@ -152,4 +342,44 @@ fn migrate_example(time_series: &TimeSeries) -> MigratedTimeSeries {
 
 fn date(year: i32, month: u32, day: u32) -> i64 {
     Local.with_ymd_and_hms(year, month, day, 0, 0, 0).unwrap().timestamp() * 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_series(labels: &[(&str, &str)]) -> TimeSeries {
+        let mut time_series = TimeSeries::new(labels.iter().map(|&(name, value)| {
+            (name.to_owned(), value.to_owned())
+        }).collect());
+        time_series.add(1000, Some(1.0));
+        time_series
+    }
+
+    #[test]
+    fn collisions() {
+        let migration = Migration {
+            version: 1,
+            name: "rename".to_owned(),
+            rules: RuleSet::parse(r#"rule = [{ match = { job = "a" }, set_labels = { job = "c" } }]"#).unwrap(),
+            rollback: false,
+        };
+
+        let migrator = Migrator::new(vec![migration], None, false);
+        let mut stat = Stat::new(false);
+
+        for job in ["a", "b"] {
+            migrator.migrate(&time_series(&[("__name__", "metric"), ("job", job)]), &mut stat);
+        }
+        assert_eq!(stat.migrations()[0].changed, 1);
+        assert_eq!(stat.migrations()[0].collisions, 0);
+
+        // The time series already has the migrated labels, so the rollback would change it
+        let existing = time_series(&[("__name__", "metric"), ("job", "c")]);
+        assert!(migrator.may_change(&existing));
+
+        migrator.migrate(&existing, &mut stat);
+        assert_eq!(stat.migrations()[0].changed, 1);
+        assert_eq!(stat.migrations()[0].collisions, 1);
+    }
 }
\ No newline at end of file
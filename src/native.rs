@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::metrics::TimeSeries;
+use crate::varint::{self, write_uvarint, write_varint};
+
+// VictoriaMetrics native export/import format.
+//
+// The stream starts with a time range header (two big-endian int64 millisecond timestamps) followed by blocks. Each
+// block is a length-prefixed marshaled metric name and a length-prefixed portable block of up to 8K samples. A time
+// series may be split into several blocks which aren't necessarily adjacent in the stream.
+//
+// Sample timestamps and values are stored as int64 arrays encoded with one of VictoriaMetrics marshal types. Values are
+// stored as decimal mantissas with a common exponent (scale).
+
+const HEADER_SIZE: usize = 16;
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+const MAX_ROWS_PER_BLOCK: usize = 8 * 1024;
+const MIN_COMPRESSION_SIZE: usize = 128;
+
+const PRECISION_BITS: u8 = 64;
+
+const ESCAPE_CHAR: u8 = 0;
+const TAG_SEPARATOR_CHAR: u8 = 1;
+const KV_SEPARATOR_CHAR: u8 = 2;
+
+const MARSHAL_TYPE_ZSTD_NEAREST_DELTA2: u8 = 1;
+const MARSHAL_TYPE_DELTA_CONST: u8 = 2;
+const MARSHAL_TYPE_CONST: u8 = 3;
+const MARSHAL_TYPE_ZSTD_NEAREST_DELTA: u8 = 4;
+const MARSHAL_TYPE_NEAREST_DELTA2: u8 = 5;
+const MARSHAL_TYPE_NEAREST_DELTA: u8 = 6;
+
+// Special decimal values
+const VALUE_INF_POS: i64 = i64::MAX;
+const VALUE_INF_NEG: i64 = i64::MIN;
+const VALUE_STALE_NAN: i64 = i64::MAX - 1;
+const VALUE_MAX: i64 = i64::MAX - 2;
+const VALUE_MIN: i64 = i64::MIN + 1;
+
+pub struct Block {
+    metric_name: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Block {
+    pub async fn read<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Block>> {
+        if reader.fill_buf().await?.is_empty() {
+            return Ok(None);
+        }
+
+        let metric_name = read_chunk(reader).await?;
+        let data = read_chunk(reader).await?;
+
+        Ok(Some(Block {metric_name, data}))
+    }
+
+    pub fn metric_name(&self) -> &[u8] {
+        &self.metric_name
+    }
+
+    pub fn labels(&self) -> GenericResult<HashMap<String, String>> {
+        let mut labels = HashMap::new();
+        let mut src = self.metric_name.as_slice();
+
+        let name = unmarshal_tag_value(&mut src)?;
+        labels.insert("__name__".to_owned(), name);
+
+        while !src.is_empty() {
+            let name = unmarshal_tag_value(&mut src)?;
+            let value = unmarshal_tag_value(&mut src)?;
+            labels.insert(name, value);
+        }
+
+        Ok(labels)
+    }
+
+    pub fn rows(&self) -> GenericResult<usize> {
+        let mut src = self.data.as_slice();
+        read_varint(&mut src)?;
+        read_varint(&mut src)?;
+
+        let rows = read_uvarint(&mut src)?;
+        if rows == 0 || rows > 2 * MAX_ROWS_PER_BLOCK as u64 {
+            return Err!("Invalid native block rows count: {rows}");
+        }
+
+        Ok(rows as usize)
+    }
+
+    // Decodes the block samples appending them to the time series
+    pub fn decode(&self, time_series: &mut TimeSeries) -> EmptyResult {
+        let rows = self.rows()?;
+        let mut src = self.data.as_slice();
+
+        let first_timestamp = read_varint(&mut src)?;
+        let first_value = read_varint(&mut src)?;
+        read_uvarint(&mut src)?;
+
+        let scale = read_varint(&mut src)?;
+        let scale: i16 = scale.try_into().map_err(|_| format!("Invalid native block scale: {scale}"))?;
+
+        let &[timestamps_type, values_type, _precision_bits] = read_bytes(&mut src, 3)? else {
+            unreachable!();
+        };
+
+        let timestamps_data = read_length_prefixed(&mut src)?;
+        let values_data = read_length_prefixed(&mut src)?;
+        if !src.is_empty() {
+            return Err!("Got an invalid native block: it has unexpected trailing data");
+        }
+
+        let timestamps = unmarshal_int64s(timestamps_type, timestamps_data, first_timestamp, rows)?;
+        let values = unmarshal_int64s(values_type, values_data, first_value, rows)?;
+
+        for (time, value) in timestamps.into_iter().zip(values) {
+            time_series.add(time, decimal_to_float(value, scale));
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        write_chunk(buf, &self.metric_name);
+        write_chunk(buf, &self.data);
+    }
+}
+
+pub async fn read_header<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<()> {
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    Ok(())
+}
+
+// Time range header for import: the samples outside of the [start, end] range are dropped by VictoriaMetrics
+pub fn encode_header(start_time: i64, end_time: i64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend(start_time.to_be_bytes());
+    header.extend(end_time.to_be_bytes());
+    header
+}
+
+pub fn encode(time_series: &TimeSeries, buf: &mut Vec<u8>) {
+    let mut metric_name = Vec::new();
+    marshal_tag_value(&mut metric_name, time_series.name());
+
+    for (name, value) in time_series.labels() {
+        marshal_tag_value(&mut metric_name, name);
+        marshal_tag_value(&mut metric_name, value);
+    }
+
+    let samples: Vec<_> = time_series.iter().collect();
+
+    for samples in samples.chunks(MAX_ROWS_PER_BLOCK) {
+        let timestamps: Vec<i64> = samples.iter().map(|&(time, _value)| time).collect();
+        let (values, scale) = floats_to_decimal(samples.iter().map(|&(_time, value)| value));
+
+        let mut data = Vec::new();
+        write_varint(&mut data, timestamps[0]);
+        write_varint(&mut data, values[0]);
+        write_uvarint(&mut data, samples.len() as u64);
+        write_varint(&mut data, scale.into());
+
+        let (timestamps_type, timestamps_data) = marshal_int64s(&timestamps);
+        let (values_type, values_data) = marshal_int64s(&values);
+
+        data.extend([timestamps_type, values_type, PRECISION_BITS]);
+        write_length_prefixed(&mut data, &timestamps_data);
+        write_length_prefixed(&mut data, &values_data);
+
+        write_chunk(buf, &metric_name);
+        write_chunk(buf, &data);
+    }
+}
+
+async fn read_chunk<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let size = reader.read_u32().await? as usize;
+    if size > MAX_CHUNK_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Got too big native block: {size} bytes")));
+    }
+
+    let mut data = vec![0; size];
+    reader.read_exact(&mut data).await?;
+
+    Ok(data)
+}
+
+fn write_chunk(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend((data.len() as u32).to_be_bytes());
+    buf.extend(data);
+}
+
+fn marshal_tag_value(buf: &mut Vec<u8>, value: &str) {
+    for &byte in value.as_bytes() {
+        match byte {
+            ESCAPE_CHAR => buf.extend([ESCAPE_CHAR, b'0']),
+            TAG_SEPARATOR_CHAR => buf.extend([ESCAPE_CHAR, b'1']),
+            KV_SEPARATOR_CHAR => buf.extend([ESCAPE_CHAR, b'2']),
+            _ => buf.push(byte),
+        }
+    }
+    buf.push(TAG_SEPARATOR_CHAR);
+}
+
+fn unmarshal_tag_value(src: &mut &[u8]) -> GenericResult<String> {
+    let Some(end) = src.iter().position(|&byte| byte == TAG_SEPARATOR_CHAR) else {
+        return Err!("Got an invalid native metric name: missing tag separator");
+    };
+
+    let mut value = Vec::with_capacity(end);
+    let mut bytes = src[..end].iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte != ESCAPE_CHAR {
+            value.push(byte);
+            continue;
+        }
+
+        value.push(match bytes.next() {
+            Some(b'0') => ESCAPE_CHAR,
+            Some(b'1') => TAG_SEPARATOR_CHAR,
+            Some(b'2') => KV_SEPARATOR_CHAR,
+            _ => return Err!("Got an invalid native metric name: invalid escape sequence"),
+        });
+    }
+
+    *src = &src[end + 1..];
+
+    Ok(String::from_utf8(value).map_err(|_| "Got an invalid native metric name: it's not a valid UTF-8 string")?)
+}
+
+fn unmarshal_int64s(marshal_type: u8, data: &[u8], first_value: i64, count: usize) -> GenericResult<Vec<i64>> {
+    let mut values = Vec::with_capacity(count);
+
+    match marshal_type {
+        MARSHAL_TYPE_ZSTD_NEAREST_DELTA | MARSHAL_TYPE_ZSTD_NEAREST_DELTA2 => {
+            // Each delta is encoded as varint which takes up to 10 bytes
+            let data = zstd::bulk::decompress(data, count * 10).map_err(|e| format!(
+                "Failed to decompress native block data: {e}"))?;
+
+            let marshal_type = if marshal_type == MARSHAL_TYPE_ZSTD_NEAREST_DELTA {
+                MARSHAL_TYPE_NEAREST_DELTA
+            } else {
+                MARSHAL_TYPE_NEAREST_DELTA2
+            };
+
+            return unmarshal_int64s(marshal_type, &data, first_value, count);
+        },
+
+        MARSHAL_TYPE_NEAREST_DELTA => {
+            let mut src = data;
+            let mut value = first_value;
+            values.push(value);
+
+            for _ in 1..count {
+                value = value.wrapping_add(read_varint(&mut src)?);
+                values.push(value);
+            }
+
+            if !src.is_empty() {
+                return Err!("Got an invalid native block: it has unexpected trailing data");
+            }
+        },
+
+        MARSHAL_TYPE_NEAREST_DELTA2 => {
+            if count < 2 {
+                return Err!("Got an invalid native block: too few items for nearest delta2 encoding");
+            }
+
+            let mut src = data;
+            let mut value = first_value;
+            let mut delta = read_varint(&mut src)?;
+
+            values.push(value);
+            value = value.wrapping_add(delta);
+            values.push(value);
+
+            for _ in 2..count {
+                delta = delta.wrapping_add(read_varint(&mut src)?);
+                value = value.wrapping_add(delta);
+                values.push(value);
+            }
+
+            if !src.is_empty() {
+                return Err!("Got an invalid native block: it has unexpected trailing data");
+            }
+        },
+
+        MARSHAL_TYPE_CONST => {
+            if !data.is_empty() {
+                return Err!("Got an invalid native block: it has unexpected trailing data");
+            }
+            values.resize(count, first_value);
+        },
+
+        MARSHAL_TYPE_DELTA_CONST => {
+            let mut src = data;
+            let delta = read_varint(&mut src)?;
+
+            if !src.is_empty() {
+                return Err!("Got an invalid native block: it has unexpected trailing data");
+            }
+
+            let mut value = first_value;
+            for _ in 0..count {
+                values.push(value);
+                value = value.wrapping_add(delta);
+            }
+        },
+
+        _ => return Err!("Got an invalid native block: unsupported marshal type {marshal_type}"),
+    }
+
+    Ok(values)
+}
+
+// Encodes the values as deltas with full precision, compressing them if it's worth it
+fn marshal_int64s(values: &[i64]) -> (u8, Vec<u8>) {
+    let mut data = Vec::new();
+    for pair in values.windows(2) {
+        write_varint(&mut data, pair[1].wrapping_sub(pair[0]));
+    }
+
+    if data.len() >= MIN_COMPRESSION_SIZE {
+        if let Ok(compressed) = zstd::bulk::compress(&data, 1) {
+            return (MARSHAL_TYPE_ZSTD_NEAREST_DELTA, compressed);
+        }
+    }
+
+    (MARSHAL_TYPE_NEAREST_DELTA, data)
+}
+
+fn decimal_to_float(value: i64, scale: i16) -> Option<f64> {
+    Some(match value {
+        VALUE_STALE_NAN => return None,
+        VALUE_INF_POS => f64::INFINITY,
+        VALUE_INF_NEG => f64::NEG_INFINITY,
+        _ if scale >= 0 => value as f64 * 10_f64.powi(scale.into()),
+        _ => value as f64 / 10_f64.powi((-scale).into()),
+    })
+}
+
+// Converts the values to decimal mantissas with a common scale. Precision of the values with small exponents is reduced
+// if it's required to fit the mantissas with the common scale into int64.
+fn floats_to_decimal<I: Iterator<Item = Option<f64>>>(values: I) -> (Vec<i64>, i16) {
+    let decimals: Vec<Result<(i64, i16), i64>> = values.map(|value| match value {
+        None => Err(VALUE_STALE_NAN),
+        Some(value) if value.is_nan() => Err(VALUE_STALE_NAN),
+        Some(f64::INFINITY) => Err(VALUE_INF_POS),
+        Some(f64::NEG_INFINITY) => Err(VALUE_INF_NEG),
+        Some(value) => Ok(float_to_decimal(value)),
+    }).collect();
+
+    let mut scale = decimals.iter()
+        .filter_map(|decimal| decimal.ok())
+        .filter(|&(mantissa, _exponent)| mantissa != 0)
+        .map(|(_mantissa, exponent)| exponent)
+        .min().unwrap_or(0);
+
+    loop {
+        let mantissas = decimals.iter().map(|decimal| match *decimal {
+            Ok((mantissa, exponent)) => rescale_decimal(mantissa, exponent, scale),
+            Err(special) => Some(special),
+        }).collect::<Option<Vec<_>>>();
+
+        if let Some(mantissas) = mantissas {
+            return (mantissas, scale);
+        }
+
+        scale += 1;
+    }
+}
+
+fn float_to_decimal(value: f64) -> (i64, i16) {
+    // The shortest representation which is parsed back to the same value (up to 17 significant digits)
+    let formatted = format!("{value:e}");
+
+    let (digits, exponent) = formatted.split_once('e').unwrap();
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+    let mut mantissa: i64 = format!("{integer}{fraction}").parse().unwrap();
+    let mut exponent = exponent.parse::<i16>().unwrap() - fraction.len() as i16;
+
+    if mantissa == 0 {
+        return (0, 0);
+    }
+
+    while mantissa % 10 == 0 {
+        mantissa /= 10;
+        exponent += 1;
+    }
+
+    (mantissa, exponent)
+}
+
+fn rescale_decimal(mantissa: i64, exponent: i16, scale: i16) -> Option<i64> {
+    let mantissa = if exponent >= scale {
+        10_i64.checked_pow((exponent - scale) as u32).and_then(|multiplier| mantissa.checked_mul(multiplier))?
+    } else {
+        match 10_i128.checked_pow((scale - exponent) as u32) {
+            Some(divisor) => {
+                let mantissa = i128::from(mantissa);
+                ((mantissa + mantissa.signum() * divisor / 2) / divisor) as i64
+            },
+            None => 0,
+        }
+    };
+
+    (VALUE_MIN..=VALUE_MAX).contains(&mantissa).then_some(mantissa)
+}
+
+fn read_uvarint(src: &mut &[u8]) -> GenericResult<u64> {
+    Ok(varint::read_uvarint(src).ok_or("Got an invalid native block: invalid varint")?)
+}
+
+fn read_varint(src: &mut &[u8]) -> GenericResult<i64> {
+    Ok(varint::read_varint(src).ok_or("Got an invalid native block: invalid varint")?)
+}
+
+fn read_bytes<'a>(src: &mut &'a [u8], size: usize) -> GenericResult<&'a [u8]> {
+    if src.len() < size {
+        return Err!("Got an invalid native block: unexpected end of data");
+    }
+
+    let (data, tail) = src.split_at(size);
+    *src = tail;
+
+    Ok(data)
+}
+
+fn read_length_prefixed<'a>(src: &mut &'a [u8]) -> GenericResult<&'a [u8]> {
+    let size = read_uvarint(src)?;
+    read_bytes(src, size.try_into().unwrap_or(usize::MAX))
+}
+
+fn write_length_prefixed(buf: &mut Vec<u8>, data: &[u8]) {
+    write_uvarint(buf, data.len() as u64);
+    buf.extend(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_series(labels: &[(&str, &str)], samples: &[(i64, Option<f64>)]) -> TimeSeries {
+        let mut time_series = TimeSeries::new(labels.iter().map(|&(name, value)| {
+            (name.to_owned(), value.to_owned())
+        }).collect());
+
+        for &(time, value) in samples {
+            time_series.add(time, value);
+        }
+
+        time_series
+    }
+
+    async fn decode(mut data: &[u8]) -> Vec<(HashMap<String, String>, Vec<(i64, Option<f64>)>)> {
+        let mut result = Vec::new();
+
+        read_header(&mut data).await.unwrap();
+        while let Some(block) = Block::read(&mut data).await.unwrap() {
+            let labels = block.labels().unwrap();
+            let mut time_series = TimeSeries::new(labels.clone());
+            block.decode(&mut time_series).unwrap();
+            result.push((labels, time_series.iter().collect()));
+        }
+
+        result
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let labels = [("__name__", "metric"), ("job", "a\0b\x01c\x02")];
+        let samples = [
+            (1000, Some(1.5)), (2000, None), (3500, Some(-0.25)), (3600, Some(f64::INFINITY)),
+            (10000, Some(f64::NEG_INFINITY)), (10001, Some(1e10)), (20000, Some(0.0)),
+        ];
+        let regular: Vec<_> = (0..MAX_ROWS_PER_BLOCK as i64 * 2 + 10)
+            .map(|index| (index * 15000, Some((index % 100) as f64)))
+            .collect();
+        let constant = [(1000, Some(42.0)), (2000, Some(42.0))];
+
+        let mut data = encode_header(0, i64::MAX);
+        encode(&time_series(&labels, &samples), &mut data);
+        encode(&time_series(&[("__name__", "regular")], &regular), &mut data);
+        encode(&time_series(&[("__name__", "constant")], &constant), &mut data);
+
+        let blocks = decode(&data).await;
+        let block_labels: Vec<_> = blocks.iter().map(|(labels, _samples)| labels["__name__"].as_str()).collect();
+        assert_eq!(block_labels, ["metric", "regular", "regular", "regular", "constant"]);
+
+        let (metric_labels, metric_samples) = &blocks[0];
+        assert_eq!(*metric_labels, HashMap::from([
+            ("__name__".to_owned(), "metric".to_owned()),
+            ("job".to_owned(), "a\0b\x01c\x02".to_owned()),
+        ]));
+        assert_eq!(*metric_samples, samples);
+
+        let decoded: Vec<_> = blocks[1..4].iter().flat_map(|(_labels, samples)| samples.iter().cloned()).collect();
+        assert_eq!(blocks[1].1.len(), MAX_ROWS_PER_BLOCK);
+        assert_eq!(decoded, regular);
+
+        assert_eq!(blocks[4].1, constant);
+    }
+
+    #[tokio::test]
+    async fn invalid() {
+        let mut data = encode_header(0, i64::MAX);
+        encode(&time_series(&[("__name__", "metric")], &[(1000, Some(1.0)), (2000, Some(2.0))]), &mut data);
+
+        // Truncated block
+        let mut src = &data[..data.len() - 1];
+        read_header(&mut src).await.unwrap();
+        assert!(Block::read(&mut src).await.is_err());
+
+        // Trailing data in the block
+        let mut src = &data[HEADER_SIZE..];
+        let mut block = Block::read(&mut src).await.unwrap().unwrap();
+        block.data.push(0);
+        assert!(block.decode(&mut TimeSeries::new(HashMap::new())).is_err());
+    }
+}
\ No newline at end of file
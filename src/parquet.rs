@@ -0,0 +1,512 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, RecordBatch, new_null_array};
+use arrow_array::builder::{Float64Builder, StringBuilder, TimestampMillisecondBuilder};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::DateTime;
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+
+use crate::core::{EmptyResult, GenericError, GenericResult};
+use crate::metrics::TimeSeries;
+
+// Parquet files for offline analysis: a row per sample with `__name__`, label (null when missing), `__timestamp__`
+// (UTC milliseconds) and `__value__` (null for null values) columns. When partitioned, the target is a directory with
+// Hive-style partitions like `metric=node_cpu/date=2024-01-01/data.parquet`, otherwise it's a single file.
+//
+// The rows are written in row groups as the data arrives, with one open file per partition. The label columns of a
+// file are determined by its first row group, so if a time series with other labels comes later, the file is finished
+// and the partition continues in the next one: `data-1.parquet` and so on. The same happens when a partition's file is
+// closed to limit the number of open files. The single file target continues in temporary part files instead, which
+// are merged into the file with the union of their label columns when the writing is finished.
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+const BATCH_ROWS: usize = 64 * 1024;
+const MAX_BUFFERED_ROWS: usize = 1024 * 1024;
+const MAX_OPEN_FILES: usize = 64;
+const PARTITION_FILE_NAME: &str = "data.parquet";
+
+#[derive(Clone, Default)]
+pub struct Options {
+    pub partition_by_metric: bool,
+    pub partition_by_day: bool,
+}
+
+type PartitionKey = (Option<String>, Option<i64>);
+
+pub struct Writer {
+    path: PathBuf,
+    options: Options,
+    partitions: BTreeMap<PartitionKey, Partition>,
+    buffered: usize,
+    files: usize,
+    clock: u64,
+}
+
+impl Writer {
+    pub fn new(path: PathBuf, options: Options) -> Writer {
+        Writer {path, options, partitions: BTreeMap::new(), buffered: 0, files: 0, clock: 0}
+    }
+
+    pub fn add(&mut self, time_series: TimeSeries) -> EmptyResult {
+        let metric = self.options.partition_by_metric.then(|| time_series.name().to_owned());
+
+        let parts = if self.options.partition_by_day {
+            let days: BTreeSet<i64> = time_series.iter().map(|(time, _value)| time.div_euclid(DAY)).collect();
+            days.into_iter().map(|day| {
+                ((metric.clone(), Some(day)), time_series.filter(|time, _value| time.div_euclid(DAY) == day))
+            }).collect()
+        } else {
+            vec![((metric, None), time_series)]
+        };
+
+        for (key, time_series) in parts {
+            if time_series.is_empty() {
+                continue;
+            }
+
+            let partition = match self.partitions.entry(key.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let path = self.options.partition_path(&self.path, entry.key())?;
+                    entry.insert(Partition::new(path, !self.options.is_partitioned()))
+                },
+            };
+
+            self.buffered += time_series.len();
+            partition.rows += time_series.len();
+            partition.series.push(time_series);
+
+            if partition.rows >= BATCH_ROWS {
+                self.flush(&key)?;
+            }
+        }
+
+        if self.buffered >= MAX_BUFFERED_ROWS {
+            let keys: Vec<_> = self.partitions.iter().filter(|(_, partition)| partition.rows != 0)
+                .map(|(key, _)| key.clone()).collect();
+
+            for key in keys {
+                self.flush(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the number of written files
+    pub fn finish(&mut self) -> GenericResult<usize> {
+        let keys: Vec<_> = self.partitions.iter().filter(|(_, partition)| partition.rows != 0)
+            .map(|(key, _)| key.clone()).collect();
+
+        for key in keys {
+            self.flush(&key)?;
+        }
+
+        // An empty file is written if there is no data at all
+        if self.partitions.is_empty() && !self.options.is_partitioned() {
+            let mut partition = Partition::new(self.path.clone(), true);
+            partition.open(BTreeSet::new())?;
+            self.partitions.insert((None, None), partition);
+            self.files += 1;
+        }
+
+        for partition in self.partitions.values_mut() {
+            partition.close()?;
+        }
+
+        if !self.options.is_partitioned() {
+            for partition in self.partitions.values_mut() {
+                partition.merge()?;
+            }
+            return Ok(1);
+        }
+
+        Ok(self.files)
+    }
+
+    // Writes the buffered rows of the partition as a row group
+    fn flush(&mut self, key: &PartitionKey) -> EmptyResult {
+        let partition = self.partitions.get_mut(key).unwrap();
+        let mut series = std::mem::take(&mut partition.series);
+        self.buffered -= std::mem::take(&mut partition.rows);
+
+        let label_names: BTreeSet<String> = series.iter()
+            .flat_map(|time_series| time_series.labels().into_iter().map(|(name, _value)| name.to_owned()))
+            .collect();
+
+        if partition.file.as_ref().is_some_and(|file| !label_names.is_subset(&file.label_names)) {
+            partition.close()?;
+        }
+
+        if partition.file.is_none() {
+            if self.partitions.values().filter(|partition| partition.file.is_some()).count() >= MAX_OPEN_FILES {
+                let (_, partition) = self.partitions.iter_mut().filter(|(_, partition)| partition.file.is_some())
+                    .min_by_key(|(_, partition)| partition.last_used).unwrap();
+                partition.close()?;
+            }
+
+            let partition = self.partitions.get_mut(key).unwrap();
+            partition.open(label_names)?;
+            self.files += 1;
+        }
+
+        self.clock += 1;
+        let partition = self.partitions.get_mut(key).unwrap();
+        partition.last_used = self.clock;
+
+        series.sort_by_cached_key(TimeSeries::format_metric);
+        partition.file.as_mut().unwrap().write(&series).map_err(|e| format!(
+            "Unable to write {:?}: {e}", partition.path))?;
+
+        Ok(())
+    }
+}
+
+impl Options {
+    fn is_partitioned(&self) -> bool {
+        self.partition_by_metric || self.partition_by_day
+    }
+
+    fn partition_path(&self, path: &Path, (metric, day): &PartitionKey) -> GenericResult<PathBuf> {
+        if !self.is_partitioned() {
+            return Ok(path.to_owned());
+        }
+
+        let mut path = path.to_owned();
+
+        if let Some(metric) = metric {
+            path.push(format!("metric={}", escape_partition_value(metric)));
+        }
+
+        if let Some(day) = day {
+            let date = DateTime::from_timestamp_millis(day * DAY).ok_or_else(|| format!(
+                "Got an invalid timestamp: {}", day * DAY))?;
+            path.push(format!("date={}", date.format("%Y-%m-%d")));
+        }
+
+        Ok(path.join(PARTITION_FILE_NAME))
+    }
+}
+
+struct Partition {
+    path: PathBuf,
+    parts: bool,
+    files: usize,
+    file: Option<FileWriter>,
+    series: Vec<TimeSeries>,
+    rows: usize,
+    last_used: u64,
+}
+
+impl Partition {
+    // With parts, the next files are temporary parts of the first one to be merged into it
+    fn new(path: PathBuf, parts: bool) -> Partition {
+        Partition {path, parts, files: 0, file: None, series: Vec::new(), rows: 0, last_used: 0}
+    }
+
+    fn file_path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.path.clone(),
+            index if self.parts => PathBuf::from(format!("{}.{index}.part", self.path.display())),
+            index => {
+                let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+                let name = match self.path.extension() {
+                    Some(extension) => format!("{stem}-{index}.{}", extension.to_string_lossy()),
+                    None => format!("{stem}-{index}"),
+                };
+                self.path.with_file_name(name)
+            },
+        }
+    }
+
+    fn open(&mut self, label_names: BTreeSet<String>) -> EmptyResult {
+        let path = self.file_path(self.files);
+
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| format!("Unable to create {parent:?}: {e}"))?;
+        }
+
+        self.file = Some(FileWriter::new(path, label_names)?);
+        self.files += 1;
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> EmptyResult {
+        if let Some(file) = self.file.take() {
+            file.close()?;
+        }
+        Ok(())
+    }
+
+    // Merges the closed part files into the first one with the union of their label columns
+    fn merge(&mut self) -> EmptyResult {
+        if !self.parts || self.files < 2 {
+            return Ok(());
+        }
+
+        let paths: Vec<PathBuf> = (0..self.files).map(|index| self.file_path(index)).collect();
+
+        let result = (|| -> EmptyResult {
+            let mut readers = Vec::new();
+            let mut label_names = BTreeSet::new();
+
+            for path in &paths {
+                let reader = File::open(path).map_err(GenericError::from).and_then(|file| {
+                    Ok(ParquetRecordBatchReaderBuilder::try_new(file)?.with_batch_size(BATCH_ROWS))
+                }).map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+
+                let fields = reader.schema().fields();
+                label_names.extend(fields[1..fields.len() - 2].iter().map(|field| field.name().to_owned()));
+                readers.push((path, reader));
+            }
+
+            let mut file = FileWriter::new(self.path.clone(), label_names)?;
+
+            for (path, reader) in readers {
+                for batch in reader.build()? {
+                    let batch = batch.map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+                    file.write_batch(&batch).map_err(|e| format!("Unable to write {:?}: {e}", self.path))?;
+                }
+            }
+
+            file.close()
+        })();
+
+        for path in &paths[1..] {
+            let _ = fs::remove_file(path);
+        }
+        self.files = 1;
+
+        result
+    }
+}
+
+// Writes the file into a temporary one and renames it on close, so the temporary file is deleted if the writing fails
+struct FileWriter {
+    path: PathBuf,
+    temp_path: PathBuf,
+    label_names: BTreeSet<String>,
+    schema: Arc<Schema>,
+    writer: Option<ArrowWriter<File>>,
+}
+
+impl FileWriter {
+    fn new(path: PathBuf, label_names: BTreeSet<String>) -> GenericResult<FileWriter> {
+        let mut fields = vec![Field::new("__name__", DataType::Utf8, false)];
+        fields.extend(label_names.iter().map(|name| Field::new(name, DataType::Utf8, true)));
+        fields.push(Field::new("__timestamp__", DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())), false));
+        fields.push(Field::new("__value__", DataType::Float64, true));
+        let schema = Arc::new(Schema::new(fields));
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+
+        let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+
+        let writer = File::create(&temp_path).map_err(GenericError::from).and_then(|file| {
+            Ok(ArrowWriter::try_new(file, schema.clone(), Some(properties))?)
+        }).map_err(|e| format!("Unable to create {temp_path:?}: {e}"))?;
+
+        Ok(FileWriter {path, temp_path, label_names, schema, writer: Some(writer)})
+    }
+
+    fn write(&mut self, series: &[TimeSeries]) -> EmptyResult {
+        let mut batch = Batch::new(self.label_names.len());
+
+        for time_series in series {
+            for (time, value) in time_series.iter() {
+                batch.name.append_value(time_series.name());
+
+                for (builder, name) in batch.labels.iter_mut().zip(&self.label_names) {
+                    match time_series.label(name) {
+                        "" => builder.append_null(),
+                        value => builder.append_value(value),
+                    }
+                }
+
+                batch.timestamp.append_value(time);
+                batch.value.append_option(value);
+                batch.rows += 1;
+            }
+        }
+
+        let batch = batch.finish(&self.schema)?;
+        self.write_batch(&batch)
+    }
+
+    // Writes the batch with the file's columns: the ones missing in the batch are filled with nulls
+    fn write_batch(&mut self, batch: &RecordBatch) -> EmptyResult {
+        let columns = self.schema.fields().iter().map(|field| match batch.column_by_name(field.name()) {
+            Some(column) => column.clone(),
+            None => new_null_array(field.data_type(), batch.num_rows()),
+        }).collect();
+
+        let writer = self.writer.as_mut().unwrap();
+        writer.write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+
+        // Each batch is written as a separate row group to not keep it in memory
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn close(mut self) -> EmptyResult {
+        let writer = self.writer.take().unwrap();
+
+        writer.close().map_err(GenericError::from).and_then(|_| Ok(fs::rename(&self.temp_path, &self.path)?))
+            .map_err(|e| format!("Unable to write {:?}: {e}", self.path))?;
+
+        Ok(())
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+struct Batch {
+    name: StringBuilder,
+    labels: Vec<StringBuilder>,
+    timestamp: TimestampMillisecondBuilder,
+    value: Float64Builder,
+    rows: usize,
+}
+
+impl Batch {
+    fn new(labels: usize) -> Batch {
+        Batch {
+            name: StringBuilder::new(),
+            labels: (0..labels).map(|_| StringBuilder::new()).collect(),
+            timestamp: TimestampMillisecondBuilder::new().with_timezone("UTC"),
+            value: Float64Builder::new(),
+            rows: 0,
+        }
+    }
+
+    fn finish(&mut self, schema: &Arc<Schema>) -> GenericResult<RecordBatch> {
+        let mut columns: Vec<ArrayRef> = vec![Arc::new(self.name.finish())];
+        columns.extend(self.labels.iter_mut().map(|builder| Arc::new(builder.finish()) as ArrayRef));
+        columns.push(Arc::new(self.timestamp.finish()));
+        columns.push(Arc::new(self.value.finish()));
+        self.rows = 0;
+
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+// Escapes the value like Hive does to make it a safe path component
+fn escape_partition_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use super::*;
+
+    fn time_series(labels: &[(&str, &str)], times: &[i64]) -> TimeSeries {
+        let mut time_series = TimeSeries::new(labels.iter().map(|&(name, value)| {
+            (name.to_owned(), value.to_owned())
+        }).collect::<HashMap<_, _>>());
+
+        for &time in times {
+            time_series.add(time, Some(time as f64));
+        }
+
+        time_series
+    }
+
+    // Returns column names and number of rows
+    fn read(path: &Path) -> (Vec<String>, i64) {
+        let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        let columns = metadata.schema_descr().columns().iter().map(|column| column.name().to_owned()).collect();
+        (columns, metadata.num_rows())
+    }
+
+    #[test]
+    fn partitions() {
+        let path = std::env::temp_dir().join(format!("vm-migrate-{}-parquet", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        let mut writer = Writer::new(path.clone(), Options {partition_by_metric: true, partition_by_day: true});
+        writer.add(time_series(&[("__name__", "up"), ("job", "a")], &[0, 1000, DAY])).unwrap();
+        writer.add(time_series(&[("__name__", "cpu"), ("job", "a")], &[0])).unwrap();
+        writer.flush(&(Some("up".to_owned()), Some(0))).unwrap();
+
+        // The file has no column for the new label, so the next one is started
+        writer.add(time_series(&[("__name__", "up"), ("job", "b"), ("mode", "idle")], &[2000])).unwrap();
+        writer.add(time_series(&[("__name__", "up"), ("job", "b")], &[3000])).unwrap();
+        assert_eq!(writer.finish().unwrap(), 4);
+
+        let partition = path.join("metric=up/date=1970-01-01");
+        assert_eq!(read(&partition.join("data.parquet")), (
+            ["__name__", "job", "__timestamp__", "__value__"].map(str::to_owned).to_vec(), 2));
+        assert_eq!(read(&partition.join("data-1.parquet")), (
+            ["__name__", "job", "mode", "__timestamp__", "__value__"].map(str::to_owned).to_vec(), 2));
+
+        assert_eq!(read(&path.join("metric=up/date=1970-01-02/data.parquet")).1, 1);
+        assert_eq!(read(&path.join("metric=cpu/date=1970-01-01/data.parquet")).1, 1);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn single_file() {
+        let path = std::env::temp_dir().join(format!("vm-migrate-{}.parquet", std::process::id()));
+
+        let mut writer = Writer::new(path.clone(), Options::default());
+        assert_eq!(writer.finish().unwrap(), 1);
+        assert_eq!(read(&path).1, 0);
+
+        let mut writer = Writer::new(path.clone(), Options::default());
+        writer.add(time_series(&[("__name__", "up")], &[0, 1000])).unwrap();
+        writer.add(time_series(&[("__name__", "cpu"), ("mode", "idle")], &[0])).unwrap();
+        assert_eq!(writer.finish().unwrap(), 1);
+        assert_eq!(read(&path), (["__name__", "mode", "__timestamp__", "__value__"].map(str::to_owned).to_vec(), 3));
+
+        // The rows with new labels go to a part file, which is merged into the single one
+        let mut writer = Writer::new(path.clone(), Options::default());
+        writer.add(time_series(&[("__name__", "up"), ("mode", "idle")], &[0, 1000])).unwrap();
+        writer.flush(&(None, None)).unwrap();
+        writer.add(time_series(&[("__name__", "up"), ("job", "a")], &[2000])).unwrap();
+        assert_eq!(writer.finish().unwrap(), 1);
+        assert_eq!(read(&path), (
+            ["__name__", "job", "mode", "__timestamp__", "__value__"].map(str::to_owned).to_vec(), 3));
+        assert!(!PathBuf::from(format!("{}.1.part", path.display())).exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_partition_value("node_cpu-total"), "node_cpu-total");
+        assert_eq!(escape_partition_value("a/b:c"), "a%2Fb%3Ac");
+    }
+}
\ No newline at end of file
@@ -1,153 +1,1122 @@
-use std::io::{self, ErrorKind};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use async_stream::try_stream;
 use futures_core::stream::Stream;
-use futures_util::TryStreamExt;
-use reqwest::{self, Body, Client, ClientBuilder, Response};
+use chrono::Utc;
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt, future, stream, stream::BoxStream};
+use log::{info, warn};
+use reqwest::{self, Client, ClientBuilder, Response};
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::pin;
-use tokio::io::AsyncBufReadExt;
+use tokio::io::{AsyncBufRead, AsyncWriteExt};
+use tokio::task::JoinSet;
 use tokio_util::io::StreamReader;
 use url::Url;
 
+use crate::checkpoint::{Checkpoint, Header};
 use crate::core::{EmptyResult, GenericResult};
-use crate::migrator;
+use crate::migrator::Migrator;
+use crate::formats::{self, Format};
+use crate::location::{Compression, Location, Reader, Writer};
 use crate::metrics::{TimeSeries, MigratedTimeSeries};
-use crate::stat::Stat;
+use crate::native;
+use crate::parquet;
+use crate::remote;
+use crate::selector::{self, Selector};
+use crate::retry::{http_error, is_transient_status, retry, transient, with_context};
+use crate::stat::{MigrationStat, Stat};
+use crate::time;
+use crate::tsdb;
 
-#[tokio::main(flavor = "current_thread")]
-pub async fn process(source_url: &Url, start_time: Option<&str>, target_url: Option<&Url>) -> EmptyResult {
-    let import_stream = get_import_stream(source_url, start_time).await;
+const ALL_SERIES_SELECTOR: &str = r#"{__name__!=""}"#;
 
-    let Some(target_url) = target_url else {
-        pin!(import_stream);
-        while import_stream.try_next().await?.is_some() {
+#[derive(Clone)]
+pub struct Options {
+    pub source: Location,
+    pub target: Option<Location>,
+    pub compression: Option<Compression>,
+    pub source_format: Format,
+    pub target_format: Format,
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+    pub window: Option<i64>,
+    pub jobs: usize,
+    pub checkpoint: Option<PathBuf>,
+    pub resume: bool,
+    pub retries: usize,
+    pub batch_lines: usize,
+    pub batch_size: usize,
+    pub import_jobs: usize,
+    pub selectors: Vec<Selector>,
+    // Metric names to export one by one instead of the whole database (or all metric names when sharding)
+    pub metrics: Option<Vec<String>>,
+    pub export_params: Vec<(String, String)>,
+    pub import_params: Vec<(String, String)>,
+    pub parquet: parquet::Options,
+    // The applied migrations are recorded in the state, so the migration markers are written to the target
+    pub record: bool,
+}
+
+pub type Consumer = Box<dyn Fn(TimeSeries) + Send + Sync>;
+
+impl Options {
+    fn compression(&self, location: &Location) -> Compression {
+        self.compression.unwrap_or_else(|| location.compression())
+    }
+}
+
+enum Sink {
+    VictoriaMetrics(Url),
+    RemoteWrite(Url),
+    Tsdb(Mutex<tsdb::Writer>),
+    Parquet(Mutex<parquet::Writer>),
+    Consumer(Consumer),
+    Writer(tokio::sync::Mutex<Writer>),
+}
+
+// A unit of work: a metric (or the whole database) within a time window (or the whole time range)
+#[derive(PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Task {
+    name: Option<String>,
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+}
+
+impl Task {
+    // Export request parameters: the specified series selectors (or all series) restricted to the task's metric
+    fn selector_params(&self, selectors: &[Selector]) -> Vec<(&'static str, String)> {
+        let name_selector = self.name.as_ref().map(|name| format!("{{__name__={}}}", selector::quote(name)));
+
+        if selectors.is_empty() {
+            return vec![("match[]", name_selector.unwrap_or_else(|| ALL_SERIES_SELECTOR.to_owned()))];
+        }
+
+        selectors.iter().map(|selector| ("match[]", selector.to_string()))
+            .chain(name_selector.map(|selector| ("extra_filters[]", selector)))
+            .collect()
+    }
+
+    fn description(&self) -> String {
+        let mut description = self.name.clone().unwrap_or_else(|| "all metrics".to_owned());
+
+        if self.start_time.is_some() || self.end_time.is_some() {
+            let format = |time: Option<i64>| time.map(time::format_time).unwrap_or_default();
+            description += &format!(" [{} - {})", format(self.start_time), format(self.end_time));
+        }
+
+        description
+    }
+}
+
+// Returns the statistics of the applied migrations for the whole migration (including the resumed tasks)
+#[tokio::main]
+pub async fn process(options: Options, migrator: Arc<Migrator>) -> GenericResult<Vec<MigrationStat>> {
+    run(options, migrator, None).await
+}
+
+// Migrates the source data like process() does, but instead of writing it to the target, passes the resulting time
+// series to the consumer
+#[tokio::main]
+pub async fn consume(options: Options, migrator: Arc<Migrator>, consumer: Consumer) -> EmptyResult {
+    run(options, migrator, Some(consumer)).await?;
+    Ok(())
+}
+
+async fn run(
+    mut options: Options, migrator: Arc<Migrator>, consumer: Option<Consumer>,
+) -> GenericResult<Vec<MigrationStat>> {
+    if !options.export_params.is_empty() && !matches!(options.source, Location::VictoriaMetrics(_)) {
+        return Err!("Export parameters are supported only for VictoriaMetrics source");
+    }
+
+    if options.metrics.is_some() && !matches!(options.source, Location::VictoriaMetrics(_)) {
+        return Err!("Metric names can be specified only for VictoriaMetrics source");
+    }
+
+    if !options.import_params.is_empty() && !matches!(options.target, Some(Location::VictoriaMetrics(_))) {
+        return Err!("Import parameters are supported only for VictoriaMetrics target");
+    }
+
+    match options.source {
+        Location::VictoriaMetrics(_) if options.source_format.export_path().is_none() => {
+            return Err!("{} format is not supported for VictoriaMetrics source", options.source_format.name());
+        },
+        Location::VictoriaMetrics(_) => {},
+        Location::RemoteRead(_) | Location::Tsdb(_) => {
+            if options.jobs > 1 {
+                return Err!("Jobs option is supported only for VictoriaMetrics source");
+            }
+            if let Location::RemoteRead(_) = options.source {
+                options.source_format = Format::Remote;
+            }
+        },
+        Location::RemoteWrite(_) => return Err!("Remote write is supported only as a target"),
+        Location::Parquet(_) => return Err!("Parquet is supported only as a target"),
+        Location::File(_) | Location::Stdio => {
+            if options.jobs > 1 || options.start_time.is_some() || options.end_time.is_some() {
+                return Err!("Time range and jobs options are not supported for file sources");
+            }
+            if !options.selectors.is_empty() && options.source_format == Format::Native {
+                return Err!("Series selectors are not supported for native format file sources");
+            }
+        },
+    }
+
+    match options.target {
+        _ if consumer.is_some() => {
+            options.target = None;
+            options.target_format = Format::Json;
+        },
+        // The time series are collected into blocks and files from JSON lines
+        Some(Location::Tsdb(_) | Location::Parquet(_)) => options.target_format = Format::Json,
+        Some(Location::RemoteWrite(_)) => options.target_format = Format::Remote,
+        Some(_) if options.target_format.is_source_only() => {
+            return Err!("{} format is supported only for source files", options.target_format.name());
+        },
+        Some(Location::VictoriaMetrics(_)) if options.target_format.import_path().is_none() => {
+            return Err!("{} format is not supported for VictoriaMetrics target", options.target_format.name());
+        },
+        _ => {},
+    }
+
+    // Sample conditions must be evaluated on the whole time series, so it mustn't be split between tasks. Sharding is
+    // fine here: each shard is a separate metric name.
+    if migrator.has_sample_conditions() && (
+        options.start_time.is_some() || options.end_time.is_some() || options.window.is_some()
+    ) {
+        return Err!("Rules with sample conditions can't be applied to a time range of the data");
+    }
+
+    let resumed = match options.checkpoint {
+        Some(ref path) if options.resume => Checkpoint::<Task, Vec<MigrationStat>>::load(path)?,
+        _ => None,
+    };
+
+    // Use the same time range as the interrupted migration if end time has been determined automatically
+    if let Some((ref header, ref completed)) = resumed {
+        if options.end_time.is_none() {
+            options.end_time = header.end_time;
+        }
+
+        // The target file is recreated, so the completed tasks would be lost
+        if !completed.is_empty() && options.target.as_ref().is_some_and(|target| {
+            !matches!(target, Location::VictoriaMetrics(_) | Location::RemoteWrite(_))
+        }) {
+            return Err!("Migration to a file can't be resumed");
         }
-        return Ok(());
+    }
+
+    if options.window.is_some() && options.end_time.is_none() {
+        options.end_time = Some(Utc::now().timestamp_millis());
+    }
+
+    let mut stat = Stat::new(matches!(options.target, Some(Location::Stdio)));
+
+    let mut checkpoint = match options.checkpoint {
+        Some(ref path) => {
+            let header = Header {
+                start_time: options.start_time,
+                end_time: options.end_time,
+                window: options.window,
+                sharded: options.jobs > 1 || options.metrics.is_some(),
+                selectors: options.selectors.iter().map(ToString::to_string).collect(),
+                migrator: migrator.digest()?,
+            };
+
+            let completed = match resumed {
+                Some((resumed_header, completed)) => {
+                    if resumed_header != header {
+                        return Err!(concat!(
+                            "Unable to resume the migration from {:?}: ",
+                            "it has been started with other time range, jobs, series selector options or ",
+                            "migration rules"), path);
+                    }
+                    info!("Resuming the migration: {} tasks have been already completed.", completed.len());
+
+                    for migrations in completed.values() {
+                        stat.add_migrations(migrations);
+                    }
+
+                    completed
+                },
+                None => HashMap::new(),
+            };
+
+            Some(Checkpoint::create(path, &header, completed)?)
+        },
+        None => None,
     };
 
-    let import_url = target_url.join("/api/v1/import").map_err(|e| format!(
-        "Invalid URL: {e}"))?;
+    let sink = match options.target {
+        _ if consumer.is_some() => consumer.map(Sink::Consumer),
+        Some(Location::VictoriaMetrics(ref url)) => Some(Sink::VictoriaMetrics(url.clone())),
+        Some(Location::RemoteWrite(ref url)) => Some(Sink::RemoteWrite(url.clone())),
+        Some(Location::Tsdb(ref path)) => Some(Sink::Tsdb(Mutex::new(tsdb::Writer::new(path.clone())))),
+        Some(Location::Parquet(ref path)) => Some(Sink::Parquet(Mutex::new(parquet::Writer::new(
+            path.clone(), options.parquet.clone())))),
+        Some(ref location) => {
+            let mut writer = location.create(options.compression(location)).await?;
+
+            // The tasks' time ranges don't overlap, so all data is written under a single time range header
+            if options.target_format == Format::Native {
+                writer.write_all(&native::encode_header(i64::MIN, i64::MAX)).await.map_err(|e| format!(
+                    "Failed to write data: {e}"))?;
+            }
+
+            Some(Sink::Writer(tokio::sync::Mutex::new(writer)))
+        },
+        None => None,
+    };
+
+    let sink = Arc::new(sink);
+    let stat = Arc::new(Mutex::new(stat));
+    let options = Arc::new(options);
+
+    // Export the whole database in one stream or shard it by metric name to process the shards in parallel
+    let names = if let Some(ref names) = options.metrics {
+        names.iter().cloned().map(Some).collect()
+    } else if options.jobs > 1 {
+        let Location::VictoriaMetrics(ref source_url) = options.source else {
+            unreachable!();
+        };
+
+        let names = retry(options.retries, "Failed to get metric names", || stat.lock().unwrap().on_retry(), || {
+            get_metric_names(source_url, &options.selectors, options.start_time, options.end_time)
+        }).await.map_err(|e| format!("Failed to get metric names from source VictoriaMetrics: {e}"))?;
+        info!("Migrating {} metrics in {} jobs...", names.len(), options.jobs);
+        names.into_iter().map(Some).collect()
+    } else {
+        vec![None]
+    };
+
+    // Split the time range into windows to not export all history in one request
+    let windows = match (options.window, options.start_time, options.end_time) {
+        (Some(window), Some(start_time), Some(end_time)) => {
+            let windows = split_time_range(start_time, end_time, window);
+            info!("Migrating {} time windows...", windows.len());
+            windows.into_iter().map(|(start, end)| (Some(start), Some(end))).collect()
+        },
+        _ => vec![(options.start_time, options.end_time)],
+    };
+
+    let mut tasks = Vec::with_capacity(windows.len() * names.len());
+    for (start_time, end_time) in windows {
+        for name in &names {
+            let task = Task {name: name.clone(), start_time, end_time};
+            if checkpoint.as_ref().is_none_or(|checkpoint| !checkpoint.is_completed(&task)) {
+                tasks.push(task);
+            }
+        }
+    }
+
+    let total = tasks.len();
+    let mut tasks = tasks.into_iter();
+    let mut running = JoinSet::new();
+    let mut completed = 0;
+
+    loop {
+        while running.len() < options.jobs {
+            let Some(task) = tasks.next() else {
+                break;
+            };
+
+            let (options, sink) = (options.clone(), sink.clone());
+            let (migrator, stat) = (migrator.clone(), stat.clone());
+
+            running.spawn(async move {
+                let result = process_task(options, &task, migrator, stat, sink).await;
+                (task, result)
+            });
+        }
+
+        let Some(result) = running.join_next().await else {
+            break;
+        };
+
+        let (task, result) = result.map_err(|e| format!("Migration task has crashed: {e}"))?;
+        let migrations = result.map_err(|e| format!("Failed to migrate {}: {e}", task.description()))?;
+
+        completed += 1;
+        if total > 1 {
+            info!("[{completed}/{total}] {} is migrated.", task.description());
+        }
+
+        if let Some(ref mut checkpoint) = checkpoint {
+            checkpoint.add(task, migrations)?;
+        }
+    }
+
+    let stat = Arc::into_inner(stat).unwrap().into_inner().unwrap();
+    if !matches!(*sink, Some(Sink::Consumer(_))) {
+        stat.print();
+    }
+
+    for (migration, stat) in migrator.migrations().iter().zip(stat.migrations()) {
+        if stat.collisions != 0 {
+            warn!(concat!(
+                "{} migration can't be rolled back: {} time series won't be restored to their original state by ",
+                "the rollback rules (they probably have had the migrated labels before the migration)."),
+                migration.id(), stat.collisions);
+        }
+    }
+
+    match *sink {
+        Some(Sink::VictoriaMetrics(ref target_url)) if options.record => {
+            let markers = get_migration_markers(&migrator, &stat)?;
+            if !markers.is_empty() {
+                import(target_url, &Format::Json, &[], markers.into()).await?;
+            }
+        },
+        Some(Sink::VictoriaMetrics(_) | Sink::RemoteWrite(_) | Sink::Consumer(_)) | None => {},
+        Some(Sink::Tsdb(ref writer)) => {
+            info!("Writing Prometheus TSDB blocks...");
+            let mut writer = std::mem::replace(&mut *writer.lock().unwrap(), tsdb::Writer::new(PathBuf::new()));
+            let blocks = tokio::task::spawn_blocking(move || writer.finish()).await??;
+            info!("{blocks} blocks are written.");
+        },
+        Some(Sink::Parquet(ref writer)) => {
+            info!("Writing Parquet files...");
+            let mut writer = std::mem::replace(&mut *writer.lock().unwrap(), parquet::Writer::new(
+                PathBuf::new(), parquet::Options::default()));
+            let files = tokio::task::spawn_blocking(move || writer.finish()).await??;
+            info!("{files} files are written.");
+        },
+        Some(Sink::Writer(ref writer)) => {
+            writer.lock().await.shutdown().await.map_err(|e| format!("Failed to write data: {e}"))?;
+        },
+    }
+
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.remove()?;
+    }
+
+    Ok(stat.migrations().to_vec())
+}
+
+// The migrated data is imported in batches limited by number of lines and size, so each batch is retried separately on
+// transient errors without importing duplicate data.
+//
+// When the time range is split into windows, each window is exported and migrated into memory first, so export errors
+// are retried too. Otherwise the data is streamed from source to target, so the task is retried as a whole only if it
+// fails before any data has been passed to the target. The data written before a failure in the middle of the stream
+// would be written again, so such a task fails and is migrated from the beginning on resume, importing its already
+// imported samples again: use time windows or sharding by metric name to make the tasks smaller.
+//
+// Files are always read in one stream and written as is.
+//
+// Returns the statistics of the migrations applied by the task, so they can be recorded in the checkpoint
+async fn process_task(
+    options: Arc<Options>, task: &Task, migrator: Arc<Migrator>, stat: Arc<Mutex<Stat>>, sink: Arc<Option<Sink>>,
+) -> GenericResult<Vec<MigrationStat>> {
+    let on_retry = || stat.lock().unwrap().on_retry();
+
+    // Native import requires a time range header, so specify the task's time range to not lose any samples
+    let header = match options.target_format {
+        Format::Native => native::encode_header(
+            task.start_time.unwrap_or(i64::MIN), task.end_time.map_or(i64::MAX, |time| time - 1)),
+        _ => Vec::new(),
+    };
+
+    if options.window.is_none() {
+        let written = AtomicBool::new(false);
+
+        let task_stat = retry(options.retries, "Failed to migrate data", on_retry, || async {
+            let task_stat = Arc::new(Mutex::new(stat.lock().unwrap().fork()));
+
+            let result = async {
+                let import_stream = get_import_stream(options.clone(), task, migrator.clone(), task_stat.clone())
+                    .await?
+                    .inspect_ok(|_| written.store(true, Ordering::Relaxed));
+                write(options.clone(), sink.as_ref().as_ref(), &header, import_stream, stat.clone()).await
+            }.await;
+
+            match result {
+                Ok(()) => Ok(Arc::into_inner(task_stat).unwrap().into_inner().unwrap()),
+                Err(err) if written.load(Ordering::Relaxed) => Err(err.to_string().into()),
+                Err(err) => Err(err),
+            }
+        }).await?;
+
+        let migrations = task_stat.migrations().to_vec();
+        stat.lock().unwrap().merge(task_stat);
+
+        return Ok(migrations);
+    }
+
+    let (lines, task_stat) = retry(options.retries, "Failed to export data", on_retry, || async {
+        let task_stat = Arc::new(Mutex::new(stat.lock().unwrap().fork()));
+        let import_stream = get_import_stream(options.clone(), task, migrator.clone(), task_stat.clone()).await?;
+        let lines: Vec<Vec<u8>> = import_stream.try_collect().await?;
+
+        let task_stat = Arc::into_inner(task_stat).unwrap().into_inner().unwrap();
+        Ok((lines, task_stat))
+    }).await?;
+
+    let lines = stream::iter(lines.into_iter().map(Ok));
+    write(options.clone(), sink.as_ref().as_ref(), &header, lines, stat.clone()).await?;
+
+    let migrations = task_stat.migrations().to_vec();
+    stat.lock().unwrap().merge(task_stat);
+
+    Ok(migrations)
+}
+
+async fn write<S>(
+    options: Arc<Options>, sink: Option<&Sink>, header: &[u8], lines: S, stat: Arc<Mutex<Stat>>,
+) -> EmptyResult
+    where S: Stream<Item = GenericResult<Vec<u8>>>
+{
+    pin!(lines);
+
+    match sink {
+        Some(Sink::VictoriaMetrics(target_url) | Sink::RemoteWrite(target_url)) => {
+            import_batches(options, target_url, header, lines, stat).await?;
+        },
+        Some(Sink::Tsdb(writer)) => {
+            while let Some(data) = lines.try_next().await? {
+                for time_series in decode_json_lines(&data) {
+                    writer.lock().unwrap().add(&time_series?)?;
+                }
+            }
+        },
+        Some(Sink::Parquet(writer)) => {
+            while let Some(data) = lines.try_next().await? {
+                for time_series in decode_json_lines(&data) {
+                    writer.lock().unwrap().add(time_series?)?;
+                }
+            }
+        },
+        Some(Sink::Consumer(consumer)) => {
+            while let Some(data) = lines.try_next().await? {
+                for time_series in decode_json_lines(&data) {
+                    consumer(time_series?);
+                }
+            }
+        },
+        Some(Sink::Writer(writer)) => {
+            while let Some(data) = lines.try_next().await? {
+                writer.lock().await.write_all(&data).await.map_err(|e| format!("Failed to write data: {e}"))?;
+            }
+        },
+        None => {
+            while lines.try_next().await?.is_some() {
+            }
+        },
+    }
+
+    Ok(())
+}
+
+fn decode_json_lines(data: &[u8]) -> impl Iterator<Item = GenericResult<TimeSeries>> + use<'_> {
+    data.split(|&byte| byte == b'\n').filter(|line| !line.is_empty()).map(|line| {
+        Ok(serde_json::from_slice(line).map_err(|e| format!("Got an invalid time series: {e}"))?)
+    })
+}
+
+async fn import_batches<S>(
+    options: Arc<Options>, target_url: &Url, header: &[u8], lines: S, stat: Arc<Mutex<Stat>>,
+) -> EmptyResult
+    where S: Stream<Item = GenericResult<Vec<u8>>>
+{
+    pin!(lines);
+
+    let mut batch = header.to_vec();
+    let mut batch_lines = 0;
+    let mut batches = JoinSet::new();
+
+    loop {
+        let line = lines.try_next().await?;
+        let is_last = line.is_none();
+
+        if let Some(line) = line {
+            batch.extend(line);
+            batch_lines += 1;
+
+            if batch_lines < options.batch_lines && batch.len() < options.batch_size {
+                continue;
+            }
+        }
+
+        if batch_lines != 0 {
+            while batches.len() >= options.import_jobs {
+                batches.join_next().await.unwrap().map_err(|e| format!("Import task has crashed: {e}"))??;
+            }
+
+            let data = Bytes::from(std::mem::replace(&mut batch, header.to_vec()));
+            let lines = std::mem::take(&mut batch_lines);
+            let (options, target_url, stat) = (options.clone(), target_url.clone(), stat.clone());
 
-    let response = new_client()?.post(import_url).body(Body::wrap_stream(import_stream)).send().await.map_err(|e| {
+            batches.spawn(async move {
+                retry(options.retries, "Failed to import data", || stat.lock().unwrap().on_retry(), || {
+                    import(&target_url, &options.target_format, &options.import_params, data.clone())
+                }).await?;
+
+                stat.lock().unwrap().on_imported(lines, data.len());
+                EmptyResult::Ok(())
+            });
+        }
+
+        if is_last {
+            break;
+        }
+    }
+
+    while let Some(result) = batches.join_next().await {
+        result.map_err(|e| format!("Import task has crashed: {e}"))??;
+    }
+
+    Ok(())
+}
+
+fn split_time_range(start_time: i64, end_time: i64, window: i64) -> Vec<(i64, i64)> {
+    let mut windows = Vec::new();
+    let mut start = start_time;
+
+    while start < end_time {
+        let end = start.saturating_add(window).min(end_time);
+        windows.push((start, end));
+        start = end;
+    }
+
+    windows
+}
+
+async fn import(target_url: &Url, format: &Format, params: &[(String, String)], body: Bytes) -> EmptyResult {
+    let (request, server) = match format {
+        Format::Remote => (new_client()?.post(target_url.clone())
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(remote::compress(&body)?), "Remote write target"),
+        _ => {
+            let mut import_url = target_url.join(format.import_path().unwrap()).map_err(|e| format!(
+                "Invalid URL: {e}"))?;
+
+            if !params.is_empty() {
+                import_url.query_pairs_mut().extend_pairs(params);
+            }
+            (new_client()?.post(import_url).body(body), "Target VictoriaMetrics")
+        },
+    };
+
+    let response = request.send().await.map_err(|e| {
         if e.is_connect() {
-            format!("Failed to establish connection to target VictoriaMetrics: {e}")
+            transient(format!("Failed to establish connection to {}: {e}", server.to_lowercase()))
         } else if e.is_body() {
-            e.to_string()
+            e.to_string().into()
         } else {
-            format!("Target VictoriaMetrics connection error: {e}")
+            with_context(http_error(e), |e| format!("{server} connection error: {e}"))
         }
     })?;
 
-    let status = response.status();
-    if !status.is_success() {
-        let message = response.text().await.unwrap_or_else(|e| e.to_string());
-        return Err!("Target VictoriaMetrics returned an error ({status}): {}", message.trim());
-    }
-
+    check_response(response, server).await?;
     Ok(())
 }
 
-async fn get_import_stream(source_url: &Url, start_time: Option<&str>) -> impl Stream<Item = GenericResult<Vec<u8>>> {
-    let source_url = source_url.clone();
-    let start_time = start_time.map(ToOwned::to_owned);
+fn get_response_reader(response: Response) -> Reader {
+    Box::new(StreamReader::new(response.bytes_stream().map_err(io::Error::other)))
+}
+
+// Opens the task's source data stream and returns the stream of the migrated data in the target format
+async fn get_import_stream(
+    options: Arc<Options>, task: &Task, migrator: Arc<Migrator>, stat: Arc<Mutex<Stat>>,
+) -> GenericResult<BoxStream<'static, GenericResult<Vec<u8>>>> {
+    let reader = match options.source {
+        Location::VictoriaMetrics(ref source_url) => get_response_reader(get_export_stream(
+            source_url, &options.source_format, &task.selector_params(&options.selectors), &options.export_params,
+            task.start_time, task.end_time).await?),
+
+        Location::RemoteRead(ref source_url) => {
+            let response = get_remote_read_stream(
+                source_url, &options.selectors, task.name.as_deref(), task.start_time, task.end_time).await?;
+
+            let streamed = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with(remote::STREAMED_CONTENT_TYPE));
+
+            let time_series_stream = remote::read_time_series(
+                streamed, options.selectors.clone(), get_response_reader(response));
+            let pass_through = options.target_format == Format::Remote;
+            return Ok(get_time_series_import_stream(options, time_series_stream, pass_through, migrator, stat).boxed());
+        },
+
+        // The time series are read from binary files, so there is no source data to pass through
+        Location::Tsdb(ref path) => {
+            let time_series_stream = select(&options, tsdb::read_time_series(
+                path.clone(), task.start_time, task.end_time,
+            ).map_ok(|time_series| (time_series, Vec::new())));
+
+            return Ok(get_time_series_import_stream(options, time_series_stream, false, migrator, stat).boxed());
+        },
+
+        ref location => location.open(options.compression(location)).await?,
+    };
+
+    Ok(match options.source_format {
+        Format::Native => get_native_import_stream(options, reader, migrator, stat).boxed(),
+        _ => {
+            let time_series_stream = select(&options, formats::read_time_series(options.source_format.clone(), reader));
+            let pass_through = options.source_format == options.target_format;
+            get_time_series_import_stream(options, time_series_stream, pass_through, migrator, stat).boxed()
+        },
+    })
+}
+
+// Filters the time series by the series selectors for the sources which don't support them
+fn select<S>(options: &Options, time_series_stream: S) -> impl Stream<Item = GenericResult<(TimeSeries, Vec<u8>)>>
+    where S: Stream<Item = GenericResult<(TimeSeries, Vec<u8>)>>
+{
+    let selectors = options.selectors.clone();
+    time_series_stream.try_filter(move |(time_series, _data)| future::ready(
+        selectors.is_empty() || selectors.iter().any(|selector| selector.matches(time_series))))
+}
 
+// The unchanged time series are passed through as is if source data is in the target format.
+//
+// A time series may be split into several parts by the source (VictoriaMetrics exports it by its storage blocks, remote
+// read responses are chunked), so if the migration has sample conditions, the time series it may change are collected
+// in memory and migrated as a whole when the stream ends.
+fn get_time_series_import_stream<S>(
+    options: Arc<Options>, time_series_stream: S, pass_through: bool, migrator: Arc<Migrator>, stat: Arc<Mutex<Stat>>,
+) -> impl Stream<Item = GenericResult<Vec<u8>>>
+    where S: Stream<Item = GenericResult<(TimeSeries, Vec<u8>)>>
+{
     try_stream! {
-        let export_stream = get_export_stream(&source_url, start_time.as_deref()).await.map_err(|e| format!(
-            "Failed to establish connection to source VictoriaMetrics: {e}"))?
-            .bytes_stream().map_err(|e| io::Error::new(ErrorKind::Other, e));
+        pin!(time_series_stream);
 
-        let mut stat = Stat::new();
-        let mut export_lines = StreamReader::new(export_stream).lines();
+        let collect = migrator.has_sample_conditions();
+        let mut collected: HashMap<String, TimeSeries> = HashMap::new();
 
-        loop {
-            let Some(export_line) = export_lines.next_line().await.map_err(|e| format!(
-                "Source VictoriaMetrics connection error: {e}"
-            ))? else {
-                break;
-            };
+        while let Some((time_series, data)) = time_series_stream.try_next().await? {
+            if collect && migrator.may_change(&time_series) {
+                match collected.entry(time_series.format_metric()) {
+                    Entry::Occupied(mut entry) => entry.get_mut().extend(&time_series),
+                    Entry::Vacant(entry) => { entry.insert(time_series); },
+                }
+                continue;
+            }
 
-            let time_series: TimeSeries = serde_json::from_str(&export_line).map_err(|e| format!(
-                "Got an invalid time series ({e}): {export_line}"))?;
+            let result = migrate(&migrator, &stat, &time_series);
 
-            let result = migrator::migrate(&time_series);
-            stat.add(&time_series, &result);
+            if pass_through && matches!(result, MigratedTimeSeries::Unchanged) {
+                yield data;
+                continue;
+            }
 
-            match result {
-                MigratedTimeSeries::Unchanged => {
-                    let mut data = export_line.into_bytes();
-                    data.push(b'\n');
-                    yield data;
-                },
+            for data in encode_migrated(&options.target_format, time_series, result)? {
+                yield data;
+            }
+        }
 
-                MigratedTimeSeries::Changed(time_series) => if !time_series.is_empty() {
-                    let mut buf = export_line.into_bytes();
-                    buf.truncate(0);
+        for (_, mut time_series) in collected {
+            time_series.sort();
 
-                    serde_json::to_writer(&mut buf, &time_series).map_err(|e| format!(
-                        "Failed to serialize time series: {e}"))?;
+            let result = migrate(&migrator, &stat, &time_series);
 
-                    buf.push(b'\n');
-                    yield buf;
-                },
+            for data in encode_migrated(&options.target_format, time_series, result)? {
+                yield data;
+            }
+        }
+    }
+}
 
-                MigratedTimeSeries::Rewrite(result) => {
-                    let mut buf = export_line.into_bytes();
-                    buf.truncate(0);
+// Native blocks of the time series which can't be affected by the migration are passed through as is (or converted
+// block by block if target format differs). The matching time series are decoded, and since their blocks may be spread
+// over the whole stream, they are collected in memory, migrated and encoded back when the stream ends.
+fn get_native_import_stream<R: AsyncBufRead + Unpin>(
+    options: Arc<Options>, mut reader: R, migrator: Arc<Migrator>, stat: Arc<Mutex<Stat>>,
+) -> impl Stream<Item = GenericResult<Vec<u8>>> {
+    try_stream! {
+        let pass_through = options.target_format == Format::Native;
 
-                    let mut buf = Some(buf);
+        let read_error = |e: io::Error| if e.kind() == io::ErrorKind::InvalidData {
+            e.to_string().into()
+        } else {
+            transient(format!("Failed to read source data: {e}"))
+        };
 
-                    for time_series in result {
-                        if !time_series.is_empty() {
-                            let mut buf = buf.take().unwrap_or_default();
+        native::read_header(&mut reader).await.map_err(read_error)?;
 
-                            serde_json::to_writer(&mut buf, &time_series).map_err(|e| format!(
-                                "Failed to serialize time series: {e}"))?;
+        let mut decoded: HashMap<Vec<u8>, TimeSeries> = HashMap::new();
 
-                            buf.push(b'\n');
-                            yield buf;
-                        }
-                    }
-                },
+        while let Some(block) = native::Block::read(&mut reader).await.map_err(read_error)? {
+            if let Some(time_series) = decoded.get_mut(block.metric_name()) {
+                block.decode(time_series)?;
+                continue;
+            }
 
-                MigratedTimeSeries::Deleted => {},
+            let mut time_series = TimeSeries::new(block.labels()?);
+
+            if !migrator.may_change(&time_series) {
+                let mut data = Vec::new();
+
+                if pass_through {
+                    let rows = block.rows()?;
+                    stat.lock().unwrap().add_unchanged(time_series.name(), rows);
+                    block.write(&mut data);
+                } else {
+                    block.decode(&mut time_series)?;
+                    stat.lock().unwrap().add_unchanged(time_series.name(), time_series.len());
+                    options.target_format.encode(&time_series, &mut data)?;
+                }
+
+                yield data;
+                continue;
             }
+
+            block.decode(&mut time_series)?;
+            decoded.insert(block.metric_name().to_owned(), time_series);
         }
 
-        stat.print();
+        for (_, mut time_series) in decoded {
+            time_series.sort();
+
+            let result = migrate(&migrator, &stat, &time_series);
+
+            for data in encode_migrated(&options.target_format, time_series, result)? {
+                yield data;
+            }
+        }
     }
 }
 
-async fn get_export_stream(source_url: &Url, start_time: Option<&str>) -> GenericResult<Response> {
-    let mut export_url = source_url.join("/api/v1/export").map_err(|e| format!(
-        "Invalid URL: {e}"))?;
+fn migrate(migrator: &Migrator, stat: &Mutex<Stat>, time_series: &TimeSeries) -> MigratedTimeSeries {
+    let mut stat = stat.lock().unwrap();
+    let result = migrator.migrate(time_series, &mut stat);
+    stat.add(time_series, &result);
+    result
+}
 
-    {
-        let mut query = export_url.query_pairs_mut();
-        query.append_pair("match", r#"{__name__!=""}"#);
-        query.append_pair("reduce_mem_usage", "1");
+// Encodes the resulting time series in the target format
+fn encode_migrated(format: &Format, source: TimeSeries, result: MigratedTimeSeries) -> GenericResult<Vec<Vec<u8>>> {
+    let results = match result {
+        MigratedTimeSeries::Unchanged => vec![source],
+        MigratedTimeSeries::Changed(time_series) => vec![time_series],
+        MigratedTimeSeries::Rewrite(results) => results,
+        MigratedTimeSeries::Deleted => Vec::new(),
+    };
 
-        if let Some(start_time) = start_time {
-            query.append_pair("start", start_time);
+    let mut encoded = Vec::with_capacity(results.len());
+
+    for time_series in results {
+        if !time_series.is_empty() {
+            let mut data = Vec::new();
+            format.encode(&time_series, &mut data)?;
+            encoded.push(data);
         }
     }
 
-    let response = new_client()?.get(export_url).send().await?;
+    Ok(encoded)
+}
+
+// Generates vm_migrate_* marker time series for the applied (or rolled back) migrations, so the history rewrites can be
+// shown as annotations in Grafana. They are written only by the recorded run, so a migration made in several passes
+// (like by the migrate script) is marked once with the series counts of the recorded pass.
+fn get_migration_markers(migrator: &Migrator, stat: &Stat) -> GenericResult<Vec<u8>> {
+    let time = Utc::now().timestamp_millis();
+    let mut buf = Vec::new();
+
+    for (index, migration) in migrator.migrations().iter().enumerate() {
+        let version = migration.version.to_string();
+        let stat = stat.migrations().get(index).cloned().unwrap_or_default();
+
+        for (name, value) in [
+            (if migration.rollback { "vm_migrate_rolled_back" } else { "vm_migrate_applied" }, 1),
+            ("vm_migrate_changed_series", stat.changed),
+            ("vm_migrate_deleted_series", stat.deleted),
+        ] {
+            let time_series = json!({
+                "metric": {
+                    "__name__": name,
+                    "migration": migration.name,
+                    "version": version,
+                },
+                "values": [value],
+                "timestamps": [time],
+            });
 
+            serde_json::to_writer(&mut buf, &time_series).map_err(|e| format!(
+                "Failed to serialize time series: {e}"))?;
+
+            buf.push(b'\n');
+        }
+    }
+
+    Ok(buf)
+}
+
+// Deletes the time series matching the selectors (VictoriaMetrics deletes the whole series regardless of time range)
+#[tokio::main]
+pub async fn delete_series(url: &Url, selectors: &[Selector], retries: usize) -> EmptyResult {
+    let mut delete_url = url.join("/api/v1/admin/tsdb/delete_series").map_err(|e| format!("Invalid URL: {e}"))?;
+    delete_url.query_pairs_mut().extend_pairs(selectors.iter().map(|selector| ("match[]", selector.to_string())));
+
+    retry(retries, "Failed to delete time series", || {}, || async {
+        let response = new_client()?.post(delete_url.clone()).send().await.map_err(|e| with_context(
+            http_error(e), |e| format!("Failed to establish connection to VictoriaMetrics: {e}")))?;
+        check_response(response, "VictoriaMetrics").await?;
+        Ok(())
+    }).await
+}
+
+// Resets rollup result cache, so the queries don't return stale results cached before the time series were deleted
+#[tokio::main]
+pub async fn reset_rollup_cache(url: &Url, retries: usize) -> EmptyResult {
+    let reset_url = url.join("/internal/resetRollupResultCache").map_err(|e| format!("Invalid URL: {e}"))?;
+
+    retry(retries, "Failed to reset rollup result cache", || {}, || async {
+        let response = new_client()?.get(reset_url.clone()).send().await.map_err(|e| with_context(
+            http_error(e), |e| format!("Failed to establish connection to VictoriaMetrics: {e}")))?;
+        check_response(response, "VictoriaMetrics").await?;
+        Ok(())
+    }).await
+}
+
+// Imports the time series into the target VictoriaMetrics in batches
+#[tokio::main]
+pub async fn import_time_series(options: Options, time_series: Vec<TimeSeries>) -> EmptyResult {
+    let Some(Location::VictoriaMetrics(ref target_url)) = options.target else {
+        return Err!("Import is supported only for VictoriaMetrics target");
+    };
+
+    let target_url = target_url.clone();
+    let stat = Arc::new(Mutex::new(Stat::new(false)));
+    let options = Arc::new(Options {target_format: Format::Json, ..options});
+
+    let lines = stream::iter(time_series.into_iter().map(|time_series| {
+        let mut data = Vec::new();
+        Format::Json.encode(&time_series, &mut data)?;
+        Ok(data)
+    }));
+
+    import_batches(options, &target_url, &[], lines, stat).await
+}
+
+// Flushes the recently ingested data to make it visible for search
+#[tokio::main]
+pub async fn force_flush(url: &Url, retries: usize) -> EmptyResult {
+    let flush_url = url.join("/internal/force_flush").map_err(|e| format!("Invalid URL: {e}"))?;
+
+    retry(retries, "Failed to flush the ingested data", || {}, || async {
+        let response = new_client()?.get(flush_url.clone()).send().await.map_err(|e| with_context(
+            http_error(e), |e| format!("Failed to establish connection to VictoriaMetrics: {e}")))?;
+        check_response(response, "VictoriaMetrics").await?;
+        Ok(())
+    }).await
+}
+
+// Returns the names of the source metrics matching the series selectors
+#[tokio::main]
+pub async fn metric_names(options: &Options) -> GenericResult<Vec<String>> {
+    let Location::VictoriaMetrics(ref source_url) = options.source else {
+        return Err!("Metric names can be requested only from VictoriaMetrics");
+    };
+
+    retry(options.retries, "Failed to get metric names", || {}, || {
+        get_metric_names(source_url, &options.selectors, options.start_time, options.end_time)
+    }).await.map_err(|e| format!("Failed to get metric names from VictoriaMetrics: {e}").into())
+}
+
+async fn get_metric_names(
+    source_url: &Url, selectors: &[Selector], start_time: Option<i64>, end_time: Option<i64>,
+) -> GenericResult<Vec<String>> {
+    #[derive(Deserialize)]
+    struct LabelValues {
+        data: Vec<String>,
+    }
+
+    let mut url = source_url.join("/api/v1/label/__name__/values").map_err(|e| format!(
+        "Invalid URL: {e}"))?;
+
+    url.query_pairs_mut().extend_pairs(selectors.iter().map(|selector| ("match[]", selector.to_string())));
+    set_time_range(&mut url, start_time, end_time);
+
+    let response = new_client()?.get(url).send().await.map_err(http_error)?;
+    let response = check_response(response, "The server").await?;
+
+    let mut names = response.json::<LabelValues>().await.map_err(http_error)?.data;
+    names.sort();
+
+    Ok(names)
+}
+
+// The extra parameters override the format's default ones
+async fn get_export_stream(
+    source_url: &Url, format: &Format, selector_params: &[(&str, String)], extra_params: &[(String, String)],
+    start_time: Option<i64>, end_time: Option<i64>,
+) -> GenericResult<Response> {
+    let mut export_url = source_url.join(format.export_path().unwrap()).map_err(|e| format!(
+        "Invalid URL: {e}"))?;
+
+    export_url.query_pairs_mut()
+        .extend_pairs(selector_params)
+        .extend_pairs(format.export_params().into_iter().filter(|(name, _value)| {
+            extra_params.iter().all(|(extra_name, _value)| extra_name != name)
+        }))
+        .extend_pairs(extra_params);
+
+    set_time_range(&mut export_url, start_time, end_time);
+
+    let response = new_client()?.get(export_url).send().await.map_err(|e| with_context(http_error(e), |e| format!(
+        "Failed to establish connection to source VictoriaMetrics: {e}")))?;
+
+    check_response(response, "Source VictoriaMetrics").await
+}
+
+// Requests [start, end) time range data via remote read (remote read treats the end time as inclusive)
+async fn get_remote_read_stream(
+    source_url: &Url, selectors: &[Selector], name: Option<&str>, start_time: Option<i64>, end_time: Option<i64>,
+) -> GenericResult<Response> {
+    let end_time = end_time.unwrap_or_else(|| Utc::now().timestamp_millis() + 1) - 1;
+    let request = remote::encode_read_request(selectors, name, start_time.unwrap_or(0), end_time)?;
+
+    let response = new_client()?.post(source_url.clone())
+        .header(CONTENT_TYPE, "application/x-protobuf")
+        .header(CONTENT_ENCODING, "snappy")
+        .header("X-Prometheus-Remote-Read-Version", "0.1.0")
+        .body(request)
+        .send().await.map_err(|e| with_context(http_error(e), |e| format!(
+            "Failed to establish connection to remote read source: {e}")))?;
+
+    check_response(response, "Remote read source").await
+}
+
+async fn check_response(response: Response, server: &str) -> GenericResult<Response> {
     let status = response.status();
-    if !status.is_success() {
-        let message = response.text().await.unwrap_or_else(|e| e.to_string());
-        return Err!("The server returned an error ({}): {}", status, message.trim());
+    if status.is_success() {
+        return Ok(response);
     }
 
-    Ok(response)
+    let message = response.text().await.unwrap_or_else(|e| e.to_string());
+    let error = format!("{server} returned an error ({status}): {}", message.trim());
+
+    Err(if is_transient_status(status) {
+        transient(error)
+    } else {
+        error.into()
+    })
+}
+
+// Sets [start, end) time range query parameters (VictoriaMetrics treats the end time as inclusive)
+fn set_time_range(url: &mut Url, start_time: Option<i64>, end_time: Option<i64>) {
+    let mut query = url.query_pairs_mut();
+
+    if let Some(start_time) = start_time {
+        query.append_pair("start", &time::format_timestamp(start_time));
+    }
+
+    if let Some(end_time) = end_time {
+        query.append_pair("end", &time::format_timestamp(end_time - 1));
+    }
 }
 
 fn new_client() -> GenericResult<Client> {
     Ok(ClientBuilder::new()
+        .connect_timeout(Duration::from_secs(30))
+        .read_timeout(Duration::from_secs(10 * 60))
         .redirect(reqwest::redirect::Policy::none())
         .no_brotli()
         .no_deflate()
         .no_gzip()
         .no_zstd()
         .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    use super::*;
+
+    const LINES: &str = concat!(
+        r#"{"metric":{"__name__":"up","job":"a"},"values":[1],"timestamps":[1000]}"#, "\n",
+        r#"{"metric":{"__name__":"up","job":"b"},"values":[2],"timestamps":[1000]}"#, "\n",
+    );
+
+    // A stand-in source VictoriaMetrics which responds to the export requests with the specified number of the data
+    // bytes (dropping the connection in the middle of the response) and then with the whole data
+    fn source(truncated: Vec<usize>) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        thread::spawn(move || for connection in listener.incoming() {
+            let mut connection = BufReader::new(connection.unwrap());
+
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                connection.read_line(&mut line).unwrap();
+            }
+
+            let request = counter.fetch_add(1, Ordering::SeqCst);
+            let size = truncated.get(request).copied().unwrap_or(LINES.len());
+
+            write!(connection.get_mut(), "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   LINES.len(), &LINES[..size]).unwrap();
+        });
+
+        (url, requests)
+    }
+
+    fn migrate(source_url: Url, target: &Path) -> EmptyResult {
+        process(Options {
+            source: Location::VictoriaMetrics(source_url),
+            target: Some(Location::File(target.to_owned())),
+            compression: Some(Compression::None),
+            source_format: Format::Json,
+            target_format: Format::Json,
+            start_time: None,
+            end_time: None,
+            window: None,
+            jobs: 1,
+            checkpoint: None,
+            resume: false,
+            retries: 2,
+            batch_lines: 1000,
+            batch_size: 1024 * 1024,
+            import_jobs: 1,
+            selectors: Vec::new(),
+            metrics: None,
+            export_params: Vec::new(),
+            import_params: Vec::new(),
+            parquet: parquet::Options::default(),
+            record: false,
+        }, Arc::new(Migrator::new(Vec::new(), None, false)))?;
+        Ok(())
+    }
+
+    #[test]
+    fn retry_streamed_task() {
+        let path = std::env::temp_dir().join(format!("vm-migrate-{}-retry.jsonl", std::process::id()));
+
+        // Nothing has been written yet: the task is retried
+        let (url, requests) = source(vec![0, 10]);
+        migrate(url, &path).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(fs::read_to_string(&path).unwrap(), LINES);
+
+        // The first time series has been written: retrying would write it again
+        let (url, requests) = source(vec![LINES.find('\n').unwrap() + 10]);
+        let error = migrate(url, &path).unwrap_err().to_string();
+        assert!(error.contains("Failed to read source data"), "{error}");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
 }
\ No newline at end of file
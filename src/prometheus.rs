@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::core::GenericResult;
+use crate::metrics::{LabeledSample, TimeSeries};
+
+// Prometheus text exposition format with timestamps: `name{label="value",...} value timestamp_ms` line per sample.
+// Null values are written as NaN and vice versa.
+
+pub fn encode(time_series: &TimeSeries, buf: &mut Vec<u8>) {
+    let name = time_series.name();
+    let mut metric = String::new();
+    let mut labels = time_series.labels();
+
+    if is_valid_name(name) {
+        metric.push_str(name);
+    } else {
+        labels.insert(0, ("__name__", name));
+    }
+
+    if !labels.is_empty() {
+        metric.push('{');
+
+        for (index, (name, value)) in labels.into_iter().enumerate() {
+            if index != 0 {
+                metric.push(',');
+            }
+
+            _ = write!(&mut metric, "{name}=\"");
+
+            for char in value.chars() {
+                match char {
+                    '\\' => metric.push_str(r"\\"),
+                    '"' => metric.push_str(r#"\""#),
+                    '\n' => metric.push_str(r"\n"),
+                    _ => metric.push(char),
+                }
+            }
+
+            metric.push('"');
+        }
+
+        metric.push('}');
+    }
+
+    for (time, value) in time_series.iter() {
+        let value = match value {
+            None => "NaN".to_owned(),
+            Some(f64::INFINITY) => "+Inf".to_owned(),
+            Some(f64::NEG_INFINITY) => "-Inf".to_owned(),
+            Some(value) => value.to_string(),
+        };
+
+        buf.extend(format!("{metric} {value} {time}\n").as_bytes());
+    }
+}
+
+// Parses a line into sample labels, time and value. Returns None for comments and empty lines.
+pub fn parse_line(line: &str) -> GenericResult<Option<LabeledSample>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let invalid = || format!("Got an invalid Prometheus text line: {line}");
+
+    let name_end = line.find(|char: char| char == '{' || char.is_whitespace()).ok_or_else(invalid)?;
+    let (name, mut rest) = line.split_at(name_end);
+
+    let mut labels = HashMap::new();
+    if !name.is_empty() {
+        labels.insert("__name__".to_owned(), name.to_owned());
+    }
+
+    if let Some(data) = rest.strip_prefix('{') {
+        rest = data;
+
+        loop {
+            rest = rest.trim_start();
+
+            if let Some(data) = rest.strip_prefix('}') {
+                rest = data;
+                break;
+            }
+
+            let (name, data) = rest.split_once('=').ok_or_else(invalid)?;
+            let mut chars = data.trim_start().strip_prefix('"').ok_or_else(invalid)?.chars();
+            let mut value = String::new();
+
+            loop {
+                match chars.next().ok_or_else(invalid)? {
+                    '"' => break,
+                    '\\' => value.push(match chars.next().ok_or_else(invalid)? {
+                        'n' => '\n',
+                        char => char,
+                    }),
+                    char => value.push(char),
+                }
+            }
+
+            labels.insert(name.trim().to_owned(), value);
+
+            rest = chars.as_str().trim_start();
+            if let Some(data) = rest.strip_prefix(',') {
+                rest = data;
+            } else if !rest.starts_with('}') {
+                return Err(invalid().into());
+            }
+        }
+    }
+
+    if !labels.contains_key("__name__") {
+        return Err(invalid().into());
+    }
+
+    let mut fields = rest.split_whitespace();
+    let (Some(value), Some(time), None) = (fields.next(), fields.next(), fields.next()) else {
+        return Err!("Got an invalid Prometheus text line (a value with timestamp is expected): {line}");
+    };
+
+    let value = match value {
+        "NaN" => None,
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        _ => Some(value.parse().map_err(|_| invalid())?),
+    };
+
+    let time = time.parse().map_err(|_| invalid())?;
+
+    Ok(Some((labels, time, value)))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|char| char.is_ascii_alphabetic() || char == '_' || char == ':') &&
+        chars.all(|char| char.is_ascii_alphanumeric() || char == '_' || char == ':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = [
+            (1000, Some(1.5)), (2000, None), (3000, Some(f64::INFINITY)), (4000, Some(f64::NEG_INFINITY)),
+            (-5000, Some(-1e-300)),
+        ];
+
+        for metric in [
+            labels(&[("__name__", "up")]),
+            labels(&[("__name__", "up"), ("job", "node"), ("path", "C:\\dir \"quoted\"\nnext, {}")]),
+            labels(&[("__name__", "invalid-name.total"), ("job", "node")]),
+        ] {
+            let mut time_series = TimeSeries::new(metric.clone());
+            for (time, value) in samples {
+                time_series.add(time, value);
+            }
+
+            let mut buf = Vec::new();
+            encode(&time_series, &mut buf);
+
+            let data = String::from_utf8(buf).unwrap();
+            let parsed: Vec<_> = data.lines().map(|line| parse_line(line).unwrap().unwrap()).collect();
+
+            let expected: Vec<_> = samples.iter().map(|&(time, value)| (metric.clone(), time, value)).collect();
+            assert_eq!(parsed, expected, "{data}");
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_line("  # HELP up").unwrap(), None);
+        assert_eq!(parse_line("").unwrap(), None);
+
+        let expected = labels(&[("__name__", "up"), ("job", "node"), ("instance", r"a\b")]);
+        assert_eq!(
+            parse_line(r#"up{ job = "node" , instance="a\\b", } 1e3 1700000000000"#).unwrap(),
+            Some((expected, 1_700_000_000_000, Some(1000.0))),
+        );
+        assert_eq!(
+            parse_line(r#"{__name__="up"} NaN 1000"#).unwrap(),
+            Some((labels(&[("__name__", "up")]), 1000, None)),
+        );
+
+        for line in [
+            "up 1", "up 1 1000 extra", "up x 1000", "up 1 1.5", r#"{job="node"} 1 1000"#, r#"up{job="node} 1 1000"#,
+            r#"up{job=node} 1 1000"#, r#"up{job="node" instance="a"} 1 1000"#,
+        ] {
+            assert!(parse_line(line).is_err(), "{line}");
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+use std::io;
+
+use async_stream::try_stream;
+use futures_core::stream::Stream;
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::core::{GenericError, GenericResult};
+use crate::metrics;
+use crate::retry::transient;
+use crate::selector::Selector;
+use crate::xor;
+
+// Prometheus remote storage protocol: snappy-compressed protobuf messages for remote write and remote read requests
+// and sampled remote read responses, and CRC32-checked frames of uncompressed protobuf messages with XOR-encoded
+// chunks for streamed remote read responses.
+
+pub const STREAMED_CONTENT_TYPE: &str = "application/x-streamed-protobuf";
+
+const MATCH_EQUAL: i32 = 0;
+const MATCH_NOT_EQUAL: i32 = 1;
+const MATCH_REGEX: i32 = 2;
+const MATCH_NOT_REGEX: i32 = 3;
+
+const RESPONSE_SAMPLES: i32 = 0;
+const RESPONSE_STREAMED_XOR_CHUNKS: i32 = 1;
+
+const CHUNK_XOR: i32 = 1;
+
+const MAX_FRAME_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReadRequest {
+    #[prost(message, repeated, tag = "1")]
+    queries: Vec<Query>,
+    #[prost(int32, repeated, tag = "2")]
+    accepted_response_types: Vec<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Query {
+    #[prost(int64, tag = "1")]
+    start_timestamp_ms: i64,
+    #[prost(int64, tag = "2")]
+    end_timestamp_ms: i64,
+    #[prost(message, repeated, tag = "3")]
+    matchers: Vec<LabelMatcher>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LabelMatcher {
+    #[prost(int32, tag = "1")]
+    r#type: i32,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "3")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    results: Vec<QueryResult>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ChunkedReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    chunked_series: Vec<ChunkedSeries>,
+    #[prost(int64, tag = "2")]
+    query_index: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ChunkedSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Chunk {
+    #[prost(int32, tag = "3")]
+    r#type: i32,
+    #[prost(bytes = "vec", tag = "4")]
+    data: Vec<u8>,
+}
+
+// Encodes the time series as a `WriteRequest.timeseries` field, so a concatenation of encoded time series forms a
+// valid `WriteRequest`. Null values are written as NaN.
+pub fn encode(time_series: &metrics::TimeSeries, buf: &mut Vec<u8>) {
+    let mut labels: Vec<_> = time_series.labels().into_iter().chain([("__name__", time_series.name())])
+        .map(|(name, value)| Label {name: name.to_owned(), value: value.to_owned()})
+        .collect();
+
+    // Remote write requires labels to be sorted by name
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let samples = time_series.iter().map(|(timestamp, value)| Sample {
+        value: value.unwrap_or(f64::NAN),
+        timestamp,
+    }).collect();
+
+    prost::encoding::message::encode(1, &TimeSeries {labels, samples}, buf);
+}
+
+pub fn compress(data: &[u8]) -> GenericResult<Vec<u8>> {
+    Ok(snap::raw::Encoder::new().compress_vec(data).map_err(|e| format!(
+        "Failed to compress remote storage request: {e}"))?)
+}
+
+// Encodes a remote read request for the time series matching any of the selectors (or all time series) of the specified
+// metric (or all metrics) within [start, end] time range preferring streamed response. Each selector is a separate
+// query.
+pub fn encode_read_request(
+    selectors: &[Selector], name: Option<&str>, start_time: i64, end_time: i64,
+) -> GenericResult<Vec<u8>> {
+    let name_matcher = match name {
+        Some(name) => LabelMatcher {r#type: MATCH_EQUAL, name: "__name__".to_owned(), value: name.to_owned()},
+        None => LabelMatcher {r#type: MATCH_REGEX, name: "__name__".to_owned(), value: ".+".to_owned()},
+    };
+
+    let query = |selector: Option<&Selector>| Query {
+        start_timestamp_ms: start_time,
+        end_timestamp_ms: end_time,
+        matchers: [name_matcher.clone()].into_iter().chain(selector.into_iter().flat_map(|selector| {
+            selector.matchers().map(|(name, operator, value)| LabelMatcher {
+                r#type: match operator {
+                    "=" => MATCH_EQUAL,
+                    "!=" => MATCH_NOT_EQUAL,
+                    "=~" => MATCH_REGEX,
+                    "!~" => MATCH_NOT_REGEX,
+                    _ => unreachable!(),
+                },
+                name: name.to_owned(),
+                value: value.to_owned(),
+            })
+        })).collect(),
+    };
+
+    let queries = if selectors.is_empty() {
+        vec![query(None)]
+    } else {
+        selectors.iter().map(|selector| query(Some(selector))).collect()
+    };
+
+    let request = ReadRequest {
+        queries,
+        accepted_response_types: vec![RESPONSE_STREAMED_XOR_CHUNKS, RESPONSE_SAMPLES],
+    };
+
+    compress(&request.encode_to_vec())
+}
+
+// Reads remote read response to the request with the specified selectors returning the time series along with their
+// data in remote write format. Consecutive streamed chunked series with the same labels are merged. The time series
+// matching several selectors are returned only for the first of them.
+//
+// Streamed response is decoded frame by frame. Sampled response is a single snappy-compressed message, so it's read
+// as a whole.
+pub fn read_time_series<R: AsyncRead + Unpin>(
+    streamed: bool, selectors: Vec<Selector>, mut reader: R,
+) -> impl Stream<Item = GenericResult<(metrics::TimeSeries, Vec<u8>)>> {
+    let is_duplicate = move |query_index: usize, time_series: &metrics::TimeSeries| {
+        selectors.iter().take(query_index).any(|selector| selector.matches(time_series))
+    };
+
+    try_stream! {
+        if !streamed {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await.map_err(read_error)?;
+
+            let data = snap::raw::Decoder::new().decompress_vec(&data).map_err(|e| format!(
+                "Got an invalid remote read response: {e}"))?;
+
+            let response = ReadResponse::decode(data.as_slice()).map_err(|e| format!(
+                "Got an invalid remote read response: {e}"))?;
+
+            for (query_index, result) in response.results.into_iter().enumerate() {
+                for time_series in result.timeseries {
+                    let mut result = metrics::TimeSeries::new(get_labels(time_series.labels)?);
+                    if is_duplicate(query_index, &result) {
+                        continue;
+                    }
+
+                    for sample in time_series.samples {
+                        result.add(sample.timestamp, get_value(sample.value));
+                    }
+
+                    yield with_data(result);
+                }
+            }
+        } else {
+            let mut current: Option<(usize, Vec<Label>, metrics::TimeSeries)> = None;
+
+            while let Some(data) = read_frame(&mut reader).await? {
+                let response = ChunkedReadResponse::decode(data.as_slice()).map_err(|e| format!(
+                    "Got an invalid remote read response: {e}"))?;
+                let query_index = usize::try_from(response.query_index).unwrap_or_default();
+
+                for series in response.chunked_series {
+                    let is_new = current.as_ref().is_none_or(|(index, labels, _)| {
+                        *index != query_index || *labels != series.labels
+                    });
+
+                    if is_new {
+                        let time_series = metrics::TimeSeries::new(get_labels(series.labels.clone())?);
+                        if is_duplicate(query_index, &time_series) {
+                            continue;
+                        }
+
+                        if let Some((_, _, time_series)) = current.replace((query_index, series.labels, time_series)) {
+                            yield with_data(time_series);
+                        }
+                    }
+
+                    let (_, _, time_series) = current.as_mut().unwrap();
+
+                    for chunk in series.chunks {
+                        if chunk.r#type != CHUNK_XOR {
+                            Err(format!("Got an unsupported remote read chunk type: {}", chunk.r#type))?;
+                        }
+
+                        for (time, value) in xor::decode(&chunk.data)? {
+                            time_series.add(time, get_value(value));
+                        }
+                    }
+                }
+            }
+
+            if let Some((_, _, time_series)) = current {
+                yield with_data(time_series);
+            }
+        }
+    }
+}
+
+fn with_data(time_series: metrics::TimeSeries) -> (metrics::TimeSeries, Vec<u8>) {
+    let mut data = Vec::new();
+    encode(&time_series, &mut data);
+    (time_series, data)
+}
+
+fn get_labels(labels: Vec<Label>) -> GenericResult<HashMap<String, String>> {
+    let labels: HashMap<_, _> = labels.into_iter().map(|label| (label.name, label.value)).collect();
+    if !labels.contains_key("__name__") {
+        return Err!("Got a time series without metric name");
+    }
+    Ok(labels)
+}
+
+// Stale markers and other NaN values are treated as null values
+fn get_value(value: f64) -> Option<f64> {
+    if value.is_nan() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn read_error(e: io::Error) -> GenericError {
+    transient(format!("Failed to read source data: {e}"))
+}
+
+// Reads a frame of streamed response: uvarint data size, big endian CRC32 (Castagnoli) of the data and the data
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> GenericResult<Option<Vec<u8>>> {
+    let mut size: u64 = 0;
+
+    for index in 0.. {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && index == 0 => return Ok(None),
+            Err(e) => return Err(read_error(e)),
+        };
+
+        if index > 9 {
+            return Err!("Got an invalid remote read response frame");
+        }
+
+        size |= u64::from(byte & 0x7f) << (7 * index);
+        if byte < 0x80 {
+            break;
+        }
+    }
+
+    if size > MAX_FRAME_SIZE {
+        return Err!("Got too big remote read response frame: {size} bytes");
+    }
+
+    let checksum = reader.read_u32().await.map_err(read_error)?;
+
+    let mut data = vec![0; size as usize];
+    reader.read_exact(&mut data).await.map_err(read_error)?;
+
+    if crc32c::crc32c(&data) != checksum {
+        return Err!("Got a remote read response frame with invalid checksum");
+    }
+
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use futures_util::TryStreamExt;
+    use url::Url;
+
+    use crate::formats::Format;
+    use crate::location::Location;
+    use crate::migrator::Migrator;
+    use crate::processor::{self, Options};
+    use crate::varint::write_uvarint;
+
+    use super::*;
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {name: name.to_owned(), value: value.to_owned()}
+    }
+
+    fn selectors(selectors: &[&str]) -> Vec<Selector> {
+        selectors.iter().map(|selector| Selector::parse(selector).unwrap()).collect()
+    }
+
+    async fn read(streamed: bool, selectors: Vec<Selector>, data: &[u8]) -> Vec<(String, Vec<(i64, Option<f64>)>)> {
+        let stream = read_time_series(streamed, selectors, data);
+        let time_series: Vec<_> = stream.try_collect().await.unwrap();
+
+        time_series.into_iter().map(|(time_series, data)| {
+            // The data must be a valid remote write request which has the same layout as QueryResult
+            let request = QueryResult::decode(data.as_slice()).unwrap();
+            assert_eq!(request.timeseries.len(), 1);
+            (time_series.format_metric(), time_series.iter().collect())
+        }).collect()
+    }
+
+    fn decode_read_request(data: &[u8]) -> ReadRequest {
+        let data = snap::raw::Decoder::new().decompress_vec(data).unwrap();
+        ReadRequest::decode(data.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn read_request() {
+        let selectors = selectors(&[r#"up{job="node"}"#, r#"{instance!~"proxy.*", env!="dev"}"#]);
+        let request = decode_read_request(&encode_read_request(&selectors, Some("up"), 1000, 2000).unwrap());
+        assert_eq!(request.accepted_response_types, [RESPONSE_STREAMED_XOR_CHUNKS, RESPONSE_SAMPLES]);
+
+        let queries: Vec<Vec<_>> = request.queries.iter().map(|query| {
+            assert_eq!((query.start_timestamp_ms, query.end_timestamp_ms), (1000, 2000));
+            query.matchers.iter().map(|matcher| {
+                (matcher.r#type, matcher.name.as_str(), matcher.value.as_str())
+            }).collect()
+        }).collect();
+
+        let name = (MATCH_EQUAL, "__name__", "up");
+        assert_eq!(queries, [
+            vec![name, name, (MATCH_EQUAL, "job", "node")],
+            vec![name, (MATCH_NOT_REGEX, "instance", "proxy.*"), (MATCH_NOT_EQUAL, "env", "dev")],
+        ]);
+
+        let request = decode_read_request(&encode_read_request(&[], None, 0, 1).unwrap());
+        assert_eq!(request.queries.len(), 1);
+        assert_eq!(request.queries[0].matchers, [
+            LabelMatcher {r#type: MATCH_REGEX, name: "__name__".to_owned(), value: ".+".to_owned()}]);
+    }
+
+    #[tokio::test]
+    async fn sampled_response() {
+        let time_series = |job: &str, samples: &[(i64, f64)]| TimeSeries {
+            labels: vec![label("__name__", "up"), label("job", job)],
+            samples: samples.iter().map(|&(timestamp, value)| Sample {value, timestamp}).collect(),
+        };
+
+        let response = ReadResponse {results: vec![
+            QueryResult {timeseries: vec![time_series("a", &[(1000, 1.0), (2000, f64::NAN)])]},
+            QueryResult {timeseries: vec![time_series("a", &[(1000, 1.0)]), time_series("b", &[(3000, 2.0)])]},
+        ]};
+        let data = compress(&response.encode_to_vec()).unwrap();
+
+        assert_eq!(read(false, selectors(&[r#"{job="a"}"#, "up"]), &data).await, [
+            (r#"up{job="a"}"#.to_owned(), vec![(1000, Some(1.0)), (2000, None)]),
+            (r#"up{job="b"}"#.to_owned(), vec![(3000, Some(2.0))]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn streamed_response() {
+        let frame = |query_index: i64, series: &[(&str, &[(i64, f64)])]| {
+            let response = ChunkedReadResponse {
+                chunked_series: series.iter().map(|&(job, samples)| ChunkedSeries {
+                    labels: vec![label("__name__", "up"), label("job", job)],
+                    chunks: vec![Chunk {r#type: CHUNK_XOR, data: xor::encode(samples)}],
+                }).collect(),
+                query_index,
+            }.encode_to_vec();
+
+            let mut frame = Vec::new();
+            write_uvarint(&mut frame, response.len() as u64);
+            frame.extend(crc32c::crc32c(&response).to_be_bytes());
+            frame.extend(response);
+            frame
+        };
+
+        // The series of the first query is split between frames and is returned by the second query too
+        let mut data = frame(0, &[("a", &[(1000, 1.0)])]);
+        data.extend(frame(0, &[("a", &[(2000, 2.0)])]));
+        data.extend(frame(1, &[("a", &[(1000, 1.0)]), ("b", &[(1000, 3.0)])]));
+
+        assert_eq!(read(true, selectors(&[r#"{job="a"}"#, "up"]), &data).await, [
+            (r#"up{job="a"}"#.to_owned(), vec![(1000, Some(1.0)), (2000, Some(2.0))]),
+            (r#"up{job="b"}"#.to_owned(), vec![(1000, Some(3.0))]),
+        ]);
+
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+
+        let result: GenericResult<Vec<_>> = read_time_series(true, Vec::new(), data.as_slice()).try_collect().await;
+        assert!(result.err().unwrap().to_string().contains("invalid checksum"));
+    }
+
+    type Requests = Arc<Mutex<Vec<(HashMap<String, String>, Vec<u8>)>>>;
+
+    // A stand-in remote write receiver: records the request headers and bodies and fails the first request with a
+    // transient error
+    fn receiver() -> (Url, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/api/v1/write", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        thread::spawn(move || for connection in listener.incoming() {
+            let requests = received.clone();
+
+            thread::spawn(move || {
+                let mut connection = BufReader::new(connection.unwrap());
+
+                loop {
+                    let mut line = String::new();
+                    if connection.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                    assert_eq!(line, "POST /api/v1/write HTTP/1.1\r\n");
+
+                    let mut headers = HashMap::new();
+                    loop {
+                        line.clear();
+                        connection.read_line(&mut line).unwrap();
+
+                        match line.trim_end().split_once(": ") {
+                            Some((name, value)) => headers.insert(name.to_lowercase(), value.to_owned()),
+                            None => break,
+                        };
+                    }
+
+                    let mut body = vec![0; headers["content-length"].parse().unwrap()];
+                    connection.read_exact(&mut body).unwrap();
+
+                    let status = {
+                        let mut requests = requests.lock().unwrap();
+                        requests.push((headers, body));
+                        if requests.len() == 1 {"503 Service Unavailable"} else {"204 No Content"}
+                    };
+
+                    write!(connection.get_mut(), "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").unwrap();
+                }
+            });
+        });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn remote_write() {
+        let path = std::env::temp_dir().join(format!("vm-migrate-{}-remote-write.jsonl", std::process::id()));
+        let (url, requests) = receiver();
+
+        let mut source = String::new();
+        for (job, samples) in [
+            ("a", &[(1000, Some(1.0)), (2000, None)][..]), ("b", &[(1000, Some(2.0))]), ("c", &[(3000, Some(-1.0))]),
+        ] {
+            let mut time_series = metrics::TimeSeries::new(HashMap::from([
+                ("__name__".to_owned(), "up".to_owned()),
+                ("job".to_owned(), job.to_owned()),
+            ]));
+            for &(time, value) in samples {
+                time_series.add(time, value);
+            }
+            source += &serde_json::to_string(&time_series).unwrap();
+            source.push('\n');
+        }
+        fs::write(&path, source).unwrap();
+
+        processor::process(Options {
+            source: Location::File(path.clone()),
+            target: Some(Location::RemoteWrite(url)),
+            compression: None,
+            source_format: Format::Json,
+            target_format: Format::Json,
+            start_time: None,
+            end_time: None,
+            window: None,
+            jobs: 1,
+            checkpoint: None,
+            resume: false,
+            retries: 1,
+            batch_lines: 2,
+            batch_size: 1024 * 1024,
+            import_jobs: 1,
+            selectors: Vec::new(),
+            metrics: None,
+            export_params: Vec::new(),
+            import_params: Vec::new(),
+            parquet: Default::default(),
+            record: false,
+        }, Arc::new(Migrator::new(Vec::new(), None, false))).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        let requests = requests.lock().unwrap();
+        let requests: Vec<_> = requests.iter().map(|(headers, body)| {
+            assert_eq!(headers["content-type"], "application/x-protobuf");
+            assert_eq!(headers["content-encoding"], "snappy");
+            assert_eq!(headers["x-prometheus-remote-write-version"], "0.1.0");
+
+            // WriteRequest has the same layout as QueryResult
+            let data = snap::raw::Decoder::new().decompress_vec(body).unwrap();
+            QueryResult::decode(data.as_slice()).unwrap().timeseries.into_iter().map(|time_series| {
+                let labels: Vec<_> = time_series.labels.iter().map(|label| {
+                    format!("{}={}", label.name, label.value)
+                }).collect();
+                let samples: Vec<_> = time_series.samples.iter().map(|sample| {
+                    (sample.timestamp, if sample.value.is_nan() {None} else {Some(sample.value)})
+                }).collect();
+                (labels.join(","), samples)
+            }).collect::<Vec<_>>()
+        }).collect();
+
+        // The first batch is retried
+        let first = vec![
+            ("__name__=up,job=a".to_owned(), vec![(1000, Some(1.0)), (2000, None)]),
+            ("__name__=up,job=b".to_owned(), vec![(1000, Some(2.0))]),
+        ];
+        assert_eq!(requests, [first.clone(), first, vec![("__name__=up,job=c".to_owned(), vec![(3000, Some(-1.0))])]]);
+    }
+}
\ No newline at end of file
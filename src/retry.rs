@@ -0,0 +1,80 @@
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::time::Duration;
+
+use log::warn;
+use reqwest::StatusCode;
+
+use crate::core::{GenericError, GenericResult};
+
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+// An error which is worth retrying: connection errors, timeouts, server-side errors
+#[derive(Debug)]
+pub struct TransientError(String);
+
+impl Display for TransientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for TransientError {
+}
+
+pub fn transient<E: Display>(error: E) -> GenericError {
+    Box::new(TransientError(error.to_string()))
+}
+
+pub fn is_transient(error: &GenericError) -> bool {
+    error.is::<TransientError>()
+}
+
+// Adds context to the error message preserving its transient status
+pub fn with_context<F: FnOnce(&GenericError) -> String>(error: GenericError, context: F) -> GenericError {
+    let message = context(&error);
+    if is_transient(&error) {
+        transient(message)
+    } else {
+        message.into()
+    }
+}
+
+pub fn is_transient_http_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout() || error.is_request() || error.is_decode() ||
+        error.status().is_some_and(is_transient_status)
+}
+
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT
+}
+
+pub fn http_error(error: reqwest::Error) -> GenericError {
+    if is_transient_http_error(&error) {
+        transient(error)
+    } else {
+        error.into()
+    }
+}
+
+// Executes the operation retrying transient errors with exponential backoff
+pub async fn retry<T, F, R, C>(retries: usize, description: &str, on_retry: C, mut operation: F) -> GenericResult<T>
+    where F: FnMut() -> R, R: Future<Output = GenericResult<T>>, C: Fn()
+{
+    let mut attempt: u32 = 0;
+
+    loop {
+        match operation().await {
+            Err(err) if is_transient(&err) && (attempt as usize) < retries => {
+                let delay = Duration::from_secs(1 << attempt.min(MAX_BACKOFF_SHIFT));
+                attempt += 1;
+
+                warn!("{description}: {err}. Retrying in {}s ({attempt}/{retries})...", delay.as_secs());
+                on_retry();
+
+                tokio::time::sleep(delay).await;
+            },
+            result => return result,
+        }
+    }
+}
\ No newline at end of file
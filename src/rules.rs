@@ -0,0 +1,818 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::metrics::{TimeSeries, MigratedTimeSeries};
+use crate::selector;
+use crate::time::Timestamp;
+
+const NAME_LABEL: &str = "__name__";
+
+// A set of declarative migration rules.
+//
+// Rules are defined in a TOML file:
+//
+//   [[rule]]
+//   match = { __name__ = { prefix = "backup_" }, job = "node", instance = { not_equal = "proxy" } }
+//   set_labels = { name = "laptop" }
+//
+// A label matcher is either a string (equality) or one of `not_equal`, `prefix`, `suffix` and `contains` operators.
+// The supported actions are `delete`, `set_labels`, `remove_labels`, `scale` and time filtering via `since`/`until`.
+//
+// Besides labels, a rule may inspect the samples of the time series. For example, the following rule deletes dead
+// targets and all-zero time series:
+//
+//   [[rule]]
+//   match = { job = "node" }
+//   samples = { last = { lt = "2024-01-01" } }
+//   delete = true
+//
+//   [[rule]]
+//   samples = { all_zero = true }
+//   delete = true
+//
+// The available sample conditions are `count`, `min`, `max`, `first` and `last` comparisons (`lt`, `le`, `gt`, `ge` and
+// `eq` operators) and `all_null` and `all_zero` flags. They are evaluated on the whole time series, so such rules can't
+// be applied when only a time range of the data is migrated.
+//
+// Rules which only rename labels and metrics, remove labels with known values and scale values can be inverted to roll
+// the migration back. The inverted rules match the migrated time series by their new labels, so the rules which migrate
+// different time series to the same labels can't be inverted. The inverted rules will also match the time series which
+// have had these labels before the migration, so such collisions are detected while applying numbered migrations and
+// their rollback is refused then.
+//
+// Rules are evaluated in the order they are defined and the first matching rule wins. To not check each time series
+// against thousands of rules, the rules are indexed by exact metric name, metric name prefix or required label value,
+// so only candidate rules are evaluated for each time series.
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    index: RuleIndex,
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> GenericResult<RuleSet> {
+        for (id, rule) in rules.iter().enumerate() {
+            rule.validate().map_err(|e| format!("Invalid rule #{}: {e}", id + 1))?;
+        }
+
+        let index = RuleIndex::new(&rules);
+        Ok(RuleSet {rules, index})
+    }
+
+    pub fn load(path: &Path) -> GenericResult<RuleSet> {
+        let data = fs::read_to_string(path).map_err(|e| format!(
+            "Unable to read {path:?}: {e}"))?;
+
+        RuleSet::parse(&data).map_err(|e| format!(
+            "Error while reading {path:?}: {e}").into())
+    }
+
+    pub fn parse(data: &str) -> GenericResult<RuleSet> {
+        let file: RulesFile = toml::from_str(data)?;
+        RuleSet::new(file.rules)
+    }
+
+    pub fn format(&self) -> GenericResult<String> {
+        Ok(toml::to_string(&RulesFile {rules: self.rules.clone()})?)
+    }
+
+    // Generates the rule set which reverts the changes made by this one
+    pub fn invert(&self) -> GenericResult<RuleSet> {
+        let mut rules = Vec::with_capacity(self.rules.len());
+        let mut errors = Vec::new();
+
+        for (id, rule) in self.rules.iter().enumerate() {
+            match rule.invert() {
+                Ok(rule) => rules.push(rule),
+                Err(err) => errors.push(format!("* rule #{}: {err}", id + 1)),
+            }
+        }
+
+        if errors.is_empty() {
+            for (id, (rule, inverted)) in self.rules.iter().zip(&rules).enumerate() {
+                for (other_id, (other, other_inverted)) in self.rules.iter().zip(&rules).enumerate().skip(id + 1) {
+                    if rule.shadows(inverted, other, other_inverted) {
+                        errors.push(format!(
+                            "* rule #{}: it would also roll back the time series migrated by rule #{}",
+                            id + 1, other_id + 1));
+                    }
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err!("The following rules are not invertible:\n{}", errors.join("\n"));
+        }
+
+        RuleSet::new(rules)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn apply(&self, time_series: &TimeSeries) -> Option<MigratedTimeSeries> {
+        for id in self.index.candidates(time_series) {
+            let rule = &self.rules[id];
+            if rule.matches(time_series) {
+                return Some(rule.apply(time_series));
+            }
+        }
+        None
+    }
+
+    // Returns Prometheus series selectors which match all the time series the rules may apply to or None if some rule
+    // matches any time series
+    pub fn selectors(&self) -> Option<Vec<String>> {
+        self.rules.iter().map(Rule::selector).collect()
+    }
+
+    pub fn has_sample_conditions(&self) -> bool {
+        self.rules.iter().any(|rule| !rule.samples.is_empty())
+    }
+
+    // Checks whether any rule may apply to the time series judging by its labels only, so the time series which can't
+    // be affected by the rules may be passed through without decoding their samples
+    pub fn may_apply(&self, time_series: &TimeSeries) -> bool {
+        self.index.candidates(time_series).into_iter().any(|id| self.rules[id].matches_labels(time_series))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(rename = "match", default)]
+    selector: BTreeMap<String, Matcher>,
+    #[serde(default, skip_serializing_if = "SamplesCondition::is_empty")]
+    samples: SamplesCondition,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    delete: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    set_labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    remove_labels: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<Timestamp>,
+}
+
+impl Rule {
+    fn validate(&self) -> EmptyResult {
+        if self.delete && (
+            !self.set_labels.is_empty() || !self.remove_labels.is_empty() || self.scale.is_some() ||
+            self.since.is_some() || self.until.is_some()
+        ) {
+            return Err!("delete action can't be combined with other actions");
+        }
+
+        for name in &self.remove_labels {
+            if name == NAME_LABEL {
+                return Err!("metric name can't be removed");
+            } else if self.set_labels.contains_key(name) {
+                return Err!("{name:?} label is both set and removed");
+            }
+        }
+
+        if self.set_labels.get(NAME_LABEL).is_some_and(String::is_empty) {
+            return Err!("metric name can't be empty");
+        }
+
+        if let Some(scale) = self.scale {
+            if !scale.is_finite() || scale == 0.0 {
+                return Err!("invalid scale: {scale}");
+            }
+        }
+
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since >= until {
+                return Err!("since must be less than until");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn invert(&self) -> GenericResult<Rule> {
+        if self.delete {
+            return Err!("deletion can't be reverted");
+        } else if self.since.is_some() || self.until.is_some() {
+            return Err!("time filter can't be reverted");
+        } else if !self.samples.is_empty() {
+            return Err!("rules with sample conditions can't be reverted");
+        }
+
+        let mut selector = self.selector.clone();
+        let mut set_labels = BTreeMap::new();
+        let mut remove_labels = Vec::new();
+
+        let changed_labels = self.set_labels.iter()
+            .map(|(name, value)| (name, value.as_str()))
+            .chain(self.remove_labels.iter().map(|name| (name, "")));
+
+        for (name, value) in changed_labels {
+            let Some(Matcher::Equal(original)) = self.selector.get(name) else {
+                return Err!("original value of {name:?} label is unknown: it must be matched exactly");
+            };
+
+            if original.is_empty() {
+                remove_labels.push(name.clone());
+            } else {
+                set_labels.insert(name.clone(), original.clone());
+            }
+
+            selector.insert(name.clone(), Matcher::Equal(value.to_owned()));
+        }
+
+        Ok(Rule {
+            selector,
+            samples: SamplesCondition::default(),
+            delete: false,
+            set_labels,
+            remove_labels,
+            scale: self.scale.map(|scale| 1.0 / scale),
+            since: None,
+            until: None,
+        })
+    }
+
+    // Checks whether the inverted rule may match the time series migrated by the other (later) rule, which would be
+    // rolled back by the wrong rule then
+    fn shadows(&self, inverted: &Rule, other: &Rule, other_inverted: &Rule) -> bool {
+        if !inverted.overlaps(other_inverted) {
+            return false;
+        }
+
+        // The time series migrated by the other rule haven't matched this rule before the migration, so the inverted
+        // rule can't match them if it checks the same original labels as this one
+        !self.selector.iter().all(|(name, matcher)| match other.original_value(name) {
+            Some(original) => matcher.matches(original),
+            None => !self.changes(name),
+        })
+    }
+
+    // Checks whether some time series may match both rules
+    fn overlaps(&self, other: &Rule) -> bool {
+        self.selector.iter().all(|(name, matcher)| {
+            other.selector.get(name).is_none_or(|other| matcher.overlaps(other))
+        })
+    }
+
+    fn changes(&self, name: &str) -> bool {
+        self.set_labels.contains_key(name) || self.remove_labels.iter().any(|removed| removed == name)
+    }
+
+    // Returns the value of the changed label which it has had before the migration (if the rule is invertible)
+    fn original_value(&self, name: &str) -> Option<&str> {
+        if !self.changes(name) {
+            return None;
+        }
+
+        match self.selector.get(name) {
+            Some(Matcher::Equal(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, time_series: &TimeSeries) -> bool {
+        self.matches_labels(time_series) && self.samples.matches(time_series)
+    }
+
+    fn selector(&self) -> Option<String> {
+        if self.selector.is_empty() {
+            return None;
+        }
+
+        let matchers: Vec<String> = self.selector.iter().map(|(name, matcher)| {
+            let regex_matcher = |regex: String| format!("{name}=~{}", selector::quote(&regex));
+
+            match matcher {
+                Matcher::Equal(value) => format!("{name}={}", selector::quote(value)),
+                Matcher::Operator(MatcherOperator::NotEqual(value)) => format!("{name}!={}", selector::quote(value)),
+                Matcher::Operator(MatcherOperator::Prefix(prefix)) => regex_matcher(regex::escape(prefix) + ".*"),
+                Matcher::Operator(MatcherOperator::Suffix(suffix)) => regex_matcher(
+                    ".*".to_owned() + &regex::escape(suffix)),
+                Matcher::Operator(MatcherOperator::Contains(substring)) => regex_matcher(
+                    format!(".*{}.*", regex::escape(substring))),
+            }
+        }).collect();
+
+        Some(format!("{{{}}}", matchers.join(",")))
+    }
+
+    fn matches_labels(&self, time_series: &TimeSeries) -> bool {
+        self.selector.iter().all(|(name, matcher)| matcher.matches(time_series.label(name)))
+    }
+
+    fn apply(&self, time_series: &TimeSeries) -> MigratedTimeSeries {
+        if self.delete {
+            return MigratedTimeSeries::Deleted;
+        }
+
+        let (since, until) = (self.since.map(|time| time.0), self.until.map(|time| time.0));
+        if self.set_labels.is_empty() && self.remove_labels.is_empty() && self.scale.is_none() &&
+            since.is_none() && until.is_none() {
+            return MigratedTimeSeries::Unchanged;
+        }
+
+        let mut result = if since.is_some() || until.is_some() {
+            time_series.filter(|time, _value| {
+                since.is_none_or(|since| time >= since) && until.is_none_or(|until| time < until)
+            })
+        } else {
+            time_series.clone()
+        };
+
+        for (name, value) in &self.set_labels {
+            result.set_label(name, value);
+        }
+
+        for name in &self.remove_labels {
+            result.remove_label(name);
+        }
+
+        if let Some(scale) = self.scale {
+            result.map_values(|value| value * scale);
+        }
+
+        MigratedTimeSeries::Changed(result)
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SamplesCondition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<Comparison<usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<Comparison<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<Comparison<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first: Option<Comparison<Timestamp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last: Option<Comparison<Timestamp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    all_null: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    all_zero: Option<bool>,
+}
+
+impl SamplesCondition {
+    fn is_empty(&self) -> bool {
+        self.count.is_none() && self.min.is_none() && self.max.is_none() && self.first.is_none() &&
+            self.last.is_none() && self.all_null.is_none() && self.all_zero.is_none()
+    }
+
+    fn matches(&self, time_series: &TimeSeries) -> bool {
+        if let Some(ref count) = self.count {
+            if !count.matches(time_series.len()) {
+                return false;
+            }
+        }
+
+        if self.first.is_some() || self.last.is_some() {
+            let mut times = time_series.iter().map(|(time, _value)| time);
+            let Some(first) = times.next() else {
+                return false;
+            };
+
+            let (first, last) = times.fold((first, first), |(first, last), time| {
+                (first.min(time), last.max(time))
+            });
+
+            if !matches_optional(&self.first, Timestamp(first)) || !matches_optional(&self.last, Timestamp(last)) {
+                return false;
+            }
+        }
+
+        if self.min.is_some() || self.max.is_some() {
+            let mut values = time_series.iter().filter_map(|(_time, value)| value);
+            let Some(first) = values.next() else {
+                return false;
+            };
+
+            let (min, max) = values.fold((first, first), |(min, max), value| {
+                (min.min(value), max.max(value))
+            });
+
+            if !matches_optional(&self.min, min) || !matches_optional(&self.max, max) {
+                return false;
+            }
+        }
+
+        if let Some(all_null) = self.all_null {
+            if time_series.iter().all(|(_time, value)| value.is_none()) != all_null {
+                return false;
+            }
+        }
+
+        if let Some(all_zero) = self.all_zero {
+            if time_series.iter().all(|(_time, value)| value.unwrap_or_default() == 0.0) != all_zero {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Comparison<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lt: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    le: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gt: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ge: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eq: Option<T>,
+}
+
+impl<T: PartialOrd> Comparison<T> {
+    fn matches(&self, value: T) -> bool {
+        self.lt.as_ref().is_none_or(|limit| value < *limit) &&
+        self.le.as_ref().is_none_or(|limit| value <= *limit) &&
+        self.gt.as_ref().is_none_or(|limit| value > *limit) &&
+        self.ge.as_ref().is_none_or(|limit| value >= *limit) &&
+        self.eq.as_ref().is_none_or(|expected| value == *expected)
+    }
+}
+
+fn matches_optional<T: PartialOrd>(comparison: &Option<Comparison<T>>, value: T) -> bool {
+    comparison.as_ref().is_none_or(|comparison| comparison.matches(value))
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Matcher {
+    Equal(String),
+    Operator(MatcherOperator),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum MatcherOperator {
+    NotEqual(String),
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+}
+
+impl Matcher {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Matcher::Equal(expected) => value == expected,
+            Matcher::Operator(MatcherOperator::NotEqual(expected)) => value != expected,
+            Matcher::Operator(MatcherOperator::Prefix(prefix)) => value.starts_with(prefix.as_str()),
+            Matcher::Operator(MatcherOperator::Suffix(suffix)) => value.ends_with(suffix.as_str()),
+            Matcher::Operator(MatcherOperator::Contains(substring)) => value.contains(substring.as_str()),
+        }
+    }
+
+    // Checks whether some label value may match both matchers
+    fn overlaps(&self, other: &Matcher) -> bool {
+        match (self, other) {
+            (Matcher::Equal(value), matcher) | (matcher, Matcher::Equal(value)) => matcher.matches(value),
+            (
+                Matcher::Operator(MatcherOperator::Prefix(prefix)),
+                Matcher::Operator(MatcherOperator::Prefix(other)),
+            ) => prefix.starts_with(other.as_str()) || other.starts_with(prefix.as_str()),
+            (
+                Matcher::Operator(MatcherOperator::Suffix(suffix)),
+                Matcher::Operator(MatcherOperator::Suffix(other)),
+            ) => suffix.ends_with(other.as_str()) || other.ends_with(suffix.as_str()),
+            _ => true,
+        }
+    }
+}
+
+struct RuleIndex {
+    by_name: HashMap<String, Vec<usize>>,
+    by_name_prefix: HashMap<String, Vec<usize>>,
+    name_prefix_lengths: Vec<usize>,
+    by_label: HashMap<String, HashMap<String, Vec<usize>>>,
+    unindexed: Vec<usize>,
+}
+
+impl RuleIndex {
+    fn new(rules: &[Rule]) -> RuleIndex {
+        let mut index = RuleIndex {
+            by_name: HashMap::new(),
+            by_name_prefix: HashMap::new(),
+            name_prefix_lengths: Vec::new(),
+            by_label: HashMap::new(),
+            unindexed: Vec::new(),
+        };
+
+        for (id, rule) in rules.iter().enumerate() {
+            match rule.selector.get(NAME_LABEL) {
+                Some(Matcher::Equal(name)) => {
+                    index.by_name.entry(name.clone()).or_default().push(id);
+                    continue;
+                },
+                Some(Matcher::Operator(MatcherOperator::Prefix(prefix))) if !prefix.is_empty() => {
+                    index.by_name_prefix.entry(prefix.clone()).or_default().push(id);
+                    continue;
+                },
+                _ => {},
+            }
+
+            let label = rule.selector.iter().find_map(|(name, matcher)| match matcher {
+                Matcher::Equal(value) => Some((name, value)),
+                Matcher::Operator(_) => None,
+            });
+
+            if let Some((name, value)) = label {
+                index.by_label.entry(name.clone()).or_default().entry(value.clone()).or_default().push(id);
+            } else {
+                index.unindexed.push(id);
+            }
+        }
+
+        index.name_prefix_lengths = index.by_name_prefix.keys().map(String::len).collect();
+        index.name_prefix_lengths.sort_unstable();
+        index.name_prefix_lengths.dedup();
+
+        index
+    }
+
+    // Returns IDs of the rules which may match the specified time series in the order of their definition. Every rule
+    // list is sorted by ID, so the candidates are merged without sorting.
+    fn candidates(&self, time_series: &TimeSeries) -> Vec<usize> {
+        let name = time_series.label(NAME_LABEL);
+        let mut lists: Vec<&[usize]> = Vec::new();
+
+        if !self.unindexed.is_empty() {
+            lists.push(&self.unindexed);
+        }
+
+        if let Some(rules) = self.by_name.get(name) {
+            lists.push(rules);
+        }
+
+        for &length in &self.name_prefix_lengths {
+            if length > name.len() {
+                break;
+            }
+
+            if name.is_char_boundary(length) {
+                if let Some(rules) = self.by_name_prefix.get(&name[..length]) {
+                    lists.push(rules);
+                }
+            }
+        }
+
+        for (label, values) in &self.by_label {
+            if let Some(rules) = values.get(time_series.label(label)) {
+                lists.push(rules);
+            }
+        }
+
+        if lists.len() <= 1 {
+            return lists.first().map(|rules| rules.to_vec()).unwrap_or_default();
+        }
+
+        let mut candidates = Vec::with_capacity(lists.iter().map(|rules| rules.len()).sum());
+
+        while let Some((index, &id)) = lists.iter().enumerate()
+            .filter_map(|(index, rules)| rules.first().map(|id| (index, id)))
+            .min_by_key(|&(_index, &id)| id)
+        {
+            candidates.push(id);
+            lists[index] = &lists[index][1..];
+        }
+
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_series(labels: &[(&str, &str)]) -> TimeSeries {
+        let mut time_series = TimeSeries::new(labels.iter().map(|&(name, value)| {
+            (name.to_owned(), value.to_owned())
+        }).collect());
+        time_series.add(1000, Some(1.0));
+        time_series
+    }
+
+    fn labels(result: Option<MigratedTimeSeries>) -> Option<String> {
+        match result? {
+            MigratedTimeSeries::Changed(time_series) => Some(time_series.format_metric()),
+            MigratedTimeSeries::Unchanged => Some("unchanged".to_owned()),
+            MigratedTimeSeries::Rewrite(_) => Some("rewrite".to_owned()),
+            MigratedTimeSeries::Deleted => Some("deleted".to_owned()),
+        }
+    }
+
+    #[test]
+    fn invert() {
+        let rules = RuleSet::parse(r#"
+            [[rule]]
+            match = { __name__ = "old_name", job = "node", instance = "" }
+            set_labels = { __name__ = "new_name", instance = "server" }
+            remove_labels = ["job"]
+            scale = 2.0
+
+            [[rule]]
+            match = { __name__ = "other", job = "node", instance = "" }
+            set_labels = { job = "server" }
+        "#).unwrap();
+
+        let inverted = rules.invert().unwrap();
+
+        let source = time_series(&[("__name__", "old_name"), ("job", "node"), ("instance", "server")]);
+        assert!(labels(rules.apply(&source)).is_none());
+
+        let source = time_series(&[("__name__", "old_name"), ("job", "node")]);
+        let Some(MigratedTimeSeries::Changed(migrated)) = rules.apply(&source) else {
+            panic!("The time series hasn't been migrated");
+        };
+        assert_eq!(migrated.format_metric(), r#"new_name{instance="server"}"#);
+        assert_eq!(migrated.iter().collect::<Vec<_>>(), [(1000, Some(2.0))]);
+
+        let Some(MigratedTimeSeries::Changed(restored)) = inverted.apply(&migrated) else {
+            panic!("The time series hasn't been restored");
+        };
+        assert!(restored.has_same_labels(&source));
+        assert_eq!(restored.iter().collect::<Vec<_>>(), [(1000, Some(1.0))]);
+
+        let migrated = time_series(&[("__name__", "other"), ("job", "server")]);
+        assert_eq!(labels(inverted.apply(&migrated)).unwrap(), r#"other{job="node"}"#);
+    }
+
+    #[test]
+    fn invert_not_invertible() {
+        for rules in [
+            r#"rule = [{ match = { job = "node" }, delete = true }]"#,
+            r#"rule = [{ match = { job = { prefix = "node" } }, set_labels = { job = "server" } }]"#,
+            r#"rule = [{ match = { __name__ = "metric" }, remove_labels = ["job"] }]"#,
+            r#"rule = [{ match = { __name__ = "metric" }, until = 1700000000 }]"#,
+            r#"rule = [{ match = { __name__ = "metric" }, samples = { all_zero = true }, scale = 2.0 }]"#,
+        ] {
+            assert!(RuleSet::parse(rules).unwrap().invert().is_err(), "{rules}");
+        }
+    }
+
+    #[test]
+    fn invert_many_to_one() {
+        let rules = RuleSet::parse(r#"
+            [[rule]]
+            match = { job = "a" }
+            set_labels = { job = "c" }
+
+            [[rule]]
+            match = { job = "b" }
+            set_labels = { job = "c" }
+        "#).unwrap();
+
+        let error = rules.invert().err().unwrap().to_string();
+        assert!(error.contains("rule #1: it would also roll back the time series migrated by rule #2"), "{error}");
+
+        // Rules with the same targets are distinguishable by the unchanged labels
+        RuleSet::parse(r#"
+            [[rule]]
+            match = { __name__ = "first", job = "a" }
+            set_labels = { job = "c" }
+
+            [[rule]]
+            match = { __name__ = "second", job = "b" }
+            set_labels = { job = "c" }
+        "#).unwrap().invert().unwrap();
+    }
+
+    #[test]
+    fn invert_overlapping() {
+        // The inverted rules overlap, but the earlier one doesn't change the labels, so it matches only the time
+        // series it has migrated
+        let rules = RuleSet::parse(r#"
+            [[rule]]
+            match = { __name__ = "metric" }
+            scale = 2.0
+
+            [[rule]]
+            match = { __name__ = { prefix = "metric" } }
+            scale = 4.0
+        "#).unwrap();
+
+        let inverted = rules.invert().unwrap();
+
+        let migrated = time_series(&[("__name__", "metric_total")]);
+        let Some(MigratedTimeSeries::Changed(restored)) = inverted.apply(&migrated) else {
+            panic!("The time series hasn't been restored");
+        };
+        assert_eq!(restored.iter().collect::<Vec<_>>(), [(1000, Some(0.25))]);
+
+        // The earlier inverted rule matches the time series renamed by the later one
+        let rules = RuleSet::parse(r#"
+            [[rule]]
+            match = { __name__ = "metric" }
+            scale = 2.0
+
+            [[rule]]
+            match = { __name__ = "old_metric" }
+            set_labels = { __name__ = "metric" }
+        "#).unwrap();
+
+        assert!(rules.invert().is_err());
+    }
+
+    #[test]
+    fn selectors() {
+        let rules = RuleSet::parse(r#"
+            [[rule]]
+            match = { __name__ = "metric", job = "a\t\"b\"\\c\nd\u00e9", instance = { prefix = "host.1" } }
+            delete = true
+
+            [[rule]]
+            match = { env = { not_equal = "dev\t" }, dc = { contains = "eu\"" } }
+            delete = true
+        "#).unwrap();
+
+        let selectors: Vec<_> = rules.selectors().unwrap().iter().map(|selector| {
+            selector::Selector::parse(selector).unwrap()
+        }).collect();
+
+        assert_eq!(selectors[0].matchers().collect::<Vec<_>>(), [
+            ("__name__", "=", "metric"), ("instance", "=~", r"host\.1.*"), ("job", "=", "a\t\"b\"\\c\nd\u{e9}"),
+        ]);
+        assert_eq!(selectors[1].matchers().collect::<Vec<_>>(), [
+            ("dc", "=~", ".*eu\".*"), ("env", "!=", "dev\t"),
+        ]);
+
+        let labels = [("__name__", "metric"), ("job", "a\t\"b\"\\c\nd\u{e9}"), ("instance", "host.10")];
+        assert!(selectors[0].matches(&time_series(&labels)));
+    }
+
+    #[test]
+    fn index() {
+        let rules = RuleSet::parse(r#"
+            [[rule]]
+            match = { job = "node", __name__ = { prefix = "node_" } }
+            set_labels = { rule = "1" }
+
+            [[rule]]
+            match = { __name__ = "node_load1" }
+            set_labels = { rule = "2" }
+
+            [[rule]]
+            match = { instance = { suffix = ":9100" } }
+            set_labels = { rule = "3" }
+
+            [[rule]]
+            match = { __name__ = { prefix = "node_l" } }
+            set_labels = { rule = "4" }
+
+            [[rule]]
+            match = { job = "app", instance = { not_equal = "" } }
+            set_labels = { rule = "5" }
+
+            [[rule]]
+            match = { __name__ = { prefix = "" } }
+            set_labels = { rule = "6" }
+        "#).unwrap();
+
+        let candidates = |labels: &[(&str, &str)]| rules.index.candidates(&time_series(labels));
+
+        assert_eq!(candidates(&[("__name__", "node_load1"), ("job", "node")]), [0, 1, 2, 3, 5]);
+        assert_eq!(candidates(&[("__name__", "node_load5")]), [0, 2, 3, 5]);
+        assert_eq!(candidates(&[("__name__", "up"), ("job", "app")]), [2, 4, 5]);
+        assert_eq!(candidates(&[("__name__", "n")]), [2, 5]);
+
+        // The first matching rule wins regardless of the index bucket it's found in
+        let rule = |labels: &[(&str, &str)]| match rules.apply(&time_series(labels)) {
+            Some(MigratedTimeSeries::Changed(time_series)) => time_series.label("rule").to_owned(),
+            _ => panic!("The time series hasn't been migrated"),
+        };
+
+        assert_eq!(rule(&[("__name__", "node_load1"), ("job", "node")]), "1");
+        assert_eq!(rule(&[("__name__", "node_load1"), ("job", "other")]), "2");
+        assert_eq!(rule(&[("__name__", "node_load5"), ("instance", "host:9100")]), "3");
+        assert_eq!(rule(&[("__name__", "node_load5")]), "4");
+        assert_eq!(rule(&[("__name__", "up"), ("job", "app"), ("instance", "host")]), "5");
+        assert_eq!(rule(&[("__name__", "up"), ("job", "app")]), "6");
+
+        assert!(rules.may_apply(&time_series(&[("__name__", "up")])));
+        assert!(!RuleSet::parse(r#"rule = [{ match = { job = "node" }, delete = true }]"#).unwrap()
+            .may_apply(&time_series(&[("__name__", "up"), ("job", "app")])));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,204 @@
+use std::fmt::{self, Display};
+
+use regex::Regex;
+
+use crate::core::GenericResult;
+use crate::metrics::TimeSeries;
+
+// Prometheus series selector: `name{label="value",...}` with =, !=, =~ and !~ matchers, where regular expressions are
+// fully anchored and a missing label is matched as an empty value
+#[derive(Clone)]
+pub struct Selector {
+    text: String,
+    matchers: Vec<(String, Matcher)>,
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Equal(String),
+    NotEqual(String),
+    Regex(Regex, String),
+    NotRegex(Regex, String),
+}
+
+impl Selector {
+    pub fn parse(text: &str) -> GenericResult<Selector> {
+        let invalid = || format!("Invalid series selector: {text:?}");
+
+        let text = text.trim();
+        let (name, mut rest) = text.split_at(text.find('{').unwrap_or(text.len()));
+
+        let mut matchers = Vec::new();
+        if !name.trim().is_empty() {
+            matchers.push(("__name__".to_owned(), Matcher::Equal(name.trim().to_owned())));
+        }
+
+        if let Some(data) = rest.strip_prefix('{') {
+            rest = data;
+
+            loop {
+                rest = rest.trim_start();
+
+                if let Some(data) = rest.strip_prefix('}') {
+                    rest = data;
+                    break;
+                }
+
+                let name_end = rest.find(['=', '!']).ok_or_else(invalid)?;
+                let (name, data) = rest.split_at(name_end);
+
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(invalid().into());
+                }
+
+                let (operator, data) = ["=~", "!~", "!=", "="].into_iter()
+                    .find_map(|operator| data.strip_prefix(operator).map(|data| (operator, data)))
+                    .ok_or_else(invalid)?;
+
+                let mut chars = data.trim_start().strip_prefix('"').ok_or_else(invalid)?.chars();
+                let mut value = String::new();
+
+                loop {
+                    match chars.next().ok_or_else(invalid)? {
+                        '"' => break,
+                        '\\' => value.push(match chars.next().ok_or_else(invalid)? {
+                            'n' => '\n',
+                            char => char,
+                        }),
+                        char => value.push(char),
+                    }
+                }
+
+                let regex = || Regex::new(&format!("^(?:{value})$")).map_err(|e| format!(
+                    "Invalid series selector: {text:?}: {e}"));
+
+                matchers.push((name.to_owned(), match operator {
+                    "=" => Matcher::Equal(value),
+                    "!=" => Matcher::NotEqual(value),
+                    "=~" => Matcher::Regex(regex()?, value),
+                    "!~" => Matcher::NotRegex(regex()?, value),
+                    _ => unreachable!(),
+                }));
+
+                rest = chars.as_str().trim_start();
+                if let Some(data) = rest.strip_prefix(',') {
+                    rest = data;
+                } else if !rest.starts_with('}') {
+                    return Err(invalid().into());
+                }
+            }
+        }
+
+        if !rest.trim().is_empty() || matchers.is_empty() {
+            return Err(invalid().into());
+        }
+
+        Ok(Selector {text: text.to_owned(), matchers})
+    }
+
+    pub fn matches(&self, time_series: &TimeSeries) -> bool {
+        self.matchers.iter().all(|(name, matcher)| {
+            let value = time_series.label(name);
+
+            match matcher {
+                Matcher::Equal(expected) => value == expected,
+                Matcher::NotEqual(expected) => value != expected,
+                Matcher::Regex(regex, _) => regex.is_match(value),
+                Matcher::NotRegex(regex, _) => !regex.is_match(value),
+            }
+        })
+    }
+
+    // Returns the label matchers as label name, operator (`=`, `!=`, `=~` or `!~`) and value
+    pub fn matchers(&self) -> impl Iterator<Item = (&str, &'static str, &str)> {
+        self.matchers.iter().map(|(name, matcher)| match matcher {
+            Matcher::Equal(value) => (name.as_str(), "=", value.as_str()),
+            Matcher::NotEqual(value) => (name.as_str(), "!=", value.as_str()),
+            Matcher::Regex(_, value) => (name.as_str(), "=~", value.as_str()),
+            Matcher::NotRegex(_, value) => (name.as_str(), "!~", value.as_str()),
+        })
+    }
+}
+
+// Quotes the value as a PromQL string literal, so it's parsed back as is
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for char in value.chars() {
+        match char {
+            '\\' => quoted.push_str(r"\\"),
+            '"' => quoted.push_str(r#"\""#),
+            '\n' => quoted.push_str(r"\n"),
+            _ => quoted.push(char),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_series(labels: &[(&str, &str)]) -> TimeSeries {
+        TimeSeries::new(labels.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect())
+    }
+
+    #[test]
+    fn parse() {
+        let text = r#"up{job="node", instance!~"proxy.*" , env!="dev",path=~"a\\\"b\n",}"#;
+        let selector = Selector::parse(&format!("  {text} ")).unwrap();
+        assert_eq!(selector.to_string(), text);
+        assert_eq!(selector.matchers().collect::<Vec<_>>(), [
+            ("__name__", "=", "up"), ("job", "=", "node"), ("instance", "!~", "proxy.*"), ("env", "!=", "dev"),
+            ("path", "=~", "a\\\"b\n"),
+        ]);
+
+        let selector = Selector::parse(r#"{__name__=~"up|down"}"#).unwrap();
+        assert_eq!(selector.matchers().collect::<Vec<_>>(), [("__name__", "=~", "up|down")]);
+
+        for text in ["", "{}", r#"up{job}"#, r#"up{job="node"#, r#"up{job=node}"#, r#"up{="node"}"#,
+                     r#"up{job="node" env="dev"}"#, r#"up{job=~"("}"#, r#"up{job="node"} extra"#] {
+            assert!(Selector::parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let selector = Selector::parse(r#"up{job=~"node|app", instance!~"proxy.*", env!="dev", dc=""}"#).unwrap();
+
+        assert!(selector.matches(&time_series(&[("__name__", "up"), ("job", "node"), ("instance", "server")])));
+        assert!(selector.matches(&time_series(&[("__name__", "up"), ("job", "app"), ("env", "prod")])));
+
+        for labels in [
+            &[("__name__", "down"), ("job", "node")][..],
+            &[("__name__", "up"), ("job", "node1")],
+            &[("__name__", "up"), ("job", "node"), ("instance", "proxy1")],
+            &[("__name__", "up"), ("job", "node"), ("env", "dev")],
+            &[("__name__", "up"), ("job", "node"), ("dc", "eu")],
+            &[("__name__", "up")],
+        ] {
+            assert!(!selector.matches(&time_series(labels)), "{labels:?}");
+        }
+
+        // A missing label is matched as an empty value
+        assert!(Selector::parse(r#"{job!~".+"}"#).unwrap().matches(&time_series(&[("__name__", "up")])));
+    }
+
+    #[test]
+    fn quote() {
+        for value in ["node", "a\\b\"c\nd", "tab\tand \u{1F600}"] {
+            let selector = Selector::parse(&format!("{{job={}}}", super::quote(value))).unwrap();
+            assert_eq!(selector.matchers().collect::<Vec<_>>(), [("job", "=", value)]);
+        }
+    }
+}
\ No newline at end of file
@@ -1,6 +1,8 @@
 use std::collections::{HashMap, HashSet};
 use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 
+use serde_derive::{Deserialize, Serialize};
 use tabled::{Table, Tabled};
 use tabled::settings::{Alignment, Height, object::{Rows, Columns}, style::Style};
 
@@ -8,19 +10,117 @@ use crate::metrics::{MigratedTimeSeries, TimeSeries};
 
 pub struct Stat {
     total: u64,
-    changes: HashSet<(String, Option<String>)>,
+    changes: Arc<Mutex<Changes>>,
     metrics: HashMap<String, u64>,
+    migrations: Vec<MigrationStat>,
+    retries: u64,
+    imported: ImportStat,
+    stderr: bool,
+}
+
+#[derive(Default)]
+struct ImportStat {
+    batches: u64,
+    lines: u64,
+    bytes: u64,
+}
+
+// Source and result (if not deleted) metrics of the reported changes
+type Changes = HashSet<(String, Option<String>)>;
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct MigrationStat {
+    pub changed: u64,
+    pub deleted: u64,
+    // Time series which the rollback rules wouldn't restore to their original state
+    #[serde(default)]
+    pub collisions: u64,
 }
 
 impl Stat {
-    pub fn new() -> Stat {
+    // Reports are printed to stderr if stdout is used for data
+    pub fn new(stderr: bool) -> Stat {
         Stat {
             total: 0,
-            changes: HashSet::new(),
+            changes: Arc::new(Mutex::new(HashSet::new())),
             metrics: HashMap::new(),
+            migrations: Vec::new(),
+            retries: 0,
+            imported: ImportStat::default(),
+            stderr,
+        }
+    }
+
+    // Creates an empty statistics for a task which may fail and be retried. It shares the reported changes with the
+    // parent statistics and is merged into it on success.
+    pub fn fork(&self) -> Stat {
+        Stat {
+            changes: self.changes.clone(),
+            ..Stat::new(self.stderr)
         }
     }
 
+    pub fn merge(&mut self, other: Stat) {
+        self.total += other.total;
+
+        for (namespace, count) in other.metrics {
+            *self.metrics.entry(namespace).or_default() += count;
+        }
+
+        self.add_migrations(&other.migrations);
+
+        self.retries += other.retries;
+        self.imported.batches += other.imported.batches;
+        self.imported.lines += other.imported.lines;
+        self.imported.bytes += other.imported.bytes;
+    }
+
+    pub fn migration(&mut self, index: usize) -> &mut MigrationStat {
+        if self.migrations.len() <= index {
+            self.migrations.resize(index + 1, MigrationStat::default());
+        }
+        &mut self.migrations[index]
+    }
+
+    pub fn migrations(&self) -> &[MigrationStat] {
+        &self.migrations
+    }
+
+    // Adds the migration statistics of other runs or tasks (like the ones completed before resume)
+    pub fn add_migrations(&mut self, migrations: &[MigrationStat]) {
+        for (index, stat) in migrations.iter().enumerate() {
+            let total = self.migration(index);
+            total.changed += stat.changed;
+            total.deleted += stat.deleted;
+            total.collisions += stat.collisions;
+        }
+    }
+
+    pub fn on_retry(&mut self) {
+        self.retries += 1;
+    }
+
+    // Counts samples of an unchanged time series which haven't been decoded
+    pub fn add_unchanged(&mut self, name: &str, samples: usize) {
+        let namespace = get_metric_namespace(name);
+        let count = samples.try_into().unwrap();
+
+        if let Some(total) = self.metrics.get_mut(namespace) {
+            *total += count;
+        } else {
+            self.metrics.insert(namespace.to_owned(), count);
+        }
+
+        self.total += count;
+    }
+
+    // Counts an import batch acknowledged by the target
+    pub fn on_imported(&mut self, lines: usize, bytes: usize) {
+        self.imported.batches += 1;
+        self.imported.lines += lines as u64;
+        self.imported.bytes += bytes as u64;
+    }
+
     pub fn add(&mut self, source: &TimeSeries, result: &MigratedTimeSeries) {
         match result {
             MigratedTimeSeries::Unchanged => {
@@ -52,7 +152,7 @@ impl Stat {
         }
     }
 
-    pub fn print(self) {
+    pub fn print(&self) {
         let mut rows = Vec::new();
         let mut add = |name, count| {
             rows.push(StatRow {
@@ -62,7 +162,7 @@ impl Stat {
         };
 
         let mut other = 0;
-        let mut metrics: Vec<_> = self.metrics.into_iter().collect();
+        let mut metrics: Vec<_> = self.metrics.iter().map(|(name, &count)| (name.clone(), count)).collect();
         metrics.sort_by(|(_, a), (_, b)| b.cmp(a));
 
         for (name, count) in metrics {
@@ -82,7 +182,25 @@ impl Stat {
         table.modify(Rows::first(), Height::increase(2));
         table.modify(Columns::single(1), Alignment::right());
 
-        let _ = writeln!(io::stdout(), "\n{}", table);
+        let _ = writeln!(self.output(), "\n{}", table);
+
+        if self.imported.batches != 0 {
+            let _ = writeln!(
+                self.output(), "Imported: {} time series in {} batches ({:.1} MB)",
+                self.imported.lines, self.imported.batches, self.imported.bytes as f64 / 1024.0 / 1024.0);
+        }
+
+        if self.retries != 0 {
+            let _ = writeln!(self.output(), "Retried requests: {}", self.retries);
+        }
+    }
+
+    fn output(&self) -> Box<dyn Write> {
+        if self.stderr {
+            Box::new(io::stderr())
+        } else {
+            Box::new(io::stdout())
+        }
     }
 
     fn on_changed(&mut self, source: &TimeSeries, result: &TimeSeries) {
@@ -90,12 +208,12 @@ impl Stat {
             return self.on_deleted(source);
         }
 
-        if self.changes.insert((source.format_metric(), Some(result.format_metric()))) {
+        if self.changes.lock().unwrap().insert((source.format_metric(), Some(result.format_metric()))) {
             let (source, result) = (source.format_metric(), result.format_metric());
             if source == result {
-                let _ = writeln!(io::stdout(), "Change: {source}");
+                let _ = writeln!(self.output(), "Change: {source}");
             } else {
-                let _ = writeln!(io::stdout(), "Change: {source} -> {result}");
+                let _ = writeln!(self.output(), "Change: {source} -> {result}");
             }
         }
 
@@ -103,22 +221,13 @@ impl Stat {
     }
 
     fn on_deleted(&mut self, source: &TimeSeries) {
-        if self.changes.insert((source.format_metric(), None)) {
-            let _ = writeln!(io::stdout(), "Delete: {}", source.format_metric());
+        if self.changes.lock().unwrap().insert((source.format_metric(), None)) {
+            let _ = writeln!(self.output(), "Delete: {}", source.format_metric());
         }
     }
 
     fn count(&mut self, time_series: &TimeSeries) {
-        let namespace = get_metric_namespace(time_series.name());
-        let count = time_series.len().try_into().unwrap();
-
-        if let Some(total) = self.metrics.get_mut(namespace) {
-            *total += count;
-        } else {
-            self.metrics.insert(namespace.to_owned(), count);
-        }
-
-        self.total += count;
+        self.add_unchanged(time_series.name(), time_series.len());
     }
 }
 
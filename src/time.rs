@@ -0,0 +1,178 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+use serde_derive::Deserialize;
+
+use crate::core::GenericResult;
+
+// Parses time in one of the following formats into milliseconds since epoch:
+// * Unix timestamp in seconds (with optional fractional part)
+// * RFC 3339 date and time
+// * YYYY-MM-DD[THH:MM[:SS]] in local time zone
+// * Duration relative to the current time with explicit minus sign (like -1h30m)
+pub fn parse_time(value: &str) -> GenericResult<i64> {
+    if let Some(duration) = value.strip_prefix('-').and_then(|duration| parse_duration(duration).ok()) {
+        return Ok(Utc::now().timestamp_millis() - duration);
+    }
+
+    parse_absolute_time(value)
+}
+
+// Parses time like parse_time() does, but doesn't accept relative time, which makes the result depend on the moment of
+// parsing
+pub fn parse_absolute_time(value: &str) -> GenericResult<i64> {
+    if let Ok(timestamp) = value.parse::<f64>() {
+        if timestamp.is_finite() {
+            return Ok((timestamp * 1000.0).round() as i64);
+        }
+    }
+
+    if value.strip_prefix('-').is_some_and(|duration| parse_duration(duration).is_ok()) {
+        return Err!("Relative time is not allowed here: {value:?}");
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_millis());
+    }
+
+    let time = if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        date.and_hms_opt(0, 0, 0)
+    } else {
+        ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"].iter().find_map(|format| {
+            NaiveDateTime::parse_from_str(value, format).ok()
+        })
+    };
+
+    let Some(time) = time.and_then(|time| Local.from_local_datetime(&time).earliest()) else {
+        return Err!("Invalid time: {value:?}");
+    };
+
+    Ok(time.timestamp_millis())
+}
+
+// Parses duration like 1d12h into milliseconds
+pub fn parse_duration(value: &str) -> GenericResult<i64> {
+    let mut duration: i64 = 0;
+    let mut number = String::new();
+
+    for char in value.chars() {
+        if char.is_ascii_digit() {
+            number.push(char);
+            continue;
+        }
+
+        let unit = match char {
+            's' => 1000,
+            'm' => 60 * 1000,
+            'h' => 60 * 60 * 1000,
+            'd' => 24 * 60 * 60 * 1000,
+            'w' => 7 * 24 * 60 * 60 * 1000,
+            _ => return Err!("Invalid duration: {value:?}"),
+        };
+
+        let Some(count) = number.parse::<i64>().ok().and_then(|count| count.checked_mul(unit)) else {
+            return Err!("Invalid duration: {value:?}");
+        };
+
+        duration = duration.checked_add(count).ok_or_else(|| format!("Invalid duration: {value:?}"))?;
+        number.clear();
+    }
+
+    if !number.is_empty() || duration == 0 {
+        return Err!("Invalid duration: {value:?}");
+    }
+
+    Ok(duration)
+}
+
+pub fn format_time(time: i64) -> String {
+    match Local.timestamp_millis_opt(time).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => time.to_string(),
+    }
+}
+
+// Formats time as Unix timestamp in seconds as VictoriaMetrics API expects it
+pub fn format_timestamp(time: i64) -> String {
+    format!("{:.3}", time as f64 / 1000.0)
+}
+
+// Time specified in one of the formats supported by parse_absolute_time() or as Unix timestamp number
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub struct Timestamp(pub i64);
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Integer(i64),
+            Float(f64),
+            String(String),
+        }
+
+        let invalid = |timestamp: &dyn std::fmt::Display| D::Error::custom(format!("Invalid timestamp: {timestamp}"));
+
+        Ok(Timestamp(match Value::deserialize(deserializer)? {
+            Value::Integer(timestamp) => timestamp.checked_mul(1000).ok_or_else(|| invalid(&timestamp))?,
+            Value::Float(timestamp) => {
+                let time = (timestamp * 1000.0).round();
+                if !(i64::MIN as f64..i64::MAX as f64).contains(&time) {
+                    return Err(invalid(&timestamp));
+                }
+                time as i64
+            },
+            Value::String(value) => parse_absolute_time(&value).map_err(D::Error::custom)?,
+        }))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match Local.timestamp_millis_opt(self.0).single() {
+            Some(time) => serializer.serialize_str(&time.to_rfc3339()),
+            None => serializer.serialize_f64(self.0 as f64 / 1000.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_time() {
+        let now = Utc::now().timestamp_millis();
+
+        let time = parse_time("-1h30m").unwrap();
+        assert!((now - 90 * 60 * 1000 - time).abs() < 60 * 1000);
+
+        assert!(parse_time("1h30m").is_err());
+        assert!(parse_absolute_time("-1h30m").is_err());
+    }
+
+    #[test]
+    fn absolute_time() {
+        assert_eq!(parse_time("1700000000").unwrap(), 1_700_000_000_000);
+        assert_eq!(parse_time("1700000000.5").unwrap(), 1_700_000_000_500);
+        assert_eq!(parse_time("-1").unwrap(), -1000);
+        assert_eq!(parse_time("2023-11-14T22:13:20Z").unwrap(), 1_700_000_000_000);
+        assert_eq!(parse_absolute_time("2023-11-14T22:13:20+00:00").unwrap(), 1_700_000_000_000);
+        assert!(parse_time("2023-11-14").is_ok());
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn timestamp() {
+        let parse = |value| serde_json::from_str::<Timestamp>(value).map(|timestamp| timestamp.0);
+        assert_eq!(parse("1700000000").unwrap(), 1_700_000_000_000);
+        assert_eq!(parse("1700000000.5").unwrap(), 1_700_000_000_500);
+        assert_eq!(parse(r#""2023-11-14T22:13:20Z""#).unwrap(), 1_700_000_000_000);
+        assert!(parse(r#""-1d""#).is_err());
+
+        // Overflows are rejected instead of wrapping or saturating
+        assert!(parse("9223372036854776").is_err());
+        assert!(parse("-9223372036854776").is_err());
+        assert!(parse("1e300").is_err());
+        assert_eq!(parse("9223372036854775").unwrap(), 9_223_372_036_854_775_000);
+    }
+}
\ No newline at end of file
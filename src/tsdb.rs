@@ -0,0 +1,1041 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use async_stream::try_stream;
+use chrono::Utc;
+use futures_core::stream::Stream;
+use log::warn;
+use serde_derive::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::core::{EmptyResult, GenericResult};
+use crate::metrics::TimeSeries;
+use crate::varint::{self, write_uvarint, write_varint};
+use crate::xor;
+
+// Prometheus TSDB data directory (or snapshot) reader and writer. Reads the persisted blocks: index v2, chunk segment
+// files and tombstones. Data of the head block which hasn't been persisted yet (WAL) isn't read.
+
+const INDEX_MAGIC: u32 = 0xBAAAD700;
+const INDEX_VERSION: u8 = 2;
+const INDEX_TOC_SIZE: usize = 6 * 8 + 4;
+const SERIES_ALIGNMENT: usize = 16;
+
+const CHUNKS_MAGIC: u32 = 0x85BD40DD;
+const CHUNKS_VERSION: u8 = 1;
+const CHUNKS_HEADER_SIZE: u64 = 8;
+const CHUNKS_SEGMENT_SIZE: u64 = 512 * 1024 * 1024;
+const CHUNK_XOR: u8 = 1;
+const CHUNK_SAMPLES: usize = 120;
+
+// Prometheus head block range: the written blocks are aligned to it
+const BLOCK_DURATION: i64 = 2 * 60 * 60 * 1000;
+
+// Size of the encoded samples the writer buffers in memory before spilling them to disk
+const SPILL_SIZE: usize = 256 * 1024 * 1024;
+
+const TOMBSTONES_MAGIC: u32 = 0x0130BA30;
+const TOMBSTONES_VERSION: u8 = 1;
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Deserialize)]
+struct Meta {
+    #[serde(rename = "minTime")]
+    min_time: i64,
+    #[serde(rename = "maxTime")]
+    max_time: i64,
+}
+
+// Reads the time series within [start, end) time range block by block, so a time series is returned once per each
+// block it has samples in. The files are read in a blocking thread.
+pub fn read_time_series(
+    path: PathBuf, start_time: Option<i64>, end_time: Option<i64>,
+) -> impl Stream<Item = GenericResult<TimeSeries>> {
+    let (sender, mut receiver) = mpsc::channel(100);
+
+    tokio::task::spawn_blocking(move || {
+        let time_range = (start_time.unwrap_or(i64::MIN), end_time.unwrap_or(i64::MAX));
+
+        // Stop reading when the receiver is closed
+        let result = read_blocks(&path, time_range, |time_series| sender.blocking_send(Ok(time_series)).is_ok());
+
+        if let Err(err) = result {
+            let _ = sender.blocking_send(Err(err));
+        }
+    });
+
+    try_stream! {
+        while let Some(result) = receiver.recv().await {
+            yield result?;
+        }
+    }
+}
+
+fn read_blocks<C>(path: &Path, time_range: (i64, i64), mut callback: C) -> EmptyResult
+    where C: FnMut(TimeSeries) -> bool
+{
+    let mut blocks = Vec::new();
+
+    if path.join("meta.json").exists() {
+        blocks.push((path.to_owned(), read_meta(path)?));
+    } else {
+        let entries = fs::read_dir(path).map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+
+        for entry in entries {
+            let path = entry.map_err(|e| format!("Unable to read {path:?}: {e}"))?.path();
+
+            // Skip temporary block directories which are being created or deleted
+            let is_block = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| !name.contains('.'));
+
+            if is_block && path.join("meta.json").exists() {
+                let meta = read_meta(&path)?;
+                blocks.push((path, meta));
+            }
+        }
+
+        if path.join("wal").exists() {
+            warn!("The data of Prometheus head block (WAL) is not read. Use a snapshot to include it.");
+        }
+    }
+
+    if blocks.is_empty() {
+        return Err!("There are no Prometheus TSDB blocks in {path:?}");
+    }
+
+    blocks.sort_by_key(|(_, meta)| meta.min_time);
+
+    for (path, meta) in blocks {
+        // Block max time is exclusive
+        if meta.max_time <= time_range.0 || meta.min_time >= time_range.1 {
+            continue;
+        }
+
+        let completed = read_block(&path, time_range, &mut callback).map_err(|e| format!(
+            "Failed to read Prometheus TSDB block {path:?}: {e}"))?;
+
+        if !completed {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_meta(path: &Path) -> GenericResult<Meta> {
+    let path = path.join("meta.json");
+    let data = fs::read(&path).map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+    Ok(serde_json::from_slice(&data).map_err(|e| format!("Error while reading {path:?}: {e}"))?)
+}
+
+// Returns false if reading has been stopped by the callback
+fn read_block<C>(path: &Path, time_range: (i64, i64), callback: &mut C) -> GenericResult<bool>
+    where C: FnMut(TimeSeries) -> bool
+{
+    let index_path = path.join("index");
+    let index = fs::read(&index_path).map_err(|e| format!("Unable to read {index_path:?}: {e}"))?;
+    let index = Index::parse(&index)?;
+
+    let tombstones = read_tombstones(&path.join("tombstones"))?;
+    let mut chunks = ChunkReader::new(path.join("chunks"));
+    let mut skipped_chunks = 0;
+
+    for series in index.series() {
+        let series = series?;
+        let deleted = tombstones.get(&series.reference).map(Vec::as_slice).unwrap_or_default();
+
+        let mut time_series = TimeSeries::new(series.labels);
+        if time_series.label("__name__").is_empty() {
+            return Err!("Got a time series without metric name");
+        }
+
+        for chunk in series.chunks {
+            // Chunk max time is inclusive
+            if chunk.max_time < time_range.0 || chunk.min_time >= time_range.1 {
+                continue;
+            }
+
+            let (encoding, data) = chunks.read(chunk.reference)?;
+            if encoding != CHUNK_XOR {
+                skipped_chunks += 1;
+                continue;
+            }
+
+            for (time, value) in xor::decode(&data)? {
+                if time < time_range.0 || time >= time_range.1 || deleted.iter().any(|&(start, end)| {
+                    start <= time && time <= end
+                }) {
+                    continue;
+                }
+
+                time_series.add(time, if value.is_nan() { None } else { Some(value) });
+            }
+        }
+
+        if !time_series.is_empty() && !callback(time_series) {
+            return Ok(false);
+        }
+    }
+
+    if skipped_chunks != 0 {
+        warn!("{skipped_chunks} native histogram chunks have been skipped in {path:?}: they are not supported.");
+    }
+
+    Ok(true)
+}
+
+struct Series {
+    reference: u64,
+    labels: HashMap<String, String>,
+    chunks: Vec<ChunkMeta>,
+}
+
+struct ChunkMeta {
+    min_time: i64,
+    max_time: i64,
+    reference: u64,
+}
+
+struct Index<'a> {
+    data: &'a [u8],
+    symbols: Vec<&'a str>,
+    series: (usize, usize),
+}
+
+impl<'a> Index<'a> {
+    fn parse(data: &'a [u8]) -> GenericResult<Index<'a>> {
+        let mut reader = Reader::new(data);
+        if reader.read_u32() != Some(INDEX_MAGIC) {
+            return Err!("Invalid index file");
+        }
+
+        match reader.read_u8() {
+            Some(INDEX_VERSION) => {},
+            Some(version) => return Err!("Unsupported index version: {version}"),
+            None => return Err!("Invalid index file"),
+        }
+
+        let toc = data.len().checked_sub(INDEX_TOC_SIZE).map(|offset| &data[offset..]).ok_or("Invalid index file")?;
+        let (toc, checksum) = toc.split_at(INDEX_TOC_SIZE - 4);
+        if crc32c::crc32c(toc) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err!("Index table of contents checksum mismatch");
+        }
+
+        let mut offsets = Vec::new();
+        let mut reader = Reader::new(toc);
+        while let Some(offset) = reader.read_u64() {
+            offsets.push(usize::try_from(offset).map_err(|_| "Invalid index table of contents")?);
+        }
+
+        let (symbols_offset, series_offset) = (offsets[0], offsets[1]);
+
+        // The series section is followed by the next non-empty section
+        let series_end = offsets.iter().cloned()
+            .filter(|&offset| offset > series_offset)
+            .min()
+            .unwrap_or(data.len() - INDEX_TOC_SIZE);
+
+        if series_end > data.len() {
+            return Err!("Invalid index table of contents");
+        }
+
+        let mut symbols = Vec::new();
+        let mut reader = Reader::new(data.get(symbols_offset..).ok_or("Invalid symbol table offset")?);
+        let symbols_data = reader.read_section().ok_or("Invalid symbol table")?;
+
+        let mut reader = Reader::new(symbols_data);
+        let count = reader.read_u32().ok_or("Invalid symbol table")?;
+
+        for _ in 0..count {
+            let symbol = reader.read_length_prefixed().ok_or("Invalid symbol table")?;
+            symbols.push(std::str::from_utf8(symbol).map_err(|_| "Invalid symbol table: invalid UTF-8 symbol")?);
+        }
+
+        Ok(Index {data, symbols, series: (series_offset, series_end)})
+    }
+
+    fn series(&self) -> impl Iterator<Item = GenericResult<Series>> + use<'_, 'a> {
+        let (mut offset, end) = self.series;
+
+        std::iter::from_fn(move || {
+            offset = offset.next_multiple_of(SERIES_ALIGNMENT);
+            if offset >= end {
+                return None;
+            }
+
+            let reference = (offset / SERIES_ALIGNMENT) as u64;
+
+            let mut reader = Reader::new(&self.data[offset..end]);
+            let result = reader.read_section_uvarint().ok_or_else(|| "Invalid series entry".into())
+                .and_then(|data| self.parse_series(reference, data));
+
+            match result {
+                Ok(series) => {
+                    offset = end - reader.data.len();
+                    Some(Ok(series))
+                },
+                Err(err) => {
+                    offset = end;
+                    Some(Err(err))
+                },
+            }
+        })
+    }
+
+    fn parse_series(&self, reference: u64, data: &[u8]) -> GenericResult<Series> {
+        let invalid = || "Invalid series entry";
+        let mut reader = Reader::new(data);
+
+        let symbol = |reader: &mut Reader| -> GenericResult<&str> {
+            let reference = reader.read_uvarint().ok_or_else(invalid)?;
+            Ok(self.symbols.get(usize::try_from(reference)?).ok_or("Invalid symbol reference")?)
+        };
+
+        let mut labels = HashMap::new();
+        for _ in 0..reader.read_uvarint().ok_or_else(invalid)? {
+            let (name, value) = (symbol(&mut reader)?, symbol(&mut reader)?);
+            labels.insert(name.to_owned(), value.to_owned());
+        }
+
+        let count = reader.read_uvarint().ok_or_else(invalid)?;
+        let mut chunks = Vec::with_capacity(count.try_into()?);
+        let (mut max_time, mut chunk_reference) = (0_i64, 0_u64);
+
+        for index in 0..count {
+            let min_time = if index == 0 {
+                reader.read_varint().ok_or_else(invalid)?
+            } else {
+                max_time.wrapping_add(reader.read_uvarint().ok_or_else(invalid)? as i64)
+            };
+
+            max_time = min_time.wrapping_add(reader.read_uvarint().ok_or_else(invalid)? as i64);
+
+            chunk_reference = if index == 0 {
+                reader.read_uvarint().ok_or_else(invalid)?
+            } else {
+                chunk_reference.wrapping_add_signed(reader.read_varint().ok_or_else(invalid)?)
+            };
+
+            chunks.push(ChunkMeta {min_time, max_time, reference: chunk_reference});
+        }
+
+        Ok(Series {reference, labels, chunks})
+    }
+}
+
+struct ChunkReader {
+    path: PathBuf,
+    files: HashMap<u64, File>,
+}
+
+impl ChunkReader {
+    fn new(path: PathBuf) -> ChunkReader {
+        ChunkReader {path, files: HashMap::new()}
+    }
+
+    // Chunk reference is segment file index in the upper 4 bytes and offset in the lower ones. A chunk consists of
+    // uvarint data length, encoding byte, data and CRC32 of encoding and data.
+    fn read(&mut self, reference: u64) -> GenericResult<(u8, Vec<u8>)> {
+        let (segment, offset) = (reference >> 32, reference & 0xFFFF_FFFF);
+
+        let path = self.path.join(format!("{:06}", segment + 1));
+        let file = match self.files.get(&segment) {
+            Some(file) => file,
+            None => {
+                let file = File::open(&path).map_err(|e| format!("Unable to open {path:?}: {e}"))?;
+
+                let mut header = [0; CHUNKS_HEADER_SIZE as usize];
+                file.read_exact_at(&mut header, 0).map_err(|e| format!("Unable to read {path:?}: {e}"))?;
+                if Reader::new(&header).read_u32() != Some(CHUNKS_MAGIC) {
+                    return Err!("Invalid chunks file: {path:?}");
+                }
+
+                self.files.entry(segment).or_insert(file)
+            },
+        };
+
+        let invalid = || format!("Invalid chunk reference: {reference}");
+        let read_error = |e| format!("Unable to read {path:?}: {e}");
+
+        let mut header = [0; 11];
+        let size = file.read_at(&mut header, offset).map_err(read_error)?;
+
+        let mut reader = Reader::new(&header[..size]);
+        let length = reader.read_uvarint().ok_or_else(invalid)?;
+        let encoding = reader.read_u8().ok_or_else(invalid)?;
+        let data_offset = offset + (size - reader.data.len()) as u64;
+
+        let mut data = vec![0; usize::try_from(length)? + 4];
+        file.read_exact_at(&mut data, data_offset).map_err(read_error)?;
+
+        let checksum = u32::from_be_bytes(data.split_off(data.len() - 4).try_into().unwrap());
+
+        let mut hasher = crc32c::crc32c_append(0, &[encoding]);
+        hasher = crc32c::crc32c_append(hasher, &data);
+        if hasher != checksum {
+            return Err!("Chunk {reference} checksum mismatch");
+        }
+
+        Ok((encoding, data))
+    }
+}
+
+// Returns deleted time ranges (inclusive) by series reference
+fn read_tombstones(path: &Path) -> GenericResult<HashMap<u64, Vec<(i64, i64)>>> {
+    let mut tombstones: HashMap<_, Vec<_>> = HashMap::new();
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(tombstones),
+        Err(e) => return Err!("Unable to read {path:?}: {e}"),
+    };
+
+    let invalid = || format!("Invalid tombstones file: {path:?}");
+
+    let mut reader = Reader::new(&data);
+    if reader.read_u32() != Some(TOMBSTONES_MAGIC) || reader.read_u8() != Some(TOMBSTONES_VERSION) ||
+        reader.data.len() < 4 {
+        return Err(invalid().into());
+    }
+
+    let (entries, checksum) = reader.data.split_at(reader.data.len() - 4);
+    if crc32c::crc32c(entries) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+        return Err(invalid().into());
+    }
+
+    let mut reader = Reader::new(entries);
+    while !reader.data.is_empty() {
+        let reference = reader.read_uvarint().ok_or_else(invalid)?;
+        let start = reader.read_varint().ok_or_else(invalid)?;
+        let end = reader.read_varint().ok_or_else(invalid)?;
+        tombstones.entry(reference).or_default().push((start, end));
+    }
+
+    Ok(tombstones)
+}
+
+// Writes the time series into new blocks aligned to Prometheus block range, like promtool does. The samples are
+// accumulated in memory per block and spilled to temporary per-block files when there are too many of them, so on
+// finish the blocks are written one by one and only one block is held in memory.
+pub struct Writer {
+    path: PathBuf,
+    spill_path: Option<PathBuf>,
+    blocks: BTreeMap<i64, Vec<u8>>,
+    spilled: BTreeSet<i64>,
+    buffered: usize,
+}
+
+impl Writer {
+    pub fn new(path: PathBuf) -> Writer {
+        Writer {path, spill_path: None, blocks: BTreeMap::new(), spilled: BTreeSet::new(), buffered: 0}
+    }
+
+    // Null values are written as NaN and empty labels are omitted as Prometheus treats them as missing
+    pub fn add(&mut self, time_series: &TimeSeries) -> EmptyResult {
+        let mut labels: Labels = time_series.labels().into_iter()
+            .chain([("__name__", time_series.name())])
+            .filter(|(_name, value)| !value.is_empty())
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+
+        labels.sort();
+
+        let mut blocks: BTreeMap<i64, Vec<(i64, f64)>> = BTreeMap::new();
+        for (time, value) in time_series.iter() {
+            blocks.entry(time.div_euclid(BLOCK_DURATION)).or_default().push((time, value.unwrap_or(f64::NAN)));
+        }
+
+        for (block, samples) in blocks {
+            let buf = self.blocks.entry(block).or_default();
+            let size = buf.len();
+            encode_spilled_series(buf, &labels, &samples);
+            self.buffered += buf.len() - size;
+        }
+
+        if self.buffered >= SPILL_SIZE {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    // Returns the number of written blocks
+    pub fn finish(&mut self) -> GenericResult<usize> {
+        fs::create_dir_all(&self.path).map_err(|e| format!("Unable to create {:?}: {e}", self.path))?;
+
+        let mut blocks: BTreeSet<i64> = self.blocks.keys().cloned().collect();
+        blocks.extend(&self.spilled);
+
+        for &block in &blocks {
+            let mut data = match self.spill_path {
+                Some(ref spill_path) if self.spilled.contains(&block) => {
+                    let path = spill_path.join(block.to_string());
+                    fs::read(&path).map_err(|e| format!("Unable to read {path:?}: {e}"))?
+                },
+                _ => Vec::new(),
+            };
+            data.extend(self.blocks.remove(&block).unwrap_or_default());
+
+            let mut series: Vec<_> = decode_spilled_series(&data)?.into_iter().collect();
+            drop(data);
+            series.sort_by(|a, b| a.0.cmp(&b.0));
+
+            for (_, samples) in &mut series {
+                // Samples with the same timestamp are deduplicated keeping the last one
+                samples.reverse();
+                samples.sort_by_key(|&(time, _value)| time);
+                samples.dedup_by_key(|&mut (time, _value)| time);
+            }
+
+            write_block(&self.path, &series)?;
+        }
+
+        if let Some(spill_path) = self.spill_path.take() {
+            fs::remove_dir_all(&spill_path).map_err(|e| format!("Unable to delete {spill_path:?}: {e}"))?;
+        }
+
+        Ok(blocks.len())
+    }
+
+    // Appends the buffered samples to the block files. The temporary directory is named like the ones Prometheus
+    // creates blocks in, so it's deleted by Prometheus on start if it's left after a failure.
+    fn spill(&mut self) -> EmptyResult {
+        let spill_path = match self.spill_path {
+            Some(ref path) => path.clone(),
+            None => {
+                let path = self.path.join(format!("{}.tmp-for-creation", new_ulid()));
+                fs::create_dir_all(&path).map_err(|e| format!("Unable to create {path:?}: {e}"))?;
+                self.spill_path.insert(path).clone()
+            },
+        };
+
+        for (block, data) in std::mem::take(&mut self.blocks) {
+            let path = spill_path.join(block.to_string());
+
+            File::options().create(true).append(true).open(&path).and_then(|mut file| file.write_all(&data))
+                .map_err(|e| format!("Unable to write {path:?}: {e}"))?;
+
+            self.spilled.insert(block);
+        }
+
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+// Spilled time series: labels and samples (varint timestamp and little-endian float bits) prefixed by their counts
+fn encode_spilled_series(buf: &mut Vec<u8>, labels: &Labels, samples: &[(i64, f64)]) {
+    write_uvarint(buf, labels.len() as u64);
+    for (name, value) in labels {
+        for string in [name, value] {
+            write_uvarint(buf, string.len() as u64);
+            buf.extend(string.as_bytes());
+        }
+    }
+
+    write_uvarint(buf, samples.len() as u64);
+    for &(time, value) in samples {
+        write_varint(buf, time);
+        buf.extend(value.to_bits().to_le_bytes());
+    }
+}
+
+fn decode_spilled_series(data: &[u8]) -> GenericResult<HashMap<Labels, Vec<(i64, f64)>>> {
+    let invalid = || "Got invalid spilled samples";
+    let mut reader = Reader::new(data);
+    let mut series: HashMap<Labels, Vec<(i64, f64)>> = HashMap::new();
+
+    while !reader.data.is_empty() {
+        let mut labels = Vec::new();
+
+        for _ in 0..reader.read_uvarint().ok_or_else(invalid)? {
+            let mut read_string = || -> GenericResult<String> {
+                let data = reader.read_length_prefixed().ok_or_else(invalid)?;
+                Ok(String::from_utf8(data.to_vec()).map_err(|_| invalid())?)
+            };
+            labels.push((read_string()?, read_string()?));
+        }
+
+        let count = reader.read_uvarint().ok_or_else(invalid)?;
+        let samples = series.entry(labels).or_default();
+
+        for _ in 0..count {
+            let time = reader.read_varint().ok_or_else(invalid)?;
+            let value = reader.read_bytes(8).ok_or_else(invalid)?;
+            samples.push((time, f64::from_bits(u64::from_le_bytes(value.try_into().unwrap()))));
+        }
+    }
+
+    Ok(series)
+}
+
+fn write_block(path: &Path, series: &[(Labels, Vec<(i64, f64)>)]) -> EmptyResult {
+    let ulid = new_ulid();
+    let block_path = path.join(&ulid);
+    let temp_path = path.join(format!("{ulid}.tmp-for-creation"));
+
+    let chunks_path = temp_path.join("chunks");
+    fs::create_dir_all(&chunks_path).map_err(|e| format!("Unable to create {chunks_path:?}: {e}"))?;
+
+    let mut chunks = ChunkWriter::new(chunks_path);
+    let mut index_series = Vec::with_capacity(series.len());
+    let (mut min_time, mut max_time, mut samples_count, mut chunks_count) = (i64::MAX, i64::MIN, 0, 0);
+
+    for (labels, samples) in series {
+        let mut series_chunks = Vec::new();
+
+        for samples in samples.chunks(CHUNK_SAMPLES) {
+            let (first, last) = (samples[0].0, samples[samples.len() - 1].0);
+
+            series_chunks.push(ChunkMeta {
+                min_time: first,
+                max_time: last,
+                reference: chunks.write(&xor::encode(samples))?,
+            });
+
+            min_time = min_time.min(first);
+            max_time = max_time.max(last);
+            samples_count += samples.len();
+            chunks_count += 1;
+        }
+
+        index_series.push((labels, series_chunks));
+    }
+
+    chunks.finish()?;
+
+    let write = |name: &str, data: &[u8]| -> EmptyResult {
+        let path = temp_path.join(name);
+        Ok(fs::write(&path, data).map_err(|e| format!("Unable to write {path:?}: {e}"))?)
+    };
+
+    write("index", &encode_index(&index_series))?;
+
+    let mut tombstones = TOMBSTONES_MAGIC.to_be_bytes().to_vec();
+    tombstones.push(TOMBSTONES_VERSION);
+    tombstones.extend(crc32c::crc32c(&[]).to_be_bytes());
+    write("tombstones", &tombstones)?;
+
+    // Block max time is exclusive
+    let meta = serde_json::json!({
+        "ulid": ulid,
+        "minTime": min_time,
+        "maxTime": max_time + 1,
+        "stats": {
+            "numSamples": samples_count,
+            "numSeries": series.len(),
+            "numChunks": chunks_count,
+        },
+        "compaction": {
+            "level": 1,
+            "sources": [ulid],
+        },
+        "version": 1,
+    });
+    write("meta.json", &serde_json::to_vec_pretty(&meta)?)?;
+
+    fs::rename(&temp_path, &block_path).map_err(|e| format!(
+        "Unable to rename {temp_path:?} to {block_path:?}: {e}"))?;
+
+    Ok(())
+}
+
+fn encode_index(series: &[(&Labels, Vec<ChunkMeta>)]) -> Vec<u8> {
+    let mut buf = INDEX_MAGIC.to_be_bytes().to_vec();
+    buf.push(INDEX_VERSION);
+
+    let mut postings: BTreeMap<(&str, &str), Vec<u32>> = BTreeMap::new();
+    for (labels, _) in series {
+        for (name, value) in labels.iter() {
+            postings.entry((name, value)).or_default();
+        }
+    }
+
+    let symbols: BTreeSet<&str> = postings.keys().flat_map(|&(name, value)| [name, value]).collect();
+    let symbol_refs: HashMap<&str, u32> = symbols.iter().enumerate()
+        .map(|(index, &symbol)| (symbol, index as u32))
+        .collect();
+
+    let symbols_offset = buf.len();
+    write_section(&mut buf, |section| {
+        section.extend((symbols.len() as u32).to_be_bytes());
+        for symbol in &symbols {
+            write_uvarint(section, symbol.len() as u64);
+            section.extend(symbol.as_bytes());
+        }
+    });
+
+    // Series are referenced by their offset divided by the alignment
+    let series_offset = buf.len();
+    let mut all_postings = Vec::with_capacity(series.len());
+
+    for (labels, chunks) in series {
+        buf.resize(buf.len().next_multiple_of(SERIES_ALIGNMENT), 0);
+        let reference = (buf.len() / SERIES_ALIGNMENT) as u32;
+
+        let mut entry = Vec::new();
+        write_uvarint(&mut entry, labels.len() as u64);
+
+        for (name, value) in labels.iter() {
+            write_uvarint(&mut entry, symbol_refs[name.as_str()].into());
+            write_uvarint(&mut entry, symbol_refs[value.as_str()].into());
+            postings.get_mut(&(name.as_str(), value.as_str())).unwrap().push(reference);
+        }
+
+        write_uvarint(&mut entry, chunks.len() as u64);
+
+        let (mut max_time, mut chunk_reference) = (0_i64, 0_u64);
+        for (index, chunk) in chunks.iter().enumerate() {
+            if index == 0 {
+                write_varint(&mut entry, chunk.min_time);
+            } else {
+                write_uvarint(&mut entry, chunk.min_time.wrapping_sub(max_time) as u64);
+            }
+
+            write_uvarint(&mut entry, chunk.max_time.wrapping_sub(chunk.min_time) as u64);
+
+            if index == 0 {
+                write_uvarint(&mut entry, chunk.reference);
+            } else {
+                write_varint(&mut entry, chunk.reference.wrapping_sub(chunk_reference) as i64);
+            }
+
+            (max_time, chunk_reference) = (chunk.max_time, chunk.reference);
+        }
+
+        write_uvarint(&mut buf, entry.len() as u64);
+        buf.extend(&entry);
+        buf.extend(crc32c::crc32c(&entry).to_be_bytes());
+
+        all_postings.push(reference);
+    }
+
+    let mut label_values: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for &(name, value) in postings.keys() {
+        label_values.entry(name).or_default().push(value);
+    }
+
+    let label_indices_offset = buf.len();
+    let mut label_index_offsets = Vec::with_capacity(label_values.len());
+
+    for (name, values) in &label_values {
+        buf.resize(buf.len().next_multiple_of(4), 0);
+        label_index_offsets.push((*name, buf.len()));
+
+        write_section(&mut buf, |section| {
+            section.extend(1_u32.to_be_bytes());
+            section.extend((values.len() as u32).to_be_bytes());
+            for value in values {
+                section.extend(symbol_refs[value].to_be_bytes());
+            }
+        });
+    }
+
+    let label_indices_table_offset = buf.len();
+    write_section(&mut buf, |section| {
+        section.extend((label_index_offsets.len() as u32).to_be_bytes());
+        for &(name, offset) in &label_index_offsets {
+            write_uvarint(section, 1);
+            write_uvarint(section, name.len() as u64);
+            section.extend(name.as_bytes());
+            write_uvarint(section, offset as u64);
+        }
+    });
+
+    // All series postings list has empty label name and value
+    let postings_offset = buf.len();
+    let mut postings_offsets = Vec::with_capacity(postings.len() + 1);
+
+    let all_postings_key = ("", "");
+    for (&(name, value), references) in [(&all_postings_key, &all_postings)].into_iter().chain(&postings) {
+        buf.resize(buf.len().next_multiple_of(4), 0);
+        postings_offsets.push((name, value, buf.len()));
+
+        write_section(&mut buf, |section| {
+            section.extend((references.len() as u32).to_be_bytes());
+            for reference in references {
+                section.extend(reference.to_be_bytes());
+            }
+        });
+    }
+
+    let postings_table_offset = buf.len();
+    write_section(&mut buf, |section| {
+        section.extend((postings_offsets.len() as u32).to_be_bytes());
+        for &(name, value, offset) in &postings_offsets {
+            write_uvarint(section, 2);
+            for string in [name, value] {
+                write_uvarint(section, string.len() as u64);
+                section.extend(string.as_bytes());
+            }
+            write_uvarint(section, offset as u64);
+        }
+    });
+
+    let toc_offset = buf.len();
+    for offset in [
+        symbols_offset, series_offset, label_indices_offset, label_indices_table_offset, postings_offset,
+        postings_table_offset,
+    ] {
+        buf.extend((offset as u64).to_be_bytes());
+    }
+
+    let checksum = crc32c::crc32c(&buf[toc_offset..]);
+    buf.extend(checksum.to_be_bytes());
+
+    buf
+}
+
+// Writes u32 length prefixed data followed by its CRC32
+fn write_section<F: FnOnce(&mut Vec<u8>)>(buf: &mut Vec<u8>, write: F) {
+    let mut section = Vec::new();
+    write(&mut section);
+
+    buf.extend((section.len() as u32).to_be_bytes());
+    buf.extend(&section);
+    buf.extend(crc32c::crc32c(&section).to_be_bytes());
+}
+
+struct ChunkWriter {
+    path: PathBuf,
+    segment: Option<(u64, BufWriter<File>)>,
+    offset: u64,
+}
+
+impl ChunkWriter {
+    fn new(path: PathBuf) -> ChunkWriter {
+        ChunkWriter {path, segment: None, offset: 0}
+    }
+
+    fn write(&mut self, data: &[u8]) -> GenericResult<u64> {
+        let mut chunk = Vec::with_capacity(data.len() + 15);
+        write_uvarint(&mut chunk, data.len() as u64);
+        chunk.push(CHUNK_XOR);
+        chunk.extend(data);
+        chunk.extend(crc32c::crc32c_append(crc32c::crc32c(&[CHUNK_XOR]), data).to_be_bytes());
+
+        let index = match self.segment {
+            Some((index, _)) if self.offset + chunk.len() as u64 <= CHUNKS_SEGMENT_SIZE => index,
+            ref segment => {
+                let index = segment.as_ref().map_or(0, |(index, _)| index + 1);
+                self.finish()?;
+
+                let path = self.path.join(format!("{:06}", index + 1));
+                let file = File::create(&path).map_err(|e| format!("Unable to create {path:?}: {e}"))?;
+
+                let mut header = CHUNKS_MAGIC.to_be_bytes().to_vec();
+                header.extend([CHUNKS_VERSION, 0, 0, 0]);
+
+                let mut writer = BufWriter::new(file);
+                writer.write_all(&header).map_err(|e| format!("Unable to write {path:?}: {e}"))?;
+
+                self.segment = Some((index, writer));
+                self.offset = CHUNKS_HEADER_SIZE;
+                index
+            },
+        };
+
+        let reference = index << 32 | self.offset;
+
+        let (_, writer) = self.segment.as_mut().unwrap();
+        writer.write_all(&chunk).map_err(|e| format!("Unable to write chunks to {:?}: {e}", self.path))?;
+        self.offset += chunk.len() as u64;
+
+        Ok(reference)
+    }
+
+    fn finish(&mut self) -> EmptyResult {
+        if let Some((_, mut writer)) = self.segment.take() {
+            writer.flush().map_err(|e| format!("Unable to write chunks to {:?}: {e}", self.path))?;
+        }
+        Ok(())
+    }
+}
+
+// Generates ULID: 48-bit timestamp in milliseconds and 80 random bits in Crockford's base32
+fn new_ulid() -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    let time = Utc::now().timestamp_millis() as u128;
+    let random = (0..2).fold(0_u128, |random, _| {
+        random << 64 | u128::from(RandomState::new().build_hasher().finish())
+    });
+
+    let value = time << 80 | random & ((1 << 80) - 1);
+    (0..26).rev().map(|index| ALPHABET[(value >> (5 * index) & 0x1f) as usize] as char).collect()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader {data}
+    }
+
+    fn read_bytes(&mut self, size: usize) -> Option<&'a [u8]> {
+        let data = self.data.get(..size)?;
+        self.data = &self.data[size..];
+        Some(data)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        Some(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_uvarint(&mut self) -> Option<u64> {
+        varint::read_uvarint(&mut self.data)
+    }
+
+    fn read_varint(&mut self) -> Option<i64> {
+        varint::read_varint(&mut self.data)
+    }
+
+    fn read_length_prefixed(&mut self) -> Option<&'a [u8]> {
+        let size = self.read_uvarint()?;
+        self.read_bytes(size.try_into().ok()?)
+    }
+
+    // Reads u32 length prefixed data followed by its CRC32
+    fn read_section(&mut self) -> Option<&'a [u8]> {
+        let size = self.read_u32()?;
+        self.read_checksummed(size.try_into().ok()?)
+    }
+
+    // Reads uvarint length prefixed data followed by its CRC32
+    fn read_section_uvarint(&mut self) -> Option<&'a [u8]> {
+        let size = self.read_uvarint()?;
+        self.read_checksummed(size.try_into().ok()?)
+    }
+
+    fn read_checksummed(&mut self, size: usize) -> Option<&'a [u8]> {
+        let data = self.read_bytes(size)?;
+        let checksum = self.read_u32()?;
+        (crc32c::crc32c(data) == checksum).then_some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_series(name: &str, job: &str, samples: &[(i64, Option<f64>)]) -> TimeSeries {
+        let mut time_series = TimeSeries::new(HashMap::from([
+            ("__name__".to_owned(), name.to_owned()),
+            ("job".to_owned(), job.to_owned()),
+        ]));
+
+        for &(time, value) in samples {
+            time_series.add(time, value);
+        }
+
+        time_series
+    }
+
+    fn read(path: &Path, time_range: (i64, i64)) -> BTreeMap<String, Vec<(i64, Option<f64>)>> {
+        let mut result: BTreeMap<String, Vec<_>> = BTreeMap::new();
+
+        read_blocks(path, time_range, |time_series| {
+            result.entry(time_series.format_metric()).or_default().extend(time_series.iter());
+            true
+        }).unwrap();
+
+        result
+    }
+
+    #[test]
+    fn write_read() {
+        let path = std::env::temp_dir().join(format!("vm-migrate-{}-tsdb", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        let hour = BLOCK_DURATION / 2;
+        let many: Vec<_> = (0..500).map(|index| (index * 1000, Some(index as f64 / 3.0))).collect();
+
+        let mut writer = Writer::new(path.clone());
+        writer.add(&time_series("first", "a", &[(0, Some(1.0)), (hour, None), (3 * hour, Some(-2.5))])).unwrap();
+        writer.add(&time_series("second", "b", &many)).unwrap();
+        writer.spill().unwrap();
+
+        // The duplicate sample is replaced by the last one
+        writer.add(&time_series("first", "a", &[(hour + 1, Some(3.0)), (3 * hour, Some(4.0))])).unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+
+        let blocks = fs::read_dir(&path).unwrap().count();
+        assert_eq!(blocks, 2);
+
+        let result = read(&path, (i64::MIN, i64::MAX));
+        assert_eq!(result.len(), 2);
+
+        let first = &result[r#"first{job="a"}"#];
+        assert_eq!(*first, [(0, Some(1.0)), (hour, None), (hour + 1, Some(3.0)), (3 * hour, Some(4.0))]);
+        assert_eq!(result[r#"second{job="b"}"#], many);
+
+        let result = read(&path, (2 * hour, i64::MAX));
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[r#"first{job="a"}"#], [(3 * hour, Some(4.0))]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn tombstones() {
+        let path = std::env::temp_dir().join(format!("vm-migrate-{}-tsdb-tombstones", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        let samples: Vec<_> = (0..10).map(|index| (index * 1000, Some(index as f64))).collect();
+
+        let mut writer = Writer::new(path.clone());
+        writer.add(&time_series("first", "a", &samples)).unwrap();
+        writer.add(&time_series("second", "b", &samples)).unwrap();
+        assert_eq!(writer.finish().unwrap(), 1);
+
+        let block_path = fs::read_dir(&path).unwrap().next().unwrap().unwrap().path();
+        let index = fs::read(block_path.join("index")).unwrap();
+
+        let reference = Index::parse(&index).unwrap().series()
+            .map(Result::unwrap)
+            .find(|series| series.labels["__name__"] == "first")
+            .unwrap().reference;
+
+        let mut entries = Vec::new();
+        for (start, end) in [(2000, 3000), (8000, i64::MAX)] {
+            write_uvarint(&mut entries, reference);
+            write_varint(&mut entries, start);
+            write_varint(&mut entries, end);
+        }
+
+        let mut tombstones = TOMBSTONES_MAGIC.to_be_bytes().to_vec();
+        tombstones.push(TOMBSTONES_VERSION);
+        tombstones.extend(&entries);
+        tombstones.extend(crc32c::crc32c(&entries).to_be_bytes());
+        fs::write(block_path.join("tombstones"), &tombstones).unwrap();
+
+        let result = read(&path, (i64::MIN, i64::MAX));
+
+        let expected: Vec<_> = samples.iter().cloned()
+            .filter(|&(time, _value)| !(2000..=3000).contains(&time) && time < 8000)
+            .collect();
+        assert_eq!(result[r#"first{job="a"}"#], expected);
+        assert_eq!(result[r#"second{job="b"}"#], samples);
+
+        // Corrupted tombstones are rejected
+        *tombstones.last_mut().unwrap() ^= 1;
+        fs::write(block_path.join("tombstones"), &tombstones).unwrap();
+        assert!(read_blocks(&path, (i64::MIN, i64::MAX), |_| true).is_err());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
\ No newline at end of file
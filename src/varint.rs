@@ -0,0 +1,83 @@
+// Variable-length integers as they are used by Protobuf, VictoriaMetrics native format and Prometheus TSDB: 7 bits per
+// byte starting from the least significant ones with the high bit set on all bytes except the last one. Signed integers
+// are ZigZag-encoded.
+
+pub fn read_uvarint(src: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+
+    for (index, &byte) in src.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+
+        if byte & 0x80 == 0 {
+            *src = &src[index + 1..];
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+pub fn read_varint(src: &mut &[u8]) -> Option<i64> {
+    let value = read_uvarint(src)?;
+    Some((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+pub fn write_uvarint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, value: i64) {
+    write_uvarint(buf, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+
+        let unsigned = [0, 1, 127, 128, 300, u64::from(u32::MAX), u64::MAX];
+        let signed = [0, 1, -1, 63, -64, 64, i64::MIN, i64::MAX];
+
+        for value in unsigned {
+            write_uvarint(&mut buf, value);
+        }
+        for value in signed {
+            write_varint(&mut buf, value);
+        }
+
+        let mut src = buf.as_slice();
+
+        for value in unsigned {
+            assert_eq!(read_uvarint(&mut src), Some(value));
+        }
+        for value in signed {
+            assert_eq!(read_varint(&mut src), Some(value));
+        }
+
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn encoding() {
+        let mut buf = Vec::new();
+        write_uvarint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+
+        buf.clear();
+        write_varint(&mut buf, -2);
+        assert_eq!(buf, [0x03]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(read_uvarint(&mut [0x80, 0x80].as_slice()), None);
+        assert_eq!(read_uvarint(&mut [0xff; 11].as_slice()), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,259 @@
+use crate::core::GenericResult;
+
+// Prometheus XOR (Gorilla) chunk: big endian u16 number of samples followed by bit stream of delta-of-delta encoded
+// timestamps and XOR encoded values
+
+pub fn decode(data: &[u8]) -> GenericResult<Vec<(i64, f64)>> {
+    let invalid = || "Got an invalid XOR chunk";
+
+    let (&[high, low], data) = data.split_first_chunk().ok_or_else(invalid)?;
+    let count = u16::from_be_bytes([high, low]);
+
+    let mut reader = BitReader {data, position: 0};
+    let mut samples = Vec::with_capacity(count.into());
+    let (mut time, mut delta, mut value) = (0_i64, 0_i64, 0_u64);
+    let (mut leading, mut trailing) = (0, 0);
+
+    for index in 0..count {
+        match index {
+            0 => {
+                time = reader.read_varint().ok_or_else(invalid)?;
+                value = reader.read_bits(64).ok_or_else(invalid)?;
+            },
+            _ => {
+                if index == 1 {
+                    delta = reader.read_uvarint().ok_or_else(invalid)? as i64;
+                } else {
+                    let mut prefix = 0;
+                    while prefix < 4 && reader.read_bit().ok_or_else(invalid)? {
+                        prefix += 1;
+                    }
+
+                    let size = [0, 14, 17, 20, 64][prefix];
+                    if size != 0 {
+                        let mut bits = reader.read_bits(size).ok_or_else(invalid)?;
+                        if size != 64 && bits > 1 << (size - 1) {
+                            bits = bits.wrapping_sub(1 << size);
+                        }
+                        delta = delta.wrapping_add(bits as i64);
+                    }
+                }
+
+                time = time.wrapping_add(delta);
+
+                if reader.read_bit().ok_or_else(invalid)? {
+                    if reader.read_bit().ok_or_else(invalid)? {
+                        leading = reader.read_bits(5).ok_or_else(invalid)? as u32;
+                        let significant = match reader.read_bits(6).ok_or_else(invalid)? as u32 {
+                            0 => 64,
+                            bits => bits,
+                        };
+                        trailing = 64_u32.checked_sub(leading + significant).ok_or_else(invalid)?;
+                    }
+
+                    let bits = reader.read_bits(64 - leading - trailing).ok_or_else(invalid)?;
+                    value ^= bits << trailing;
+                }
+            },
+        }
+
+        samples.push((time, f64::from_bits(value)));
+    }
+
+    Ok(samples)
+}
+
+// Encodes up to 65535 samples
+pub fn encode(samples: &[(i64, f64)]) -> Vec<u8> {
+    let count = u16::try_from(samples.len()).expect("Too many samples for XOR chunk");
+
+    let mut writer = BitWriter {data: count.to_be_bytes().to_vec(), position: 16};
+    let (mut time, mut delta, mut value) = (0_i64, 0_i64, 0_u64);
+    let (mut leading, mut trailing) = (u32::MAX, 0);
+
+    for (index, &(sample_time, sample_value)) in samples.iter().enumerate() {
+        let sample_value = sample_value.to_bits();
+
+        if index == 0 {
+            writer.write_varint(sample_time);
+            writer.write_bits(sample_value, 64);
+            (time, value) = (sample_time, sample_value);
+            continue;
+        }
+
+        let sample_delta = sample_time.wrapping_sub(time);
+
+        if index == 1 {
+            writer.write_uvarint(sample_delta as u64);
+        } else {
+            let delta_of_delta = sample_delta.wrapping_sub(delta);
+
+            let prefix = [14, 17, 20].iter().position(|&size| {
+                -(1 << (size - 1)) < delta_of_delta && delta_of_delta <= 1 << (size - 1)
+            });
+
+            if delta_of_delta == 0 {
+                writer.write_bits(0, 1);
+            } else if let Some(prefix) = prefix {
+                let size = [14, 17, 20][prefix];
+                writer.write_bits((1 << (prefix + 2)) - 2, prefix as u32 + 2);
+                writer.write_bits(delta_of_delta as u64 & ((1 << size) - 1), size);
+            } else {
+                writer.write_bits(0b1111, 4);
+                writer.write_bits(delta_of_delta as u64, 64);
+            }
+        }
+
+        (time, delta) = (sample_time, sample_delta);
+
+        let xor = sample_value ^ value;
+        value = sample_value;
+
+        if xor == 0 {
+            writer.write_bits(0, 1);
+            continue;
+        }
+
+        writer.write_bits(1, 1);
+
+        let (sample_leading, sample_trailing) = (xor.leading_zeros().min(31), xor.trailing_zeros());
+
+        if leading != u32::MAX && sample_leading >= leading && sample_trailing >= trailing {
+            writer.write_bits(0, 1);
+        } else {
+            (leading, trailing) = (sample_leading, sample_trailing);
+            let significant = 64 - leading - trailing;
+
+            writer.write_bits(1, 1);
+            writer.write_bits(leading.into(), 5);
+            writer.write_bits((significant & 0x3f).into(), 6);
+        }
+
+        writer.write_bits(xor >> trailing, 64 - leading - trailing);
+    }
+
+    writer.data
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+        Some(bit == 1)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Option<u64> {
+        let mut bits = 0;
+        for _ in 0..count {
+            bits = bits << 1 | u64::from(self.read_bit()?);
+        }
+        Some(bits)
+    }
+
+    fn read_uvarint(&mut self) -> Option<u64> {
+        let mut value = 0;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_bits(8)?;
+            value |= (byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
+    fn read_varint(&mut self) -> Option<i64> {
+        let value = self.read_uvarint()?;
+        Some((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+}
+
+struct BitWriter {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, bits: u64, count: u32) {
+        for index in (0..count).rev() {
+            if self.position.is_multiple_of(8) {
+                self.data.push(0);
+            }
+
+            let bit = (bits >> index & 1) as u8;
+            *self.data.last_mut().unwrap() |= bit << (7 - self.position % 8);
+            self.position += 1;
+        }
+    }
+
+    fn write_uvarint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.write_bits(value & 0x7f | 0x80, 8);
+            value >>= 7;
+        }
+        self.write_bits(value, 8);
+    }
+
+    fn write_varint(&mut self, value: i64) {
+        self.write_uvarint(((value << 1) ^ (value >> 63)) as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(samples: &[(i64, f64)]) {
+        let decoded = decode(&encode(samples)).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+
+        for (&(time, value), &(decoded_time, decoded_value)) in samples.iter().zip(&decoded) {
+            assert_eq!(decoded_time, time);
+            assert_eq!(decoded_value.to_bits(), value.to_bits());
+        }
+    }
+
+    #[test]
+    fn regular() {
+        round_trip(&[]);
+        round_trip(&[(1000, 1.0)]);
+        round_trip(&(0..120).map(|index| (1_700_000_000_000 + index * 15000, (index % 7) as f64)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn irregular() {
+        // Timestamp deltas and value changes of all bucket sizes
+        let mut samples = Vec::new();
+        let mut time = -5000;
+
+        for (index, delta) in [1, 1, 100, 3000, 3000, 70000, 1 << 20, 1 << 33, 1, 15000].into_iter().enumerate() {
+            time += delta;
+            let value = match index % 5 {
+                0 => index as f64 * 0.1,
+                1 => -1e300,
+                2 => f64::NAN,
+                3 => f64::INFINITY,
+                _ => f64::MIN_POSITIVE,
+            };
+            samples.push((time, value));
+        }
+
+        round_trip(&samples);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(decode(&[]).is_err());
+
+        let data = encode(&[(1000, 1.0), (2000, 2.0), (3000, 4.0)]);
+        assert!(decode(&data[..data.len() - 2]).is_err());
+    }
+}
\ No newline at end of file